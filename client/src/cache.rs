@@ -0,0 +1,100 @@
+use server::error;
+use server::event::Message;
+use server::server::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Recent messages of each room, kept in `<data dir>/rschat/cache/<server>.json`
+/// so a restarted client shows them right away. Each server has its own file,
+/// holding its messages by room.
+pub struct Cache {
+    // location of the cache file, if the platform has a data directory
+    path: Option<PathBuf>,
+    // newest messages of each room, oldest first
+    rooms: HashMap<String, Vec<Message>>,
+}
+
+impl Cache {
+    /// Messages kept for each room.
+    const ROOM_CAPACITY: usize = 200;
+
+    /// Reads the cache of `server`. A missing or unreadable file gives an
+    /// empty cache.
+    pub fn load(server: &str) -> Self {
+        // server addresses hold characters that some platforms forbid in file names
+        let name: String = server
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = dirs::data_dir().map(|dir| {
+            dir.join("rschat")
+                .join("cache")
+                .join(format!("{name}.json"))
+        });
+
+        let rooms = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(content)) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                error!("Ignoring malformed message cache: {err}");
+                HashMap::new()
+            }),
+            Some(Err(err)) if err.kind() != ErrorKind::NotFound => {
+                error!("Failed to read message cache: {err}");
+                HashMap::new()
+            }
+            _ => HashMap::new(),
+        };
+
+        Self { path, rooms }
+    }
+
+    /// Takes the cached messages of every room.
+    pub fn take(&mut self) -> Vec<Message> {
+        self.rooms
+            .drain()
+            .flat_map(|(_, messages)| messages)
+            .collect()
+    }
+
+    /// Replaces the cached messages with the newest ones of each room of `messages`.
+    pub fn store<'a>(&mut self, messages: impl Iterator<Item = &'a Message>) {
+        self.rooms.clear();
+        for msg in messages {
+            self.rooms
+                .entry(msg.room.clone())
+                .or_default()
+                .push(msg.clone());
+        }
+        for messages in self.rooms.values_mut() {
+            messages.sort_by_key(|msg| msg.id);
+            let extra = messages.len().saturating_sub(Self::ROOM_CAPACITY);
+            messages.drain(..extra);
+        }
+    }
+
+    /// Writes the cache to disk.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the cache was saved, or there is no data directory.
+    /// - `Err(())`: If the file could not be written, with a message logged.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|err| error!("Failed to create {}: {err}", dir.display()))?;
+        }
+        let content = serde_json::to_string(&self.rooms)
+            .map_err(|err| error!("Failed to encode message cache: {err}"))?;
+        fs::write(path, content).map_err(|err| error!("Failed to save message cache: {err}"))
+    }
+}
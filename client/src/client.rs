@@ -1,24 +1,33 @@
+use crate::cache::Cache;
+use crate::commands::{COMMANDS, Command};
+use crate::config::Heartbeat;
+use crate::drafts::Drafts;
+use crate::keymap::{Action, KeyResult, Keymap};
+use crate::search::SearchQuery;
 use crate::session::Session;
-use crate::widgets::help::HelpWidget;
+use crate::stream::Stream;
+use crate::theme::Theme;
+use crate::widgets::completion::CompletionPopup;
+use crate::widgets::help::{HelpPopup, HelpWidget};
 use crate::widgets::input::InputWidget;
 use crate::widgets::msgs_container::MsgContainer;
 use ratatui::layout::Margin;
 use ratatui::prelude::Stylize;
 use ratatui::{
     Frame,
-    crossterm::event::{self, Event, KeyCode},
+    crossterm::event::{self, Event, KeyCode, KeyEvent},
     layout::{Constraint, Layout},
     text::Line,
 };
+use rustls::ClientConfig;
 use server::error;
-use server::event::Message;
+use server::event::{Message, Reaction};
+use server::protocol::{Frame as ServerFrame, FrameReader, Welcome};
 use server::server::Result;
-use std::fmt::Display;
 use std::io::{ErrorKind, Read, Write};
-use std::net::{TcpStream, ToSocketAddrs};
-use std::str::from_utf8;
-use std::sync::mpsc::{Sender, TryRecvError, channel};
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub struct Client<'a> {
     // InputWidget handler
@@ -27,6 +36,69 @@ pub struct Client<'a> {
     mode: Mode,
     // User name
     session: Session,
+    // interface colors
+    theme: Theme,
+    // actions bound to keys
+    keymap: Keymap,
+    // what the message being written is for
+    compose: Compose,
+    // whether the keybindings popup is open
+    show_help: bool,
+    // last error reported by the server
+    notice: Option<String>,
+    // unsent messages of each room
+    drafts: Drafts,
+    // messages kept on disk between runs
+    cache: Cache,
+    // frames received along with the login answer
+    reader: FrameReader,
+    // address of the server
+    addr: String,
+    // encryption of the connection, `None` for plain sockets
+    tls: Option<Arc<ClientConfig>>,
+    // token resuming the login session after a lost connection
+    token: String,
+    // whether the user logged out, revoking the token
+    logged_out: bool,
+    // whether the server refused the token when connecting again
+    token_refused: bool,
+    // why the server closed the connection
+    disconnected: Option<String>,
+    // how often the server is pinged
+    heartbeat: Heartbeat,
+    // when the server was last pinged
+    last_ping: Instant,
+    // nonce of the ping waiting for an answer, and when it was sent
+    ping: Option<(u64, Instant)>,
+    // time the server took to answer the last ping
+    latency: Option<Duration>,
+    // message search being typed
+    search_prompt: Option<String>,
+    // last message search, highlighted and browsed with n/N
+    search: Option<SearchQuery>,
+    // where the server history search continues, when it has more results
+    search_next_page: Option<u64>,
+    // whether the next history results must be jumped to
+    search_waiting: bool,
+}
+
+/// What the client logs in to the server with.
+pub enum Credentials {
+    /// The password of the user, creating the account first if `register` is set.
+    Password { password: String, register: bool },
+    /// The token of a previous login.
+    Token(String),
+}
+
+/// A connection to the server the client is logged in on.
+pub struct Login {
+    stream: Stream,
+    // frames received after the login answer
+    reader: FrameReader,
+    // greeting of the server, received before the login answer
+    welcome: Option<Welcome>,
+    /// Token resuming the session later.
+    pub token: String,
 }
 
 #[derive(PartialEq, Clone)]
@@ -35,22 +107,139 @@ pub enum Mode {
     NormalMode,
 }
 
+/// What the text of the input box is sent as.
+#[derive(PartialEq, Clone, Copy)]
+enum Compose {
+    Message,
+    Reply(u64),
+    Reaction(u64),
+}
+
 impl<'a> Client<'a> {
-    pub fn build<A: ToSocketAddrs + Display>(addr: A, user_name: String) -> Result<Self> {
-        let stream = TcpStream::connect(&addr)
-            .map_err(|err| error!("Failed to connect to server {addr}: {err}"))?;
+    /// Times the client tries to connect again after losing the connection.
+    const RECONNECT_ATTEMPTS: usize = 5;
+    /// Time the server has to answer a login.
+    const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+    /// Start of the reason `connect` gives when the server refused the
+    /// credentials, rather than failed to answer.
+    pub const LOGIN_FAILED: &'static str = "Login failed";
+
+    pub fn build(
+        addr: String,
+        tls: Option<Arc<ClientConfig>>,
+        user_name: String,
+        login: Login,
+        theme: Theme,
+        keymap: Keymap,
+        heartbeat: Heartbeat,
+    ) -> Result<Self> {
+        let mut session = Session::new(login.stream, user_name, &theme);
+        if let Some(welcome) = login.welcome {
+            session.set_welcome(welcome);
+        }
+        let mut cache = Cache::load(&addr);
+        session.add_history(cache.take());
+
         Ok(Self {
             mode: Mode::InsertMode,
-            input: InputWidget::new(Mode::InsertMode),
-            session: Session::new(stream, user_name),
+            input: InputWidget::new(Mode::InsertMode, theme.clone()),
+            session,
+            theme,
+            keymap,
+            compose: Compose::Message,
+            show_help: false,
+            notice: None,
+            drafts: Drafts::load(addr.clone()),
+            cache,
+            reader: login.reader,
+            addr,
+            tls,
+            token: login.token,
+            logged_out: false,
+            token_refused: false,
+            disconnected: None,
+            heartbeat,
+            last_ping: Instant::now(),
+            ping: None,
+            latency: None,
+            search_prompt: None,
+            search: None,
+            search_next_page: None,
+            search_waiting: false,
         })
     }
 
+    /// Connects to the server and logs in, waiting for its answer.
+    ///
+    /// # Returns
+    /// - `Ok(Login)`: If the server accepted the credentials.
+    /// - `Err(reason)`: If the login was refused or the connection failed.
+    pub fn connect(
+        addr: &str,
+        tls: Option<&Arc<ClientConfig>>,
+        user: &str,
+        credentials: &Credentials,
+    ) -> std::result::Result<Login, String> {
+        let mut stream = Stream::connect(addr, tls)
+            .map_err(|err| format!("Failed to connect to server {addr}: {err}"))?;
+        stream
+            .set_read_timeout(Some(Self::LOGIN_TIMEOUT))
+            .map_err(|err| format!("Failed to configure socket: {err}"))?;
+
+        let frame = match credentials {
+            Credentials::Password { password, register } => {
+                let username = user.to_string();
+                let password = password.clone();
+                if *register {
+                    ServerFrame::Register { username, password }
+                } else {
+                    ServerFrame::Login { username, password }
+                }
+            }
+            Credentials::Token(token) => ServerFrame::Resume {
+                token: token.clone(),
+            },
+        };
+        stream
+            .write_all(&frame.encode())
+            .map_err(|err| format!("Failed to write to socket: {err}"))?;
+
+        let mut buffer = [0; 1024];
+        let mut reader = FrameReader::default();
+        let mut welcome = None;
+        loop {
+            while let Some(frame) = reader.next_frame() {
+                match frame {
+                    Ok(ServerFrame::Welcome(greeting)) => welcome = Some(greeting),
+                    Ok(ServerFrame::LoggedIn { token, .. }) => {
+                        stream
+                            .set_read_timeout(None)
+                            .map_err(|err| format!("Failed to configure socket: {err}"))?;
+                        return Ok(Login {
+                            stream,
+                            reader,
+                            welcome,
+                            token,
+                        });
+                    }
+                    Ok(ServerFrame::Error { reason }) => {
+                        return Err(format!("{}: {reason}", Self::LOGIN_FAILED));
+                    }
+                    Ok(ServerFrame::Disconnected { reason }) => return Err(reason),
+                    _ => {}
+                }
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(String::from("The server closed the connection")),
+                Ok(n) => reader.push(&buffer[0..n]),
+                Err(err) => return Err(format!("Failed to read from socket: {err}")),
+            }
+        }
+    }
+
     pub fn run(&mut self, term: &mut ratatui::DefaultTerminal) -> Result<()> {
-        // thread responsible to handle incoming messages
-        let (sender, receiver) = channel();
-        let stream = self.session.clone_stream()?;
-        std::thread::spawn(move || Self::incoming_messages(sender, stream));
+        let mut receiver = self.listen()?;
 
         // main client loop
         loop {
@@ -59,36 +248,120 @@ impl<'a> Client<'a> {
             if self.handle_events()? {
                 break Ok(());
             }
+            self.ping_server()?;
             match receiver.try_recv() {
-                Ok(msg) => {
-                    self.session.assign_user_color(msg.author.clone());
-                    self.session.new_message(msg);
-                }
-                Err(e) => {
-                    if let TryRecvError::Disconnected = e {
-                        break Ok(());
+                Ok(frame) => self.handle_frame(frame)?,
+                Err(TryRecvError::Disconnected) => match self.reconnect(term)? {
+                    Some(new_receiver) => receiver = new_receiver,
+                    None => break Ok(()),
+                },
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+    }
+
+    /// Starts the thread receiving the frames of the server.
+    fn listen(&mut self) -> Result<Receiver<ServerFrame>> {
+        let (sender, receiver) = channel();
+        let stream = self.session.clone_stream()?;
+        let reader = std::mem::take(&mut self.reader);
+        std::thread::spawn(move || Self::incoming_frames(sender, stream, reader));
+        Ok(receiver)
+    }
+
+    /// Connects again after losing the connection, resuming the login session.
+    ///
+    /// # Returns
+    /// - `Ok(Some(receiver))`: If the client is connected again.
+    /// - `Ok(None)`: If the server could not be reached or refused the token.
+    /// - `Err(())`: If the interface could not be drawn, with a message logged.
+    fn reconnect(
+        &mut self,
+        term: &mut ratatui::DefaultTerminal,
+    ) -> Result<Option<Receiver<ServerFrame>>> {
+        if self.logged_out || self.disconnected.is_some() {
+            return Ok(None);
+        }
+
+        let credentials = Credentials::Token(self.token.clone());
+        for attempt in 1..=Self::RECONNECT_ATTEMPTS {
+            self.notice = Some(format!(
+                "Connection lost, reconnecting ({attempt}/{})...",
+                Self::RECONNECT_ATTEMPTS
+            ));
+            term.draw(|frame| self.draw(frame))
+                .map_err(|err| error!("Failed to draw frame to terminal: {err}"))?;
+            std::thread::sleep(Duration::from_secs(attempt as u64));
+
+            match Self::connect(
+                &self.addr,
+                self.tls.as_ref(),
+                self.session.user(),
+                &credentials,
+            ) {
+                Ok(login) => {
+                    self.session.set_stream(login.stream);
+                    if let Some(welcome) = login.welcome {
+                        self.session.set_welcome(welcome);
                     }
+                    self.reader = login.reader;
+                    self.last_ping = Instant::now();
+                    self.ping = None;
+                    self.latency = None;
+                    self.notice = Some(String::from("Reconnected"));
+                    return self.listen().map(Some);
                 }
+                // a refused token will not be accepted on the next attempts
+                Err(reason) if reason.starts_with(Self::LOGIN_FAILED) => {
+                    self.token_refused = true;
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Pings the server every `heartbeat.interval`, closing the connection
+    /// when a ping is not answered within `heartbeat.timeout` so that the
+    /// client connects again.
+    fn ping_server(&mut self) -> Result<()> {
+        if let Some((_, sent)) = self.ping {
+            // the ping stays pending until the client is connected again
+            if sent.elapsed() > Duration::from_secs(self.heartbeat.timeout) {
+                self.session.shutdown();
             }
+            return Ok(());
+        }
+
+        if self.last_ping.elapsed() >= Duration::from_secs(self.heartbeat.interval) {
+            let nonce = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            self.send_frame(&ServerFrame::Ping { nonce })?;
+            self.ping = Some((nonce, Instant::now()));
+            self.last_ping = Instant::now();
         }
+        Ok(())
     }
 
-    fn incoming_messages(msgs: Sender<Message>, mut stream: TcpStream) {
+    fn incoming_frames(frames: Sender<ServerFrame>, mut stream: Stream, mut reader: FrameReader) {
         let mut buffer = [0; 1024];
         stream.set_nonblocking(true).unwrap();
         loop {
-            match stream.read(&mut buffer) {
-                Ok(n) => {
-                    if n == 0 {
-                        break;
-                    }
-
-                    if let Ok(msg_str) = from_utf8(&buffer[0..n]) {
-                        if let Ok(msg) = serde_json::from_str(msg_str) {
-                            msgs.send(msg).unwrap();
-                        }
-                    }
+            // the reader may already hold frames received during the login
+            while let Some(frame) = reader.next_frame() {
+                if let Ok(frame) = frame
+                    && frames.send(frame).is_err()
+                {
+                    return;
                 }
+            }
+
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => reader.push(&buffer[0..n]),
                 Err(e) if e.kind() == ErrorKind::WouldBlock => {
                     std::thread::sleep(Duration::from_millis(50));
                 }
@@ -97,25 +370,164 @@ impl<'a> Client<'a> {
         }
     }
 
+    fn handle_frame(&mut self, frame: ServerFrame) -> Result<()> {
+        match frame {
+            ServerFrame::Message(msg) => self.session.new_message(msg),
+            ServerFrame::React(reaction) => self.session.new_reaction(reaction),
+            ServerFrame::Rooms { rooms } => self.session.set_rooms(rooms),
+            ServerFrame::Welcome(welcome) => self.session.set_welcome(welcome),
+            ServerFrame::Joined { room } => {
+                // only the messages missing from the cache are asked for
+                let after_id = self.session.last_id(&room);
+                self.send_frame(&ServerFrame::Sync {
+                    room: room.clone(),
+                    after_id,
+                })?;
+                self.change_room(room);
+            }
+            ServerFrame::History {
+                room,
+                messages,
+                reset,
+            } => {
+                if reset {
+                    self.session.forget_room(&room);
+                }
+                self.session.add_history(messages);
+            }
+            ServerFrame::SearchResults { messages, next } => {
+                self.session.add_history(messages);
+                self.search_next_page = next;
+                if std::mem::take(&mut self.search_waiting) {
+                    self.jump_to_match(true);
+                }
+            }
+            ServerFrame::Deleted { id } => self.session.delete_message(id),
+            ServerFrame::Disconnected { reason } => self.disconnected = Some(reason),
+            ServerFrame::Notice { text } => self.notice = Some(text),
+            ServerFrame::Warning { reason } => self.notice = Some(reason),
+            ServerFrame::Ping { nonce } => self.send_frame(&ServerFrame::Pong { nonce })?,
+            ServerFrame::Pong { nonce } => {
+                if let Some((sent, at)) = self.ping
+                    && sent == nonce
+                {
+                    self.latency = Some(at.elapsed());
+                    self.ping = None;
+                }
+            }
+            ServerFrame::Error { reason } => self.notice = Some(reason),
+            ServerFrame::LoggedIn { .. }
+            | ServerFrame::Register { .. }
+            | ServerFrame::Login { .. }
+            | ServerFrame::Resume { .. }
+            | ServerFrame::Logout
+            | ServerFrame::Join { .. }
+            | ServerFrame::Search(_)
+            | ServerFrame::Sync { .. }
+            | ServerFrame::Kick { .. }
+            | ServerFrame::Ban { .. }
+            | ServerFrame::Unban { .. }
+            | ServerFrame::Mute { .. }
+            | ServerFrame::Delete { .. }
+            | ServerFrame::Link { .. }
+            | ServerFrame::Linked { .. }
+            | ServerFrame::Relay(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Moves to `room`, keeping the text being written as the draft of the
+    /// previous room and bringing back the draft of the new one.
+    fn change_room(&mut self, room: String) {
+        let previous = self.session.room();
+        if *previous == room {
+            return;
+        }
+
+        let draft = self.drafts.get(&room).cloned().unwrap_or_default();
+
+        if !previous.is_empty() {
+            self.drafts.set(&previous.clone(), self.input.text());
+            self.input.set_text(&draft);
+        } else if self.input.text().is_empty() {
+            self.input.set_text(&draft);
+        }
+        self.session.set_room(room);
+    }
+
+    /// Saves the text being written as the draft of the current room, and
+    /// writes every draft to disk.
+    pub fn save_drafts(&mut self) -> Result<()> {
+        let room = self.session.room().clone();
+        if !room.is_empty() {
+            self.drafts.set(&room, self.input.text());
+        }
+        self.drafts.save()
+    }
+
+    /// Why the server closed the connection, if it did on purpose.
+    pub fn disconnected(&self) -> Option<&String> {
+        self.disconnected.as_ref()
+    }
+
+    /// Whether the user logged out, so the token must be forgotten.
+    pub fn logged_out(&self) -> bool {
+        self.logged_out
+    }
+
+    /// Whether the server refused the token, so it must be forgotten.
+    pub fn token_refused(&self) -> bool {
+        self.token_refused
+    }
+
+    /// Writes the messages received to the cache on disk.
+    pub fn save_cache(&mut self) -> Result<()> {
+        self.cache.store(self.session.messages().iter());
+        self.cache.save()
+    }
+
     fn draw(&mut self, frame: &mut Frame) {
-        let title = Line::from(" Rschat Client ").cyan().centered();
+        let title = Line::from(" Rschat Client ")
+            .fg(self.theme.title)
+            .centered();
 
         let margin_frame = frame.area().inner(Margin::new(20, 1));
         let layout = Layout::new(
             ratatui::layout::Direction::Vertical,
             [
-                Constraint::Length(1),  // Title
-                Constraint::Length(95), // Messages container
-                Constraint::Length(1),  // Info
-                Constraint::Length(3),  // InputWidget
+                Constraint::Length(1),                   // Title
+                Constraint::Length(95),                  // Messages container
+                Constraint::Length(1),                   // Info
+                Constraint::Length(self.input.height()), // InputWidget
             ],
         )
         .split(margin_frame);
 
         frame.render_widget(title, layout[0]);
-        frame.render_widget(MsgContainer::new(&self.session), layout[1]);
-        frame.render_widget(HelpWidget::new(&self.mode), layout[2]);
+        frame.render_widget(
+            MsgContainer::new(&self.session, &self.theme).search(self.search.as_ref()),
+            layout[1],
+        );
+        frame.render_widget(
+            HelpWidget::new(&self.mode, &self.theme, &self.keymap)
+                .notice(self.notice.as_ref())
+                .prompt(self.search_prompt.as_ref())
+                .latency(self.latency),
+            layout[2],
+        );
         frame.render_widget(&mut self.input, layout[3]);
+
+        if let Some((candidates, selected)) = self.input.completion()
+            && candidates.len() > 1
+        {
+            frame.render_widget(
+                CompletionPopup::new(candidates, selected, &self.theme),
+                layout[1],
+            );
+        }
+        if self.show_help {
+            frame.render_widget(HelpPopup::new(&self.theme, &self.keymap), layout[1]);
+        }
     }
 
     fn handle_events(&mut self) -> Result<bool> {
@@ -124,46 +536,353 @@ impl<'a> Client<'a> {
             if let Event::Key(key) =
                 event::read().map_err(|err| error!("Failed to read event from terminal: {err}"))?
             {
-                match key.code {
-                    KeyCode::Esc => self.switch_mode(),
-                    KeyCode::Enter => self.send_msg()?,
-                    KeyCode::Char('a') if self.mode == Mode::NormalMode => self.switch_mode(),
-                    KeyCode::Char('q') if self.mode == Mode::NormalMode => {
-                        return Ok(true);
+                self.notice = None;
+                if self.show_help {
+                    self.show_help = false;
+                    return Ok(false);
+                }
+
+                if self.input.is_searching() {
+                    return self.handle_search_key(key);
+                }
+                if self.search_prompt.is_some() {
+                    self.handle_prompt_key(key);
+                    return Ok(false);
+                }
+
+                let result = self.keymap.handle(&self.mode, key);
+                if !matches!(
+                    result,
+                    KeyResult::Action(Action::Complete | Action::CompletePrev) | KeyResult::Pending
+                ) {
+                    self.input.end_completion();
+                }
+
+                match result {
+                    KeyResult::Action(action) => return self.perform(action),
+                    KeyResult::Pending => {}
+                    KeyResult::Unbound(keys) if self.mode == Mode::InsertMode => {
+                        for key in keys {
+                            self.input.register_key(key.to_event());
+                        }
                     }
-                    _ if self.mode == Mode::InsertMode => self.input.register_key(key),
-                    _ => {}
+                    KeyResult::Unbound(_) => {}
                 }
             }
         }
         Ok(false)
     }
 
-    fn switch_mode(&mut self) {
-        match self.mode {
-            Mode::InsertMode => {
-                self.mode = Mode::NormalMode;
-                self.input.set_mode(Mode::NormalMode);
+    /// Handles a key typed during a history search. Typed text edits the
+    /// query, sending accepts the match and leaving insert mode cancels.
+    fn handle_search_key(&mut self, key: KeyEvent) -> Result<bool> {
+        match self.keymap.handle(&self.mode, key) {
+            KeyResult::Action(Action::HistorySearch) => self.input.search_older(),
+            KeyResult::Action(Action::Send) => self.input.accept_search(),
+            KeyResult::Action(Action::NormalMode) => self.input.cancel_search(),
+            KeyResult::Action(action) => {
+                self.input.accept_search();
+                return self.perform(action);
+            }
+            KeyResult::Pending => {}
+            KeyResult::Unbound(keys) => {
+                for key in keys {
+                    self.input.search_key(key.to_event());
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Handles a key typed while writing a message search. Enter runs the
+    /// search, Esc or erasing the whole search cancels it.
+    fn handle_prompt_key(&mut self, key: KeyEvent) {
+        let Some(prompt) = &mut self.search_prompt else {
+            return;
+        };
+
+        match key.code {
+            KeyCode::Char(c) => prompt.push(c),
+            KeyCode::Backspace if prompt.pop().is_none() => self.search_prompt = None,
+            KeyCode::Esc => self.search_prompt = None,
+            KeyCode::Enter => {
+                let query = self.search_prompt.take().unwrap_or_default();
+                match SearchQuery::parse(&query) {
+                    Ok(search) => {
+                        self.search = Some(search);
+                        self.search_next_page = None;
+                        self.session.clear_selection();
+                        self.jump_to_match(true);
+                        // also looks for older messages the client never received
+                        match self.request_history(None) {
+                            Ok(sent) => {
+                                self.search_waiting = sent && self.session.selected().is_none()
+                            }
+                            Err(()) => {
+                                self.notice = Some(String::from("Failed to search the server"))
+                            }
+                        }
+                    }
+                    Err(reason) => self.notice = Some(reason),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Selects the closest message matching the search that is older, or
+    /// newer, than the selected one, wrapping around at the ends.
+    fn jump_to_match(&mut self, older: bool) {
+        let Some(search) = &self.search else {
+            self.notice = Some(String::from("No search, press / to start one"));
+            return;
+        };
+
+        let matches = self.session.search(search);
+        if matches.is_empty() {
+            self.notice = Some(String::from("No message matches the search"));
+            return;
+        }
+
+        // message ids grow with time, so they also give the order of the matches
+        let idx = match (self.session.selected(), older) {
+            (Some(selected), true) => matches.iter().rposition(|&id| id < selected),
+            (Some(selected), false) => matches.iter().position(|&id| id > selected),
+            (None, _) => None,
+        };
+        if idx.is_none() && older && self.session.selected().is_some() {
+            let before_id = self.search_next_page;
+            if before_id.is_some() && self.request_history(before_id) == Ok(true) {
+                self.search_waiting = true;
+                self.notice = Some(String::from("Searching older messages..."));
+                return;
+            }
+        }
+        let idx = idx.unwrap_or(if older { matches.len() - 1 } else { 0 });
+
+        self.session.select(matches[idx], MsgContainer::CAPACITY);
+        self.notice = Some(format!(
+            "Match {} of {}",
+            matches.len() - idx,
+            matches.len()
+        ));
+    }
+
+    /// Asks the server for the messages of the room matching the search,
+    /// starting before the message `before_id` if given.
+    ///
+    /// # Returns
+    /// - `Ok(true)`: If the request was sent.
+    /// - `Ok(false)`: If the search cannot be done by the server.
+    /// - `Err(())`: If the request could not be sent, with a message logged.
+    fn request_history(&mut self, before_id: Option<u64>) -> Result<bool> {
+        let query = self
+            .search
+            .as_ref()
+            .and_then(|search| search.history_query(self.session.room(), before_id));
+        match query {
+            Some(query) => self.send_frame(&ServerFrame::Search(query)).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Executes an action triggered by the keymap.
+    ///
+    /// # Returns
+    /// - `Ok(true)`: If the client must exit.
+    /// - `Ok(false)`: If the client keeps running.
+    /// - `Err(())`: If the action failed, with a message logged.
+    fn perform(&mut self, action: Action) -> Result<bool> {
+        let visible = MsgContainer::CAPACITY;
+        match action {
+            Action::Quit => return Ok(true),
+            Action::InsertMode => self.set_mode(Mode::InsertMode),
+            Action::NormalMode if self.mode == Mode::NormalMode => {
+                self.session.clear_selection();
+                self.search = None;
             }
-            Mode::NormalMode => {
-                self.mode = Mode::InsertMode;
-                self.input.set_mode(Mode::InsertMode);
+            Action::NormalMode => {
+                self.set_compose(Compose::Message);
+                self.set_mode(Mode::NormalMode);
             }
+            Action::Send => return self.send_msg(),
+            Action::Newline => self.input.insert_newline(),
+            Action::HistoryPrev => self.input.history_prev(),
+            Action::HistoryNext => self.input.history_next(),
+            Action::HistorySearch => self.input.search_older(),
+            Action::Complete if self.input.is_completing() => self.input.complete_next(),
+            Action::Complete => self.complete(),
+            Action::CompletePrev => self.input.complete_prev(),
+            Action::SelectPrev => self.session.select_prev(visible),
+            Action::SelectNext => self.session.select_next(),
+            Action::ScrollUp => self.session.scroll_up(visible / 2, visible),
+            Action::ScrollDown => self.session.scroll_down(visible / 2),
+            Action::ScrollTop => self.session.scroll_up(usize::MAX, visible),
+            Action::ScrollBottom => self.session.scroll_down(usize::MAX),
+            Action::Search => self.search_prompt = Some(String::new()),
+            Action::SearchNext => self.jump_to_match(true),
+            Action::SearchPrev => self.jump_to_match(false),
+            Action::Reply | Action::React => match self.session.selected() {
+                Some(id) if action == Action::Reply => self.set_compose(Compose::Reply(id)),
+                Some(id) => self.set_compose(Compose::Reaction(id)),
+                None => self.notice = Some(String::from("Select a message first")),
+            },
+            Action::Delete => match self.session.selected() {
+                Some(id) => self.send_frame(&ServerFrame::Delete { id })?,
+                None => self.notice = Some(String::from("Select a message first")),
+            },
+            Action::SwitchRoom => {
+                if let Some(room) = self.session.next_room().cloned() {
+                    self.send_frame(&ServerFrame::Join { room })?;
+                }
+            }
+            Action::Help => self.show_help = true,
+        }
+        Ok(false)
+    }
+
+    /// Completes the word before the cursor: `@` names a user, `#` a room and
+    /// `/` a command when it starts the message.
+    fn complete(&mut self) {
+        let (word, starts_message) = self.input.current_word();
+        let prefixed = |prefix: &str, names: Vec<&String>| -> Vec<String> {
+            names
+                .into_iter()
+                .map(|name| format!("{prefix}{name}"))
+                .filter(|name| name.to_lowercase().starts_with(&word.to_lowercase()))
+                .collect()
+        };
+
+        let candidates = if word.starts_with('@') {
+            prefixed("@", self.session.users())
+        } else if word.starts_with('#') {
+            prefixed("#", self.session.rooms().iter().collect())
+        } else if word.starts_with('/') && starts_message {
+            COMMANDS
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .filter(|name| name.starts_with(&word))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.input.start_completion(candidates);
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.keymap.reset();
+        self.input.set_mode(mode.clone());
+        self.mode = mode;
+    }
+
+    /// Changes what the input box is sent as, entering insert mode to write
+    /// a reply or a reaction.
+    fn set_compose(&mut self, compose: Compose) {
+        let target = |id| {
+            self.session
+                .find_message(id)
+                .map(|msg| msg.author.clone())
+                .unwrap_or_default()
+        };
+        let title = match compose {
+            Compose::Message => String::from(" Input "),
+            Compose::Reply(id) => format!(" Reply to {} ", target(id)),
+            Compose::Reaction(id) => format!(" React to {} ", target(id)),
+        };
+
+        self.input.set_title(title);
+        self.compose = compose;
+        if compose != Compose::Message {
+            self.set_mode(Mode::InsertMode);
         }
     }
 
-    fn send_msg(&mut self) -> Result<()> {
-        if let Some(msg) = self.input.get_message() {
-            let msg = Message::new(msg, SystemTime::now(), self.session.user().clone());
-            let encoded =
-                serde_json::to_string(&msg).map_err(|err| error!("Failed to encode msg: {err}"))?;
-            let mut stream = self.session.clone_stream()?;
+    fn send_frame(&self, frame: &ServerFrame) -> Result<()> {
+        let mut stream = self.session.clone_stream()?;
+        stream
+            .write_all(&frame.encode())
+            .map_err(|err| error!("Failed to write to socket: {err}"))
+    }
 
-            stream
-                .write_all(&encoded.as_bytes().to_vec())
-                .map_err(|err| error!("Failed to write to socket: {err}"))?;
+    /// Sends the text of the input box, or runs it when it is a command.
+    ///
+    /// # Returns
+    /// - `Ok(true)`: If the command asks the client to exit.
+    /// - `Ok(false)`: If the client keeps running.
+    /// - `Err(())`: If the frame could not be sent, with a message logged.
+    fn send_msg(&mut self) -> Result<bool> {
+        if let Some(body) = self.input.get_message() {
+            if self.compose == Compose::Message
+                && let Some(command) = Command::parse(&body)
+            {
+                return self.run_command(command);
+            }
+
+            let author = self.session.user().clone();
+            let frame = match self.compose {
+                Compose::Reaction(id) => ServerFrame::React(Reaction {
+                    id,
+                    emoji: body,
+                    author,
+                }),
+                compose => {
+                    let mut msg = Message::new(body, SystemTime::now(), author);
+                    if let Compose::Reply(id) = compose {
+                        msg.reply_to = Some(id);
+                    }
+                    ServerFrame::Message(msg)
+                }
+            };
+
+            self.send_frame(&frame)?;
+            if let ServerFrame::Message(msg) = frame {
+                self.input.push_history(msg.body);
+            }
             self.input.clear_input();
+            self.set_compose(Compose::Message);
         }
-        Ok(())
+        Ok(false)
+    }
+
+    fn run_command(&mut self, command: std::result::Result<Command, String>) -> Result<bool> {
+        let command = match command {
+            Ok(command) => command,
+            Err(reason) => {
+                // the text stays in the input box to be fixed
+                self.notice = Some(reason);
+                return Ok(false);
+            }
+        };
+
+        self.input.clear_input();
+        match command {
+            Command::Join(room) => self.send_frame(&ServerFrame::Join { room })?,
+            Command::Help => self.show_help = true,
+            Command::Quit => return Ok(true),
+            Command::Logout => {
+                self.send_frame(&ServerFrame::Logout)?;
+                self.logged_out = true;
+                return Ok(true);
+            }
+            Command::Kick { user, reason } => {
+                self.send_frame(&ServerFrame::Kick { user, reason })?
+            }
+            Command::Ban {
+                kind,
+                target,
+                reason,
+            } => self.send_frame(&ServerFrame::Ban {
+                kind,
+                target,
+                reason,
+            })?,
+            Command::Unban { kind, target } => {
+                self.send_frame(&ServerFrame::Unban { kind, target })?
+            }
+            Command::Mute { user, seconds } => {
+                self.send_frame(&ServerFrame::Mute { user, seconds })?
+            }
+        }
+        Ok(false)
     }
 }
@@ -0,0 +1,127 @@
+use server::moderation::BanKind;
+
+/// Commands typed in the input box instead of a message, such as `/join random`.
+pub enum Command {
+    Join(String),
+    Help,
+    Quit,
+    Logout,
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    Ban {
+        kind: BanKind,
+        target: String,
+        reason: Option<String>,
+    },
+    Unban {
+        kind: BanKind,
+        target: String,
+    },
+    Mute {
+        user: String,
+        seconds: u64,
+    },
+}
+
+const BAN_USAGE: &str = "Usage: /ban user <name> [reason] or /ban ip <address> [reason]";
+const UNBAN_USAGE: &str = "Usage: /unban user <name> or /unban ip <address>";
+
+/// Commands with their description, shown in the help popup and completed
+/// with Tab.
+pub static COMMANDS: [(&str, &str); 8] = [
+    ("/join", "move to another room"),
+    ("/help", "all keybindings and commands"),
+    ("/quit", "exit"),
+    ("/logout", "exit and forget the login"),
+    ("/kick", "disconnect a user (moderators)"),
+    ("/ban", "ban a user or IP (moderators)"),
+    ("/unban", "lift a ban (moderators)"),
+    ("/mute", "mute a user for a while (moderators)"),
+];
+
+impl Command {
+    /// Parses the text of the input box.
+    ///
+    /// # Returns
+    /// - `None`: If the text is a message rather than a command.
+    /// - `Some(Ok(Command))`: If the text is a valid command.
+    /// - `Some(Err(reason))`: If the command is unknown or its arguments are wrong.
+    pub fn parse(text: &str) -> Option<std::result::Result<Self, String>> {
+        if !text.starts_with('/') {
+            return None;
+        }
+
+        let mut words = text.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let command = match (name, args.as_slice()) {
+            ("/join", [room]) => Ok(Command::Join(room.trim_start_matches('#').to_string())),
+            ("/join", _) => Err(String::from("Usage: /join <room>")),
+            ("/help", []) => Ok(Command::Help),
+            ("/quit", []) => Ok(Command::Quit),
+            ("/logout", []) => Ok(Command::Logout),
+            ("/help" | "/quit" | "/logout", _) => Err(format!("{name} takes no arguments")),
+            ("/kick", [user, reason @ ..]) => Ok(Command::Kick {
+                user: user.trim_start_matches('@').to_string(),
+                reason: Some(reason.join(" ")).filter(|reason| !reason.is_empty()),
+            }),
+            ("/kick", []) => Err(String::from("Usage: /kick <user> [reason]")),
+            ("/ban", [kind, target, reason @ ..]) => match parse_ban_kind(kind) {
+                Some(kind) => Ok(Command::Ban {
+                    kind,
+                    target: target.trim_start_matches('@').to_string(),
+                    reason: Some(reason.join(" ")).filter(|reason| !reason.is_empty()),
+                }),
+                None => Err(String::from(BAN_USAGE)),
+            },
+            ("/ban", _) => Err(String::from(BAN_USAGE)),
+            ("/unban", [kind, target]) => match parse_ban_kind(kind) {
+                Some(kind) => Ok(Command::Unban {
+                    kind,
+                    target: target.trim_start_matches('@').to_string(),
+                }),
+                None => Err(String::from(UNBAN_USAGE)),
+            },
+            ("/unban", _) => Err(String::from(UNBAN_USAGE)),
+            ("/mute", [user, duration]) => match parse_duration(duration) {
+                Some(seconds) => Ok(Command::Mute {
+                    user: user.trim_start_matches('@').to_string(),
+                    seconds,
+                }),
+                None => Err(format!(
+                    "Invalid duration '{duration}', such as 30s, 10m or 2h"
+                )),
+            },
+            ("/mute", _) => Err(String::from("Usage: /mute <user> <duration>")),
+            _ => Err(format!("Unknown command {name}")),
+        };
+        Some(command)
+    }
+}
+
+/// Parses the kind of a ban, `user` or `ip`.
+fn parse_ban_kind(text: &str) -> Option<BanKind> {
+    match text {
+        "user" => Some(BanKind::User),
+        "ip" => Some(BanKind::Ip),
+        _ => None,
+    }
+}
+
+/// Parses a duration such as `90`, `30s`, `10m`, `2h` or `1d` into seconds.
+fn parse_duration(text: &str) -> Option<u64> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => text.split_at(idx),
+        None => (text, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(scale)
+}
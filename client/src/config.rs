@@ -0,0 +1,116 @@
+use serde::Deserialize;
+use server::error;
+use server::server::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Client settings, read from `<config dir>/rschat/config.toml`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Profile used when `--profile` is not given.
+    pub default_profile: Option<String>,
+    /// Theme used when `--theme` is not given.
+    pub theme: Option<String>,
+    /// Keybindings replacing or extending the default ones.
+    pub keybindings: KeyBindings,
+    /// Named server profiles.
+    pub profiles: HashMap<String, Profile>,
+    /// How the client checks that the server is still there.
+    pub heartbeat: Heartbeat,
+}
+
+/// Pings sent to the server, whose answers give the latency of the connection.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    /// Seconds between two pings.
+    pub interval: u64,
+    /// Seconds without an answer after which the connection is considered lost.
+    pub timeout: u64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: 10,
+            timeout: 20,
+        }
+    }
+}
+
+/// Connection settings for one server.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Address of the server.
+    pub address: Option<String>,
+    /// User name used in the session.
+    pub username: Option<String>,
+    /// Whether the connection must be encrypted with TLS. rschat servers do
+    /// not speak TLS themselves, so the server needs a TLS proxy in front of it.
+    pub tls: bool,
+    /// PEM file of the certificate authority of the server, trusted besides
+    /// the usual ones. Needed for servers with a self-signed certificate.
+    pub tls_ca: Option<PathBuf>,
+}
+
+/// Keybindings of each mode, mapping a key sequence such as `"gg"` or
+/// `"<C-r>"` to an action name, or to `"none"` to remove a default binding.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub normal: HashMap<String, String>,
+    pub insert: HashMap<String, String>,
+}
+
+impl Config {
+    /// Location of the config file in the user's configuration directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rschat").join("config.toml"))
+    }
+
+    /// Reads and parses a configuration file.
+    ///
+    /// # Arguments
+    /// - `path`: Location of the TOML file.
+    /// - `required`: Whether a missing file is an error. When `false`, a missing
+    ///   file results in the default configuration.
+    ///
+    /// # Returns
+    /// - `Ok(Config)`: If the file could be read and parsed.
+    /// - `Err(())`: If the file is unreadable or malformed, with a message logged.
+    pub fn load(path: &Path, required: bool) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                error!("Failed to read config file {}: {err}", path.display());
+                return Err(());
+            }
+        };
+
+        toml::from_str(&content)
+            .map_err(|err| error!("Invalid config file {}: {err}", path.display()))
+    }
+
+    /// Returns the profile named `name`, or the default profile when `name` is `None`.
+    ///
+    /// # Returns
+    /// - `Ok(Profile)`: The selected profile, or an empty one if no profile is selected.
+    /// - `Err(())`: If the selected profile does not exist, with a message logged.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name.or(self.default_profile.as_deref()) {
+            Some(name) => self
+                .profiles
+                .get(name)
+                .cloned()
+                .ok_or_else(|| error!("Profile '{name}' not found in config file")),
+            None => Ok(Profile::default()),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use server::error;
+use server::server::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Unsent messages of each room, kept in `<data dir>/rschat/drafts.json` so
+/// they survive restarts. Drafts are stored per server address.
+pub struct Drafts {
+    // location of the drafts file, if the platform has a data directory
+    path: Option<PathBuf>,
+    // address of the server the drafts belong to
+    server: String,
+    // drafts of every server, by server address and room
+    drafts: HashMap<String, HashMap<String, String>>,
+}
+
+impl Drafts {
+    /// Reads the drafts file. A missing or unreadable file gives no drafts.
+    pub fn load(server: String) -> Self {
+        let path = dirs::data_dir().map(|dir| dir.join("rschat").join("drafts.json"));
+        let drafts = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(content)) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                error!("Ignoring malformed drafts file: {err}");
+                HashMap::new()
+            }),
+            Some(Err(err)) if err.kind() != ErrorKind::NotFound => {
+                error!("Failed to read drafts file: {err}");
+                HashMap::new()
+            }
+            _ => HashMap::new(),
+        };
+
+        Self {
+            path,
+            server,
+            drafts,
+        }
+    }
+
+    pub fn get(&self, room: &str) -> Option<&String> {
+        self.drafts.get(&self.server)?.get(room)
+    }
+
+    /// Stores the draft of `room`, removing it when `text` is empty.
+    pub fn set(&mut self, room: &str, text: String) {
+        let rooms = self.drafts.entry(self.server.clone()).or_default();
+        if text.is_empty() {
+            rooms.remove(room);
+        } else {
+            rooms.insert(room.to_string(), text);
+        }
+    }
+
+    /// Writes the drafts to disk.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the drafts were saved, or there is no data directory.
+    /// - `Err(())`: If the file could not be written, with a message logged.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|err| error!("Failed to create {}: {err}", dir.display()))?;
+        }
+        let content = serde_json::to_string(&self.drafts)
+            .map_err(|err| error!("Failed to encode drafts: {err}"))?;
+        fs::write(path, content).map_err(|err| error!("Failed to save drafts: {err}"))
+    }
+}
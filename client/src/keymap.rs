@@ -0,0 +1,480 @@
+use crate::client::Mode;
+use crate::config::KeyBindings;
+use ratatui::crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use server::error;
+use server::server::Result;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    Quit,
+    InsertMode,
+    NormalMode,
+    Send,
+    Newline,
+    HistoryPrev,
+    HistoryNext,
+    HistorySearch,
+    Complete,
+    CompletePrev,
+    SelectPrev,
+    SelectNext,
+    ScrollUp,
+    ScrollDown,
+    ScrollTop,
+    ScrollBottom,
+    Search,
+    SearchNext,
+    SearchPrev,
+    Reply,
+    React,
+    Delete,
+    SwitchRoom,
+    Help,
+}
+
+impl Action {
+    const ALL: [Action; 24] = [
+        Action::Quit,
+        Action::InsertMode,
+        Action::NormalMode,
+        Action::Send,
+        Action::Newline,
+        Action::HistoryPrev,
+        Action::HistoryNext,
+        Action::HistorySearch,
+        Action::Complete,
+        Action::CompletePrev,
+        Action::SelectPrev,
+        Action::SelectNext,
+        Action::ScrollUp,
+        Action::ScrollDown,
+        Action::ScrollTop,
+        Action::ScrollBottom,
+        Action::Search,
+        Action::SearchNext,
+        Action::SearchPrev,
+        Action::Reply,
+        Action::React,
+        Action::Delete,
+        Action::SwitchRoom,
+        Action::Help,
+    ];
+
+    /// Name of the action in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::InsertMode => "insert_mode",
+            Action::NormalMode => "normal_mode",
+            Action::Send => "send",
+            Action::Newline => "newline",
+            Action::HistoryPrev => "history_prev",
+            Action::HistoryNext => "history_next",
+            Action::HistorySearch => "history_search",
+            Action::Complete => "complete",
+            Action::CompletePrev => "complete_prev",
+            Action::SelectPrev => "select_prev",
+            Action::SelectNext => "select_next",
+            Action::ScrollUp => "scroll_up",
+            Action::ScrollDown => "scroll_down",
+            Action::ScrollTop => "scroll_top",
+            Action::ScrollBottom => "scroll_bottom",
+            Action::Search => "search",
+            Action::SearchNext => "search_next",
+            Action::SearchPrev => "search_prev",
+            Action::Reply => "reply",
+            Action::React => "react",
+            Action::Delete => "delete",
+            Action::SwitchRoom => "switch_room",
+            Action::Help => "help",
+        }
+    }
+
+    /// Short description shown in the help line and popup.
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::Quit => "exit",
+            Action::InsertMode => "edit message",
+            Action::NormalMode => "stop editing or clear selection",
+            Action::Send => "send message",
+            Action::Newline => "new line",
+            Action::HistoryPrev => "previous sent message",
+            Action::HistoryNext => "next sent message",
+            Action::HistorySearch => "search sent messages",
+            Action::Complete => "complete @name, /command or #room",
+            Action::CompletePrev => "previous completion",
+            Action::SelectPrev => "select older message",
+            Action::SelectNext => "select newer message",
+            Action::ScrollUp => "scroll up",
+            Action::ScrollDown => "scroll down",
+            Action::ScrollTop => "scroll to oldest",
+            Action::ScrollBottom => "scroll to newest",
+            Action::Search => "search messages",
+            Action::SearchNext => "older match",
+            Action::SearchPrev => "newer match",
+            Action::Reply => "reply to selected",
+            Action::React => "react to selected",
+            Action::Delete => "delete selected",
+            Action::SwitchRoom => "next room",
+            Action::Help => "all keybindings",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.name() == name)
+    }
+}
+
+/// A key together with its modifiers, such as `<C-r>`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyChord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        // the case of a character already tells whether shift was pressed
+        let (code, modifiers) = match code {
+            KeyCode::Char(_) => (code, modifiers - KeyModifiers::SHIFT),
+            // terminals report Shift-Tab as a key of its own
+            KeyCode::BackTab => (KeyCode::Tab, modifiers | KeyModifiers::SHIFT),
+            _ => (code, modifiers),
+        };
+        Self { code, modifiers }
+    }
+
+    pub fn to_event(self) -> KeyEvent {
+        KeyEvent::new(self.code, self.modifiers)
+    }
+}
+
+impl From<KeyEvent> for KeyChord {
+    fn from(key: KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+}
+
+static KEY_NAMES: [(&str, KeyCode); 14] = [
+    ("Enter", KeyCode::Enter),
+    ("Esc", KeyCode::Esc),
+    ("Tab", KeyCode::Tab),
+    ("BS", KeyCode::Backspace),
+    ("Del", KeyCode::Delete),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Space", KeyCode::Char(' ')),
+];
+
+impl Display for KeyChord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = KEY_NAMES
+            .iter()
+            .find(|(_, code)| *code == self.code)
+            .map(|(name, _)| name.to_string());
+
+        let mut prefix = String::new();
+        for (modifier, letter) in [
+            (KeyModifiers::CONTROL, "C-"),
+            (KeyModifiers::ALT, "A-"),
+            (KeyModifiers::SHIFT, "S-"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                prefix.push_str(letter);
+            }
+        }
+
+        match (name, self.code) {
+            (None, KeyCode::Char('<')) => write!(f, "<{prefix}lt>"),
+            (None, KeyCode::Char(c)) if prefix.is_empty() => write!(f, "{c}"),
+            (None, KeyCode::Char(c)) => write!(f, "<{prefix}{c}>"),
+            (Some(name), _) => write!(f, "<{prefix}{name}>"),
+            (None, code) => write!(f, "<{prefix}{code}>"),
+        }
+    }
+}
+
+/// Parses a key sequence written like in vim, such as `gg`, `<C-r>` or `<S-Enter>`.
+pub fn parse_keys(keys: &str) -> std::result::Result<Vec<KeyChord>, String> {
+    let mut chords = Vec::new();
+    let mut chars = keys.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '<' {
+            chords.push(KeyChord::new(KeyCode::Char(c), KeyModifiers::NONE));
+            continue;
+        }
+
+        let special: String = chars.by_ref().take_while(|&c| c != '>').collect();
+        let mut name = special.as_str();
+        let mut modifiers = KeyModifiers::NONE;
+        while let Some((modifier, rest)) = name.split_once('-').filter(|(m, _)| m.len() == 1) {
+            modifiers |= match modifier {
+                "C" => KeyModifiers::CONTROL,
+                "A" => KeyModifiers::ALT,
+                "S" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier '{modifier}' in '{keys}'")),
+            };
+            name = rest;
+        }
+
+        let code = if name == "lt" {
+            KeyCode::Char('<')
+        } else if let Some((_, code)) = KEY_NAMES.iter().find(|(key, _)| *key == name) {
+            *code
+        } else {
+            let mut name_chars = name.chars();
+            match (name_chars.next(), name_chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                _ => return Err(format!("unknown key '<{special}>' in '{keys}'")),
+            }
+        };
+        chords.push(KeyChord::new(code, modifiers));
+    }
+
+    if chords.is_empty() {
+        return Err(String::from("empty key sequence"));
+    }
+    Ok(chords)
+}
+
+pub struct Binding {
+    pub keys: Vec<KeyChord>,
+    pub action: Action,
+}
+
+pub enum KeyResult {
+    // the keys typed so far triggered an action
+    Action(Action),
+    // the keys typed so far start a longer sequence
+    Pending,
+    // the keys typed so far are not bound to anything
+    Unbound(Vec<KeyChord>),
+}
+
+pub struct Keymap {
+    // bindings available in normal mode
+    normal: Vec<Binding>,
+    // bindings available in insert mode
+    insert: Vec<Binding>,
+    // keys of a sequence being typed
+    pending: Vec<KeyChord>,
+}
+
+static DEFAULT_NORMAL: [(&str, Action); 22] = [
+    ("q", Action::Quit),
+    ("a", Action::InsertMode),
+    ("i", Action::InsertMode),
+    ("k", Action::SelectPrev),
+    ("<Up>", Action::SelectPrev),
+    ("j", Action::SelectNext),
+    ("<Down>", Action::SelectNext),
+    ("<C-u>", Action::ScrollUp),
+    ("<PageUp>", Action::ScrollUp),
+    ("<C-d>", Action::ScrollDown),
+    ("<PageDown>", Action::ScrollDown),
+    ("gg", Action::ScrollTop),
+    ("G", Action::ScrollBottom),
+    ("/", Action::Search),
+    ("n", Action::SearchNext),
+    ("N", Action::SearchPrev),
+    ("r", Action::Reply),
+    ("+", Action::React),
+    ("dd", Action::Delete),
+    ("gt", Action::SwitchRoom),
+    ("?", Action::Help),
+    ("<Esc>", Action::NormalMode),
+];
+
+static DEFAULT_INSERT: [(&str, Action); 9] = [
+    ("<Esc>", Action::NormalMode),
+    ("<Enter>", Action::Send),
+    ("<S-Enter>", Action::Newline),
+    ("<A-Enter>", Action::Newline),
+    ("<Up>", Action::HistoryPrev),
+    ("<Down>", Action::HistoryNext),
+    ("<C-r>", Action::HistorySearch),
+    ("<Tab>", Action::Complete),
+    ("<S-Tab>", Action::CompletePrev),
+];
+
+impl Keymap {
+    /// Creates the keymap from the default bindings, replaced or extended by
+    /// the bindings of the config file.
+    ///
+    /// # Returns
+    /// - `Ok(Keymap)`: If every configured binding is valid.
+    /// - `Err(())`: If a key sequence or action is invalid, with a message logged.
+    pub fn build(config: &KeyBindings) -> Result<Self> {
+        Ok(Self {
+            normal: Self::bindings_for(&DEFAULT_NORMAL, &config.normal)?,
+            insert: Self::bindings_for(&DEFAULT_INSERT, &config.insert)?,
+            pending: Vec::new(),
+        })
+    }
+
+    fn bindings_for(
+        defaults: &[(&str, Action)],
+        configured: &HashMap<String, String>,
+    ) -> Result<Vec<Binding>> {
+        let mut bindings: Vec<Binding> = defaults
+            .iter()
+            .map(|(keys, action)| Binding {
+                keys: parse_keys(keys).expect("default bindings are valid"),
+                action: *action,
+            })
+            .collect();
+
+        let mut valid = true;
+        let mut configured: Vec<_> = configured.iter().collect();
+        configured.sort();
+
+        for (keys, action) in configured {
+            let keys = match parse_keys(keys) {
+                Ok(keys) => keys,
+                Err(err) => {
+                    error!("Invalid keybinding: {err}");
+                    valid = false;
+                    continue;
+                }
+            };
+
+            bindings.retain(|binding| binding.keys != keys);
+            if action == "none" {
+                continue;
+            }
+
+            match Action::from_name(action) {
+                Some(action) => bindings.push(Binding { keys, action }),
+                None => {
+                    error!("Invalid keybinding: unknown action '{action}'");
+                    valid = false;
+                }
+            }
+        }
+
+        if valid { Ok(bindings) } else { Err(()) }
+    }
+
+    /// Bindings available in `mode`.
+    pub fn bindings(&self, mode: &Mode) -> &[Binding] {
+        match mode {
+            Mode::NormalMode => &self.normal,
+            Mode::InsertMode => &self.insert,
+        }
+    }
+
+    /// Keys of the sequence being typed.
+    pub fn pending(&self) -> &[KeyChord] {
+        &self.pending
+    }
+
+    /// Feeds a key press to the keymap. A sequence that is the prefix of a
+    /// longer binding waits for more keys, even if it is bound itself.
+    pub fn handle(&mut self, mode: &Mode, key: KeyEvent) -> KeyResult {
+        self.pending.push(KeyChord::from(key));
+        let bindings = match mode {
+            Mode::NormalMode => &self.normal,
+            Mode::InsertMode => &self.insert,
+        };
+
+        let longer = bindings.iter().any(|binding| {
+            binding.keys.len() > self.pending.len() && binding.keys.starts_with(&self.pending)
+        });
+        if longer {
+            return KeyResult::Pending;
+        }
+
+        let keys = std::mem::take(&mut self.pending);
+        match bindings.iter().find(|binding| binding.keys == keys) {
+            Some(binding) => KeyResult::Action(binding.action),
+            None => KeyResult::Unbound(keys),
+        }
+    }
+
+    /// Forgets the sequence being typed.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(code: KeyCode, modifiers: KeyModifiers) -> KeyChord {
+        KeyChord::new(code, modifiers)
+    }
+
+    #[test]
+    fn plain_characters() {
+        assert_eq!(
+            parse_keys("gg").unwrap(),
+            vec![
+                chord(KeyCode::Char('g'), KeyModifiers::NONE),
+                chord(KeyCode::Char('g'), KeyModifiers::NONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn special_keys_and_modifiers() {
+        assert_eq!(
+            parse_keys("<C-r>").unwrap(),
+            vec![chord(KeyCode::Char('r'), KeyModifiers::CONTROL)]
+        );
+        assert_eq!(
+            parse_keys("<C-A-Up>x").unwrap(),
+            vec![
+                chord(KeyCode::Up, KeyModifiers::CONTROL | KeyModifiers::ALT),
+                chord(KeyCode::Char('x'), KeyModifiers::NONE),
+            ]
+        );
+        assert_eq!(
+            parse_keys("<S-Enter><Space>").unwrap(),
+            vec![
+                chord(KeyCode::Enter, KeyModifiers::SHIFT),
+                chord(KeyCode::Char(' '), KeyModifiers::NONE),
+            ]
+        );
+        assert_eq!(
+            parse_keys("<lt>").unwrap(),
+            vec![chord(KeyCode::Char('<'), KeyModifiers::NONE)]
+        );
+    }
+
+    #[test]
+    fn shift_is_part_of_characters_and_back_tab() {
+        assert_eq!(
+            parse_keys("<S-g>").unwrap(),
+            vec![chord(KeyCode::Char('g'), KeyModifiers::NONE)]
+        );
+        assert_eq!(
+            KeyChord::from(KeyEvent::new(KeyCode::BackTab, KeyModifiers::SHIFT)),
+            parse_keys("<S-Tab>").unwrap()[0]
+        );
+    }
+
+    #[test]
+    fn invalid_sequences() {
+        assert!(parse_keys("").is_err());
+        assert!(parse_keys("<X-a>").is_err());
+        assert!(parse_keys("<Nope>").is_err());
+    }
+
+    #[test]
+    fn chords_display_as_they_are_parsed() {
+        for keys in ["g", "<C-r>", "<A-Enter>", "<S-Tab>", "<lt>", "<PageDown>"] {
+            let chords = parse_keys(keys).unwrap();
+            assert_eq!(chords[0].to_string(), keys);
+        }
+    }
+}
@@ -1,32 +1,193 @@
 use clap::Parser;
-use client::Client;
+use client::{Client, Credentials};
+use config::Config;
+use keymap::Keymap;
+use ratatui::crossterm::event::{
+    KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::supports_keyboard_enhancement;
+use server::error;
 use server::server::Result;
+use std::io::stdout;
+use std::path::PathBuf;
+use theme::Theme;
+use tokens::Tokens;
 
+mod cache;
 mod client;
+mod commands;
+mod config;
+mod drafts;
+mod keymap;
+mod search;
 mod session;
+mod stream;
+mod theme;
+mod tokens;
 mod widgets;
 
 #[derive(clap::Parser)]
 #[command(version, about = "Real time multi-user chat client", long_about = None)]
 struct Cli {
     #[arg(long, short, help = "User identifier in the connection")]
-    user: String,
+    user: Option<String>,
 
     #[arg(
         long,
         short,
-        help = "Network PORT used to connect to server", 
-        default_value_t = String::from("127.0.0.1:8080")
+        help = "Network PORT used to connect to server, or unix:PATH for a Unix socket (default: 127.0.0.1:8080)"
     )]
-    address: String,
+    address: Option<String>,
+
+    #[arg(long, short, help = "Server profile from the config file")]
+    profile: Option<String>,
+
+    #[arg(long, short, help = "Path to the config file")]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        short,
+        help = "Color theme: dark, light, high-contrast or a user theme (default: dark)"
+    )]
+    theme: Option<String>,
+
+    #[arg(long, help = "Password of the user, asked for when not given")]
+    password: Option<String>,
+
+    #[arg(long, help = "Create the user account on the server before logging in")]
+    register: bool,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let mut client = Client::build(cli.address, cli.user)?;
-    client
-        .run(&mut ratatui::init())
-        .map_err(|_| ratatui::restore())?;
+    let config = match (&cli.config, Config::default_path()) {
+        (Some(path), _) => Config::load(path, true)?,
+        (None, Some(path)) => Config::load(&path, false)?,
+        (None, None) => Config::default(),
+    };
+
+    let profile = config.profile(cli.profile.as_deref())?;
+    let tls = match profile.tls {
+        true => Some(stream::tls_config(profile.tls_ca.as_deref())?),
+        false => None,
+    };
+
+    let user = cli
+        .user
+        .or(profile.username)
+        .ok_or_else(|| error!("No user name given: use --user or set one in a profile"))?;
+    let address = cli
+        .address
+        .or(profile.address)
+        .unwrap_or_else(|| String::from("127.0.0.1:8080"));
+
+    let theme = match cli.theme.or(config.theme) {
+        Some(name) => Theme::load(&name)?,
+        None => Theme::default(),
+    };
+
+    let keymap = Keymap::build(&config.keybindings)?;
+
+    // a stored token logs in without the password, unless one is given
+    let mut tokens = Tokens::load();
+    let resumed = match tokens.get(&address, &user) {
+        Some(token) if cli.password.is_none() && !cli.register => {
+            let credentials = Credentials::Token(token.clone());
+            match Client::connect(&address, tls.as_ref(), &user, &credentials) {
+                Ok(login) => Some(login),
+                Err(reason) => {
+                    error!("{reason}");
+                    // the session expired or was revoked
+                    if reason.starts_with(Client::LOGIN_FAILED) {
+                        tokens.set(&address, &user, None);
+                        tokens.save()?;
+                    }
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    // over a Unix socket the server may trust the system user instead
+    let resumed = match resumed {
+        None if address.starts_with("unix:") && cli.password.is_none() && !cli.register => {
+            let credentials = Credentials::Password {
+                password: String::new(),
+                register: false,
+            };
+            Client::connect(&address, tls.as_ref(), &user, &credentials).ok()
+        }
+        resumed => resumed,
+    };
+    let login = match resumed {
+        Some(login) => login,
+        None => {
+            let password = match cli.password {
+                Some(password) => password,
+                None => ask_password(&user, cli.register)?,
+            };
+            let credentials = Credentials::Password {
+                password,
+                register: cli.register,
+            };
+            Client::connect(&address, tls.as_ref(), &user, &credentials)
+                .map_err(|reason| error!("{reason}"))?
+        }
+    };
+    tokens.set(&address, &user, Some(login.token.clone()));
+    tokens.save()?;
+
+    let mut client = Client::build(
+        address.clone(),
+        tls,
+        user.clone(),
+        login,
+        theme,
+        keymap,
+        config.heartbeat,
+    )?;
+    let mut terminal = ratatui::init();
+
+    // lets terminals that support it report modified keys such as Shift-Enter
+    let enhanced = supports_keyboard_enhancement().unwrap_or(false);
+    if enhanced {
+        let flags = KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES;
+        let _ = execute!(stdout(), PushKeyboardEnhancementFlags(flags));
+    }
+
+    let result = client.run(&mut terminal);
+
+    if enhanced {
+        let _ = execute!(stdout(), PopKeyboardEnhancementFlags);
+    }
     ratatui::restore();
-    Ok(())
+
+    client.save_drafts()?;
+    client.save_cache()?;
+    if let Some(reason) = client.disconnected() {
+        error!("{reason}");
+    }
+    // other disconnections keep the session, to be resumed on the next start
+    if client.logged_out() || client.token_refused() {
+        tokens.set(&address, &user, None);
+        tokens.save()?;
+    }
+    result
+}
+
+/// Reads the password from the terminal without echoing it, twice when it is
+/// for a new account.
+fn ask_password(user: &str, register: bool) -> Result<String> {
+    let read = |prompt: String| {
+        rpassword::prompt_password(prompt).map_err(|err| error!("Failed to read password: {err}"))
+    };
+
+    let password = read(format!("Password for {user}: "))?;
+    if register && read(String::from("Repeat the password: "))? != password {
+        error!("The passwords do not match");
+        return Err(());
+    }
+    Ok(password)
 }
@@ -0,0 +1,203 @@
+use chrono::{DateTime, Days, Local, NaiveDate, NaiveTime, TimeZone};
+use regex::Regex;
+use server::event::Message;
+use server::history::HistoryQuery;
+use std::ops::Range;
+
+/// A message search typed after `/` in normal mode, such as
+/// `from:alice before:2026-10-01 deploy`.
+///
+/// The words without a filter are looked for in the message bodies, ignoring
+/// case. Wrapped in slashes, like `/err(or)?/`, they are a regular expression.
+pub struct SearchQuery {
+    // text looked for in the bodies
+    pattern: Option<Regex>,
+    // words of the search when they are not a regular expression
+    text: Option<String>,
+    // author of the messages
+    from: Option<String>,
+    // messages posted before the start of this date
+    before: Option<DateTime<Local>>,
+    // messages posted after the end of this date
+    after: Option<DateTime<Local>>,
+}
+
+impl SearchQuery {
+    /// Messages asked to the server at once.
+    const PAGE: usize = 50;
+
+    /// Parses a search typed by the user.
+    ///
+    /// # Returns
+    /// - `Ok(SearchQuery)`: If the filters and the pattern are valid.
+    /// - `Err(reason)`: If the search is empty, a date is malformed or the
+    ///   regular expression is invalid.
+    pub fn parse(query: &str) -> std::result::Result<Self, String> {
+        let mut search = Self {
+            pattern: None,
+            text: None,
+            from: None,
+            before: None,
+            after: None,
+        };
+
+        let mut words = Vec::new();
+        for word in query.split_whitespace() {
+            match word.split_once(':') {
+                Some(("from", user)) => search.from = Some(user.trim_start_matches('@').into()),
+                Some(("before", date)) => search.before = Some(start_of_day(date, 0)?),
+                Some(("after", date)) => search.after = Some(start_of_day(date, 1)?),
+                _ => words.push(word),
+            }
+        }
+
+        let text = words.join(" ");
+        if let Some(regex) = text
+            .strip_prefix('/')
+            .and_then(|text| text.strip_suffix('/'))
+        {
+            let regex = Regex::new(regex).map_err(|_| format!("Invalid regex /{regex}/"))?;
+            search.pattern = Some(regex);
+        } else if !text.is_empty() {
+            let regex = Regex::new(&format!("(?i){}", regex::escape(&text)))
+                .expect("escaped text is a valid regex");
+            search.pattern = Some(regex);
+            search.text = Some(text);
+        }
+
+        let filtered = search.from.is_some() || search.before.is_some() || search.after.is_some();
+        if search.pattern.is_none() && !filtered {
+            return Err(String::from("Empty search"));
+        }
+        Ok(search)
+    }
+
+    pub fn matches(&self, msg: &Message) -> bool {
+        let date = DateTime::<Local>::from(msg.date);
+        self.from.as_ref().is_none_or(|from| msg.author == *from)
+            && self.before.is_none_or(|before| date < before)
+            && self.after.is_none_or(|after| date >= after)
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(&msg.body))
+    }
+
+    /// The same search over the history of the server, restricted to `room`.
+    /// Regular expressions are only matched against the messages received.
+    pub fn history_query(&self, room: &str, before_id: Option<u64>) -> Option<HistoryQuery> {
+        if self.pattern.is_some() && self.text.is_none() {
+            return None;
+        }
+
+        Some(HistoryQuery {
+            text: self.text.clone().unwrap_or_default(),
+            author: self.from.clone(),
+            room: Some(room.to_string()),
+            after: self.after.map(Into::into),
+            before: self.before.map(Into::into),
+            before_id,
+            after_id: None,
+            limit: Some(Self::PAGE),
+        })
+    }
+
+    /// Byte ranges of `text` matching the pattern, to be highlighted.
+    pub fn ranges(&self, text: &str) -> Vec<Range<usize>> {
+        self.pattern
+            .iter()
+            .flat_map(|pattern| pattern.find_iter(text))
+            .filter(|found| !found.is_empty())
+            .map(|found| found.range())
+            .collect()
+    }
+}
+
+/// Start of the day `days` after a `YYYY-MM-DD` date, in local time.
+fn start_of_day(date: &str, days: u64) -> std::result::Result<DateTime<Local>, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.checked_add_days(Days::new(days)))
+        .and_then(|date| {
+            Local
+                .from_local_datetime(&date.and_time(NaiveTime::MIN))
+                .earliest()
+        })
+        .ok_or_else(|| format!("Invalid date '{date}', expected YYYY-MM-DD"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(author: &str, body: &str) -> Message {
+        Message::new(
+            body.to_string(),
+            std::time::SystemTime::now(),
+            author.to_string(),
+        )
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Local> {
+        let date = NaiveDate::from_ymd_opt(year, month, day).unwrap();
+        Local
+            .from_local_datetime(&date.and_time(NaiveTime::MIN))
+            .earliest()
+            .unwrap()
+    }
+
+    #[test]
+    fn words_and_filters() {
+        let search = SearchQuery::parse("from:@alice Deploy   done").unwrap();
+        assert_eq!(search.from.as_deref(), Some("alice"));
+        assert_eq!(search.text.as_deref(), Some("Deploy done"));
+        assert!(search.matches(&message("alice", "the deploy DONE at last")));
+        assert!(!search.matches(&message("bob", "deploy done")));
+        assert!(!search.matches(&message("alice", "done deploy")));
+    }
+
+    #[test]
+    fn dates_cover_whole_days() {
+        let search = SearchQuery::parse("after:2026-10-01 before:2026-10-05").unwrap();
+        assert_eq!(search.after, Some(date(2026, 10, 2)));
+        assert_eq!(search.before, Some(date(2026, 10, 5)));
+        assert!(search.pattern.is_none());
+    }
+
+    #[test]
+    fn regular_expressions() {
+        let search = SearchQuery::parse("/err(or)?s/").unwrap();
+        assert!(search.text.is_none());
+        assert!(search.matches(&message("bob", "two errors")));
+        assert!(!search.matches(&message("bob", "two ERRORS")));
+        assert!(search.history_query("general", None).is_none());
+        assert_eq!(search.ranges("errs and errors"), vec![0..4, 9..15]);
+    }
+
+    #[test]
+    fn special_characters_are_matched_literally() {
+        let search = SearchQuery::parse("1+1 (maybe)").unwrap();
+        assert!(search.matches(&message("bob", "is 1+1 (MAYBE) two?")));
+        assert!(!search.matches(&message("bob", "11 maybe")));
+    }
+
+    #[test]
+    fn history_queries_keep_the_filters() {
+        let search = SearchQuery::parse("from:alice after:2026-10-01 deploy").unwrap();
+        let query = search.history_query("general", Some(42)).unwrap();
+        assert_eq!(query.text, "deploy");
+        assert_eq!(query.author.as_deref(), Some("alice"));
+        assert_eq!(query.room.as_deref(), Some("general"));
+        assert_eq!(query.after, Some(date(2026, 10, 2).into()));
+        assert_eq!(query.before_id, Some(42));
+    }
+
+    #[test]
+    fn invalid_searches() {
+        assert!(SearchQuery::parse("").is_err());
+        assert!(SearchQuery::parse("   ").is_err());
+        assert!(SearchQuery::parse("before:yesterday").is_err());
+        assert!(SearchQuery::parse("after:2026-13-01 deploy").is_err());
+        assert!(SearchQuery::parse("/(unclosed/").is_err());
+    }
+}
@@ -1,82 +1,385 @@
-use rand::random_range;
+use crate::search::SearchQuery;
+use crate::stream::Stream;
+use crate::theme::Theme;
 use ratatui::style::Color;
+use server::error;
+use server::event::{Message, Reaction};
+use server::protocol::Welcome;
 use server::server::Result;
-use server::{error, event::Message};
-use std::{collections::HashMap, net::TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::net::Shutdown;
+use std::str::FromStr;
 
 pub struct Session {
     // server socket
-    stream: TcpStream,
+    stream: Stream,
     // color of the user messages
-    user_colors: HashMap<String, Color>,
+    user_colors: HashMap<String, UserColor>,
+    // colors assigned to the other users
+    palette: Vec<Color>,
     //user identifier
     user_name: String,
     // Users messages
     messages: Vec<Message>,
+    // reactions to each message
+    reactions: HashMap<u64, Vec<Reaction>>,
+    // rooms available on the server
+    rooms: Vec<String>,
+    // room the client is in
+    room: String,
+    // number of the newest messages of the room hidden below the view
+    scroll: usize,
+    // identifier of the selected message
+    selected: Option<u64>,
+    // what the server told about itself on connection
+    welcome: Option<Welcome>,
 }
 
-static COLORS: [Color; 14] = [
-    Color::Red,
-    Color::Green,
-    Color::Yellow,
-    Color::Blue,
-    Color::Magenta,
-    Color::Gray,
-    Color::DarkGray,
-    Color::LightRed,
-    Color::LightGreen,
-    Color::LightYellow,
-    Color::LightBlue,
-    Color::LightMagenta,
-    Color::LightCyan,
-    Color::White,
-];
+#[derive(Clone, Copy)]
+enum UserColor {
+    // index in the palette derived from the user name
+    Palette(usize),
+    // color chosen by the theme or advertised by the server
+    Fixed(Color),
+}
+
+/// FNV-1a hash of a user name. Unlike `DefaultHasher`, its output never changes
+/// between builds, so every client derives the same color for the same name.
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
 
 impl Session {
-    pub fn new(stream: TcpStream, user_name: String) -> Self {
+    pub fn new(stream: Stream, user_name: String, theme: &Theme) -> Self {
         let mut user_colors = HashMap::new();
-        // the client user is always cyan
-        user_colors.insert(user_name.clone(), Color::Cyan);
+        // the client user always has the theme's own color
+        user_colors.insert(user_name.clone(), UserColor::Fixed(theme.self_user));
         Self {
             stream,
             user_name,
             messages: Vec::new(),
             user_colors,
+            palette: theme.users.clone(),
+            reactions: HashMap::new(),
+            rooms: Vec::new(),
+            room: String::new(),
+            scroll: 0,
+            selected: None,
+            welcome: None,
         }
     }
 
-    pub fn assign_user_color(&mut self, user_name: String) {
-        if !self.user_colors.contains_key(&user_name) {
-            self.user_colors
-                .insert(user_name, COLORS[random_range(0..15)]);
+    /// Stores a message, registering its author's color. A color advertised by
+    /// the server takes precedence over the one derived from the author's name.
+    pub fn new_message(&mut self, msg: Message) {
+        self.register_author(&msg);
+
+        // keeps the view still while the user is reading older messages
+        if msg.room == self.room && self.scroll > 0 {
+            self.scroll += 1;
         }
-    }
 
-    pub fn new_message(&mut self, msg: Message) {
         self.messages.push(msg);
     }
 
-    pub fn clone_stream(&self) -> Result<TcpStream> {
+    /// Stores older messages returned by a history search, ignoring the ones
+    /// already received.
+    pub fn add_history(&mut self, messages: Vec<Message>) {
+        for msg in messages {
+            if self.find_message(msg.id).is_none() {
+                self.register_author(&msg);
+                self.messages.push(msg);
+            }
+        }
+        // message ids grow with time, so they give the order of the messages
+        self.messages.sort_by_key(|msg| msg.id);
+    }
+
+    /// Forgets the messages of `room`, when they do not match the server's history.
+    pub fn forget_room(&mut self, room: &str) {
+        self.messages.retain(|msg| msg.room != room);
+        self.scroll = 0;
+        self.selected = None;
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Identifier of the newest message of `room`, `0` when there are none.
+    pub fn last_id(&self, room: &str) -> u64 {
+        self.messages
+            .iter()
+            .filter(|msg| msg.room == room)
+            .map(|msg| msg.id)
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn register_author(&mut self, msg: &Message) {
+        if msg.author == self.user_name {
+            return;
+        }
+
+        let advertised = msg
+            .color
+            .as_deref()
+            .and_then(|color| Color::from_str(color).ok());
+        let color = match advertised {
+            Some(color) => UserColor::Fixed(color),
+            None => UserColor::Palette(name_hash(&msg.author) as usize % self.palette.len()),
+        };
+        self.user_colors.insert(msg.author.clone(), color);
+    }
+
+    /// Stores a reaction, ignoring repeated ones.
+    pub fn new_reaction(&mut self, reaction: Reaction) {
+        let reactions = self.reactions.entry(reaction.id).or_default();
+        if !reactions
+            .iter()
+            .any(|r| r.author == reaction.author && r.emoji == reaction.emoji)
+        {
+            reactions.push(reaction);
+        }
+    }
+
+    /// Forgets a message deleted on the server, with its reactions.
+    pub fn delete_message(&mut self, id: u64) {
+        self.messages.retain(|msg| msg.id != id);
+        self.reactions.remove(&id);
+        if self.selected == Some(id) {
+            self.selected = None;
+        }
+        // the view cannot start past the oldest message left
+        self.scroll = self.scroll.min(self.room_ids().len().saturating_sub(1));
+    }
+
+    pub fn reactions(&self, id: u64) -> &[Reaction] {
+        self.reactions
+            .get(&id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn find_message(&self, id: u64) -> Option<&Message> {
+        self.messages.iter().find(|msg| msg.id == id)
+    }
+
+    pub fn rooms(&self) -> &[String] {
+        &self.rooms
+    }
+
+    /// Names of the other users seen so far.
+    pub fn users(&self) -> Vec<&String> {
+        let mut users: Vec<&String> = self
+            .user_colors
+            .keys()
+            .filter(|user| **user != self.user_name)
+            .collect();
+        users.sort();
+        users
+    }
+
+    pub fn welcome(&self) -> Option<&Welcome> {
+        self.welcome.as_ref()
+    }
+
+    pub fn set_welcome(&mut self, welcome: Welcome) {
+        self.welcome = Some(welcome);
+    }
+
+    pub fn set_rooms(&mut self, rooms: Vec<String>) {
+        self.rooms = rooms;
+    }
+
+    /// Moves the view to `room`, showing its newest messages.
+    pub fn set_room(&mut self, room: String) {
+        self.room = room;
+        self.scroll = 0;
+        self.selected = None;
+    }
+
+    pub fn room(&self) -> &String {
+        &self.room
+    }
+
+    /// Room after the current one in the server's room list.
+    pub fn next_room(&self) -> Option<&String> {
+        let idx = self.rooms.iter().position(|room| *room == self.room);
+        let next = idx.map_or(0, |idx| (idx + 1) % self.rooms.len());
+        self.rooms.get(next)
+    }
+
+    fn room_messages(&self) -> Vec<&Message> {
+        self.messages
+            .iter()
+            .filter(|msg| msg.room == self.room)
+            .collect()
+    }
+
+    fn room_ids(&self) -> Vec<u64> {
+        self.room_messages().iter().map(|msg| msg.id).collect()
+    }
+
+    /// Closes the socket, when the server stopped answering.
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    /// Replaces the socket after connecting again.
+    pub fn set_stream(&mut self, stream: Stream) {
+        self.stream = stream;
+    }
+
+    pub fn clone_stream(&self) -> Result<Stream> {
         self.stream
             .try_clone()
             .map_err(|err| error!("Failed to replicate session stream: {err}"))
     }
 
-    pub fn last_n_msgs(&self, n: usize) -> Vec<(usize, &Message)> {
-        self.messages
-            .iter()
+    /// Up to `n` messages of the current room, ending `scroll` messages
+    /// before the newest one.
+    pub fn visible_msgs(&self, n: usize) -> Vec<(usize, &Message)> {
+        self.room_messages()
+            .into_iter()
             .rev()
+            .skip(self.scroll)
             .take(n)
             .rev()
             .enumerate()
             .collect()
     }
 
+    pub fn selected(&self) -> Option<u64> {
+        self.selected
+    }
+
+    /// Selects the message before the selected one, or the newest visible
+    /// message when nothing is selected. `n` is the number of messages on screen.
+    pub fn select_prev(&mut self, n: usize) {
+        let ids = self.room_ids();
+        let idx = match self.selected_idx(&ids) {
+            Some(idx) => idx.saturating_sub(1),
+            None if ids.len() > self.scroll => ids.len() - 1 - self.scroll,
+            None => return,
+        };
+
+        self.selected = Some(ids[idx]);
+        if idx + n < ids.len().saturating_sub(self.scroll) {
+            self.scroll = ids.len() - n - idx;
+        }
+    }
+
+    /// Selects the message after the selected one, clearing the selection
+    /// after the newest message.
+    pub fn select_next(&mut self) {
+        let ids = self.room_ids();
+        let Some(idx) = self.selected_idx(&ids) else {
+            return;
+        };
+
+        if idx + 1 == ids.len() {
+            self.selected = None;
+            return;
+        }
+
+        self.selected = Some(ids[idx + 1]);
+        if idx + 1 >= ids.len().saturating_sub(self.scroll) {
+            self.scroll = ids.len() - 2 - idx;
+        }
+    }
+
+    /// Selects the message `id`, scrolling so that it is among the `n`
+    /// messages on screen.
+    pub fn select(&mut self, id: u64, n: usize) {
+        let ids = self.room_ids();
+        let Some(idx) = ids.iter().position(|&other| other == id) else {
+            return;
+        };
+
+        self.selected = Some(id);
+        if idx >= ids.len().saturating_sub(self.scroll) {
+            self.scroll = ids.len() - 1 - idx;
+        } else if idx + n < ids.len().saturating_sub(self.scroll) {
+            self.scroll = ids.len() - n - idx;
+        }
+    }
+
+    /// Identifiers of the messages of the current room matching `query`, oldest first.
+    pub fn search(&self, query: &SearchQuery) -> Vec<u64> {
+        self.room_messages()
+            .into_iter()
+            .filter(|msg| query.matches(msg))
+            .map(|msg| msg.id)
+            .collect()
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selected = None;
+    }
+
+    fn selected_idx(&self, ids: &[u64]) -> Option<usize> {
+        let selected = self.selected?;
+        ids.iter().position(|&id| id == selected)
+    }
+
+    /// Scrolls `lines` messages towards the oldest one, keeping `n` messages on screen.
+    pub fn scroll_up(&mut self, lines: usize, n: usize) {
+        let max = self.room_messages().len().saturating_sub(n);
+        self.scroll = self.scroll.saturating_add(lines).min(max);
+    }
+
+    /// Scrolls `lines` messages towards the newest one.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
     pub fn user(&self) -> &String {
         &self.user_name
     }
 
-    pub fn get_user_color(&self, user: &String) -> Color {
-        *self.user_colors.get(user).unwrap()
+    /// Resolves the colors of the users currently on screen, so that no two of
+    /// them share a color while the palette has enough colors.
+    ///
+    /// Users with a fixed color keep it. The others are processed in name order,
+    /// each taking the first free palette color starting from the one derived
+    /// from their name, which gives the same result on every client.
+    pub fn visible_colors<'a>(&self, users: &[&'a String]) -> HashMap<&'a String, Color> {
+        let mut users: Vec<&String> = users.to_vec();
+        users.sort();
+        users.dedup();
+
+        let mut colors = HashMap::new();
+        let mut taken = HashSet::new();
+        let mut pending = Vec::new();
+
+        // nobody else takes the client user's color, even when it is not on screen
+        if let Some(UserColor::Fixed(color)) = self.user_colors.get(&self.user_name) {
+            taken.insert(*color);
+        }
+
+        for user in users {
+            match self.user_colors.get(user) {
+                Some(UserColor::Fixed(color)) => {
+                    taken.insert(*color);
+                    colors.insert(user, *color);
+                }
+                Some(UserColor::Palette(idx)) => pending.push((user, *idx)),
+                None => pending.push((user, name_hash(user) as usize % self.palette.len())),
+            }
+        }
+
+        for (user, idx) in pending {
+            let color = (0..self.palette.len())
+                .map(|offset| self.palette[(idx + offset) % self.palette.len()])
+                .find(|color| !taken.contains(color))
+                .unwrap_or(self.palette[idx]);
+            taken.insert(color);
+            colors.insert(user, color);
+        }
+
+        colors
     }
 }
@@ -0,0 +1,150 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use server::error;
+use server::server::Result;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Time the server has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS session over TCP, shared by the clones of a stream.
+type TlsSession = Arc<Mutex<StreamOwned<ClientConnection, TcpStream>>>;
+
+/// Socket connected to the server: TCP, TLS over TCP, or a Unix domain
+/// socket for addresses such as `unix:/run/rschat.sock`.
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// The socket is kept aside from the session to be configured or shut
+    /// down without waiting for a read.
+    Tls(TcpStream, TlsSession),
+}
+
+/// TLS settings trusting the usual certificate authorities, and the ones in
+/// the PEM file `ca_file` when given, for servers with their own authority.
+///
+/// # Returns
+/// - `Ok(ClientConfig)`: If the certificates could be loaded.
+/// - `Err(())`: If `ca_file` is unreadable or invalid, with a message logged.
+pub fn tls_config(ca_file: Option<&Path>) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = ca_file {
+        let certs = CertificateDer::pem_file_iter(path)
+            .map_err(|err| error!("Failed to read certificates {}: {err}", path.display()))?;
+        for cert in certs {
+            let cert =
+                cert.map_err(|err| error!("Invalid certificate in {}: {err}", path.display()))?;
+            roots
+                .add(cert)
+                .map_err(|err| error!("Invalid certificate in {}: {err}", path.display()))?;
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+impl Stream {
+    /// Connects to `addr`, encrypting the connection when `tls` is given.
+    pub fn connect(addr: &str, tls: Option<&Arc<ClientConfig>>) -> io::Result<Self> {
+        match (addr.strip_prefix("unix:"), tls) {
+            (Some(_), Some(_)) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "TLS is not used over Unix sockets",
+            )),
+            (Some(path), None) => UnixStream::connect(path).map(Self::Unix),
+            (None, None) => TcpStream::connect(addr).map(Self::Tcp),
+            (None, Some(config)) => {
+                // the certificate is checked against the host name, without the port
+                let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let name = ServerName::try_from(host.to_string())
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+                let session =
+                    ClientConnection::new(Arc::clone(config), name).map_err(io::Error::other)?;
+                let socket = TcpStream::connect(addr)?;
+                let mut session = StreamOwned::new(session, socket.try_clone()?);
+                // rschat servers do not speak TLS themselves, so a server
+                // without a proxy in front of it fails here
+                socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+                while session.conn.is_handshaking() {
+                    session.conn.complete_io(&mut session.sock).map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!(
+                                "TLS handshake failed: {err} (rschat servers need a TLS proxy \
+                                 in front of them to accept TLS connections)"
+                            ),
+                        )
+                    })?;
+                }
+                socket.set_read_timeout(None)?;
+                Ok(Self::Tls(socket, Arc::new(Mutex::new(session))))
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+            Self::Tls(socket, session) => Ok(Self::Tls(socket.try_clone()?, Arc::clone(session))),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) | Self::Tls(stream, _) => stream.set_read_timeout(timeout),
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) | Self::Tls(stream, _) => stream.set_nonblocking(nonblocking),
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) | Self::Tls(stream, _) => stream.shutdown(how),
+            Self::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buffer),
+            Self::Unix(stream) => stream.read(buffer),
+            Self::Tls(_, session) => session.lock().unwrap().read(buffer),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(bytes),
+            Self::Unix(stream) => stream.write(bytes),
+            Self::Tls(_, session) => session.lock().unwrap().write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+            Self::Tls(_, session) => session.lock().unwrap().flush(),
+        }
+    }
+}
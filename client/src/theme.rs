@@ -0,0 +1,156 @@
+use ratatui::style::Color;
+use serde::Deserialize;
+use server::error;
+use server::server::Result;
+use std::fs;
+use std::path::PathBuf;
+
+/// Colors used to draw the client interface.
+///
+/// User themes are TOML files in `<config dir>/rschat/themes/<name>.toml`. Any
+/// color missing from a user theme is taken from the dark theme.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    /// Title at the top of the screen.
+    pub title: Color,
+    /// Borders of the message container, messages and input box.
+    pub border: Color,
+    /// Message bodies.
+    pub text: Color,
+    /// Key names in the help line.
+    pub hint: Color,
+    /// Label of the input box in insert mode.
+    pub insert_mode: Color,
+    /// Label of the input box in normal mode.
+    pub normal_mode: Color,
+    /// Border of the selected message.
+    pub selection: Color,
+    /// Text matching a message search.
+    pub search_match: Color,
+    /// Name of the client user.
+    pub self_user: Color,
+    /// Names of the other users.
+    pub users: Vec<Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Self {
+            title: Color::Cyan,
+            border: Color::Reset,
+            text: Color::Reset,
+            hint: Color::Cyan,
+            insert_mode: Color::LightGreen,
+            normal_mode: Color::LightBlue,
+            selection: Color::Yellow,
+            search_match: Color::LightYellow,
+            self_user: Color::Cyan,
+            users: vec![
+                Color::Red,
+                Color::Green,
+                Color::Yellow,
+                Color::Blue,
+                Color::Magenta,
+                Color::Gray,
+                Color::DarkGray,
+                Color::LightRed,
+                Color::LightGreen,
+                Color::LightYellow,
+                Color::LightBlue,
+                Color::LightMagenta,
+                Color::LightCyan,
+                Color::White,
+            ],
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            title: Color::Blue,
+            border: Color::DarkGray,
+            text: Color::Black,
+            hint: Color::Blue,
+            insert_mode: Color::Green,
+            normal_mode: Color::Blue,
+            selection: Color::Magenta,
+            search_match: Color::Rgb(0xff, 0xd7, 0x00),
+            self_user: Color::Blue,
+            users: vec![
+                Color::Red,
+                Color::Green,
+                Color::Magenta,
+                Color::Cyan,
+                Color::DarkGray,
+                Color::Rgb(0x8b, 0x45, 0x13),
+                Color::Rgb(0x00, 0x64, 0x64),
+                Color::Rgb(0x6a, 0x0d, 0xad),
+            ],
+        }
+    }
+
+    pub fn high_contrast() -> Self {
+        Self {
+            title: Color::Yellow,
+            border: Color::White,
+            text: Color::White,
+            hint: Color::Yellow,
+            insert_mode: Color::LightGreen,
+            normal_mode: Color::LightCyan,
+            selection: Color::LightMagenta,
+            search_match: Color::LightYellow,
+            self_user: Color::Yellow,
+            users: vec![
+                Color::LightRed,
+                Color::LightGreen,
+                Color::LightBlue,
+                Color::LightMagenta,
+                Color::LightCyan,
+                Color::White,
+            ],
+        }
+    }
+
+    /// Location of the user themes in the user's configuration directory.
+    fn themes_dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rschat").join("themes"))
+    }
+
+    /// Finds a theme by name, looking at the built-in themes first and then
+    /// at the user themes.
+    ///
+    /// # Returns
+    /// - `Ok(Theme)`: If the theme exists and is valid.
+    /// - `Err(())`: If the theme is unknown or malformed, with a message logged.
+    pub fn load(name: &str) -> Result<Self> {
+        match name {
+            "dark" => return Ok(Self::dark()),
+            "light" => return Ok(Self::light()),
+            "high-contrast" => return Ok(Self::high_contrast()),
+            _ => {}
+        }
+
+        let path = Self::themes_dir()
+            .map(|dir| dir.join(format!("{name}.toml")))
+            .ok_or_else(|| error!("Theme '{name}' not found"))?;
+        let content = fs::read_to_string(&path)
+            .map_err(|err| error!("Failed to read theme {}: {err}", path.display()))?;
+        let theme: Self = toml::from_str(&content)
+            .map_err(|err| error!("Invalid theme {}: {err}", path.display()))?;
+
+        if theme.users.is_empty() {
+            error!(
+                "Invalid theme {}: users must list at least one color",
+                path.display()
+            );
+            return Err(());
+        }
+        Ok(theme)
+    }
+}
@@ -0,0 +1,89 @@
+use server::error;
+use server::server::Result;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+/// Session tokens given by the servers, kept in `<data dir>/rschat/tokens.json`
+/// so the client logs in again without asking for the password. Tokens are
+/// stored per server address and user.
+pub struct Tokens {
+    // location of the tokens file, if the platform has a data directory
+    path: Option<PathBuf>,
+    // token of every server and user
+    tokens: HashMap<String, HashMap<String, String>>,
+}
+
+impl Tokens {
+    /// Reads the tokens file. A missing or unreadable file gives no tokens.
+    pub fn load() -> Self {
+        let path = dirs::data_dir().map(|dir| dir.join("rschat").join("tokens.json"));
+        let tokens = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(content)) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                error!("Ignoring malformed tokens file: {err}");
+                HashMap::new()
+            }),
+            Some(Err(err)) if err.kind() != ErrorKind::NotFound => {
+                error!("Failed to read tokens file: {err}");
+                HashMap::new()
+            }
+            _ => HashMap::new(),
+        };
+
+        Self { path, tokens }
+    }
+
+    pub fn get(&self, server: &str, user: &str) -> Option<&String> {
+        self.tokens.get(server)?.get(user)
+    }
+
+    /// Stores the token of `user` on `server`, removing it when `token` is `None`.
+    pub fn set(&mut self, server: &str, user: &str, token: Option<String>) {
+        let users = self.tokens.entry(server.to_string()).or_default();
+        match token {
+            Some(token) => users.insert(user.to_string(), token),
+            None => users.remove(user),
+        };
+    }
+
+    /// Writes the tokens to disk, readable by the user only on Unix.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the tokens were saved, or there is no data directory.
+    /// - `Err(())`: If the file could not be written, with a message logged.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|err| error!("Failed to create {}: {err}", dir.display()))?;
+        }
+        let content = serde_json::to_string(&self.tokens)
+            .map_err(|err| error!("Failed to encode tokens: {err}"))?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // the file is never readable by others, even while being written
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(path)
+            .map_err(|err| error!("Failed to save tokens: {err}"))?;
+
+        // files from older versions may have been created readable by others
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))
+                .map_err(|err| error!("Failed to protect tokens file: {err}"))?;
+        }
+        file.write_all(content.as_bytes())
+            .map_err(|err| error!("Failed to save tokens: {err}"))
+    }
+}
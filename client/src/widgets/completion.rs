@@ -0,0 +1,81 @@
+use crate::theme::Theme;
+use ratatui::layout::Rect;
+use ratatui::prelude::Stylize;
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, BorderType, Clear, Paragraph, Widget};
+
+/// Popup listing the candidates of a Tab completion, drawn at the bottom left
+/// of the area it is rendered in.
+pub struct CompletionPopup<'a> {
+    candidates: &'a [String],
+    selected: usize,
+    theme: &'a Theme,
+}
+
+impl<'a> CompletionPopup<'a> {
+    /// Number of candidates shown at once.
+    const MAX_ROWS: usize = 8;
+
+    pub fn new(candidates: &'a [String], selected: usize, theme: &'a Theme) -> Self {
+        Self {
+            candidates,
+            selected,
+            theme,
+        }
+    }
+}
+
+impl<'a> Widget for CompletionPopup<'a> {
+    fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
+    where
+        Self: Sized,
+    {
+        let rows = self.candidates.len().min(Self::MAX_ROWS);
+        // keeps the selected candidate in view
+        let first = (self.selected + 1).saturating_sub(rows);
+        let width = self
+            .candidates
+            .iter()
+            .map(|candidate| candidate.chars().count())
+            .max()
+            .unwrap_or_default()
+            + 4;
+
+        let height = (rows as u16 + 2).min(area.height);
+        let width = (width as u16).min(area.width);
+        let area = Rect::new(
+            area.x + 1,
+            area.bottom().saturating_sub(height + 1),
+            width,
+            height,
+        );
+
+        let lines: Vec<Line> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(rows)
+            .map(|(idx, candidate)| {
+                let line = Line::from(format!(" {candidate} "));
+                if idx == self.selected {
+                    line.style(
+                        Style::new()
+                            .fg(self.theme.selection)
+                            .add_modifier(Modifier::BOLD | Modifier::REVERSED),
+                    )
+                } else {
+                    line.style(Style::new().fg(self.theme.text))
+                }
+            })
+            .collect();
+
+        let block = Block::bordered()
+            .border_type(BorderType::Rounded)
+            .fg(self.theme.border);
+
+        Clear.render(area, buf);
+        Paragraph::new(lines).block(block).render(area, buf);
+    }
+}
@@ -1,14 +1,51 @@
 use crate::client::Mode;
+use crate::commands::COMMANDS;
+use crate::keymap::Keymap;
+use crate::theme::Theme;
+use ratatui::layout::{Constraint, Flex, Layout, Rect};
 use ratatui::prelude::Stylize;
+use ratatui::widgets::{Block, BorderType, Clear, Paragraph};
 use ratatui::{text::Line, widgets::Widget};
+use std::time::Duration;
 
+/// One-line summary of the keybindings of the current mode.
 pub struct HelpWidget<'a> {
     mode: &'a Mode,
+    theme: &'a Theme,
+    keymap: &'a Keymap,
+    notice: Option<&'a String>,
+    prompt: Option<&'a String>,
+    latency: Option<Duration>,
 }
 
 impl<'a> HelpWidget<'a> {
-    pub fn new(mode: &'a Mode) -> Self {
-        Self { mode }
+    pub fn new(mode: &'a Mode, theme: &'a Theme, keymap: &'a Keymap) -> Self {
+        Self {
+            mode,
+            theme,
+            keymap,
+            notice: None,
+            prompt: None,
+            latency: None,
+        }
+    }
+
+    /// Text shown instead of the keybindings, such as an error from the server.
+    pub fn notice(mut self, notice: Option<&'a String>) -> Self {
+        self.notice = notice;
+        self
+    }
+
+    /// Message search being typed, shown after a `/`.
+    pub fn prompt(mut self, prompt: Option<&'a String>) -> Self {
+        self.prompt = prompt;
+        self
+    }
+
+    /// Time the server took to answer the last ping, shown on the right.
+    pub fn latency(mut self, latency: Option<Duration>) -> Self {
+        self.latency = latency;
+        self
     }
 }
 
@@ -17,23 +54,107 @@ impl<'a> Widget for HelpWidget<'a> {
     where
         Self: Sized,
     {
-        let line = match *self.mode {
-            Mode::NormalMode => Line::from(vec![
-                "Press ".into(),
-                "q".bold().cyan(),
-                " to exit, ".into(),
-                "a".bold().cyan(),
-                " to start editing message.".into(),
-            ]),
-            Mode::InsertMode => Line::from(vec![
-                "Press ".into(),
-                "ESC".bold().cyan(),
-                " to stop editing, ".into(),
-                "ENTER".bold().cyan(),
-                " to send message".into(),
-            ]),
-        };
-
-        line.render(area, buf);
+        let mut area = area;
+        if let Some(latency) = self.latency {
+            let text = format!(" {} ms", latency.as_millis());
+            let [rest, right] =
+                Layout::horizontal([Constraint::Min(0), Constraint::Length(text.len() as u16)])
+                    .areas(area);
+            Line::from(text.fg(self.theme.hint)).render(right, buf);
+            area = rest;
+        }
+
+        if let Some(prompt) = self.prompt {
+            Line::from(vec!["/".bold().fg(self.theme.hint), prompt.clone().into()])
+                .render(area, buf);
+            return;
+        }
+
+        if let Some(notice) = self.notice {
+            Line::from(notice.clone().bold().fg(self.theme.hint)).render(area, buf);
+            return;
+        }
+
+        let pending = self.keymap.pending();
+        if !pending.is_empty() {
+            let keys: String = pending.iter().map(|key| key.to_string()).collect();
+            Line::from(vec!["Keys: ".into(), keys.bold().fg(self.theme.hint)]).render(area, buf);
+            return;
+        }
+
+        let mut spans = vec!["Press ".into()];
+        let mut shown = Vec::new();
+        for binding in self.keymap.bindings(self.mode) {
+            // a single key per action keeps the line short
+            if shown.contains(&binding.action) {
+                continue;
+            }
+            shown.push(binding.action);
+
+            if spans.len() > 1 {
+                spans.push(", ".into());
+            }
+            let keys: String = binding.keys.iter().map(|key| key.to_string()).collect();
+            spans.push(keys.bold().fg(self.theme.hint));
+            spans.push(format!(" to {}", binding.action.description()).into());
+        }
+        spans.push(".".into());
+
+        Line::from(spans).render(area, buf);
+    }
+}
+
+/// Popup listing every keybinding of both modes and the commands.
+pub struct HelpPopup<'a> {
+    theme: &'a Theme,
+    keymap: &'a Keymap,
+}
+
+impl<'a> HelpPopup<'a> {
+    pub fn new(theme: &'a Theme, keymap: &'a Keymap) -> Self {
+        Self { theme, keymap }
+    }
+}
+
+impl<'a> Widget for HelpPopup<'a> {
+    fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
+    where
+        Self: Sized,
+    {
+        let mut lines = Vec::new();
+        for (title, mode) in [("NORMAL", Mode::NormalMode), ("INSERT", Mode::InsertMode)] {
+            lines.push(Line::from(title.bold().fg(self.theme.hint)));
+            for binding in self.keymap.bindings(&mode) {
+                let keys: String = binding.keys.iter().map(|key| key.to_string()).collect();
+                lines.push(Line::from(vec![
+                    format!("  {keys:<12}").bold(),
+                    binding.action.description().into(),
+                ]));
+            }
+            lines.push(Line::default());
+        }
+        lines.push(Line::from("COMMANDS".bold().fg(self.theme.hint)));
+        for (name, description) in COMMANDS {
+            lines.push(Line::from(vec![
+                format!("  {name:<12}").bold(),
+                description.into(),
+            ]));
+        }
+
+        let [area] = Layout::vertical([Constraint::Length(lines.len() as u16 + 2)])
+            .flex(Flex::Center)
+            .areas(area);
+        let [area] = Layout::horizontal([Constraint::Length(52)])
+            .flex(Flex::Center)
+            .areas(area);
+
+        let block = Block::bordered()
+            .border_type(BorderType::Rounded)
+            .title_top(" Keybindings ")
+            .title_bottom(" Press any key to close ")
+            .fg(self.theme.border);
+
+        Clear.render(area, buf);
+        Paragraph::new(lines).block(block).render(area, buf);
     }
 }
@@ -1,22 +1,65 @@
 use crate::client::Mode;
+use crate::theme::Theme;
 use ratatui::{
-    crossterm::event::KeyEvent,
-    style::Stylize,
+    crossterm::event::{KeyCode, KeyEvent},
+    style::{Style, Stylize},
     text::Line,
     widgets::{Block, BorderType, Widget},
 };
-use tui_textarea::TextArea;
+use tui_textarea::{CursorMove, TextArea};
 
 pub struct InputWidget<'a> {
     mode: Mode,
     handler: TextArea<'a>,
+    theme: Theme,
+    // what the message being written is for, such as " Reply to alice "
+    title: String,
+    // messages sent in this session, oldest first
+    history: Vec<String>,
+    // entry of `history` shown while browsing it
+    history_pos: Option<usize>,
+    // text being written before browsing or searching the history
+    stash: String,
+    // reverse search through the history, like Ctrl-R in a shell
+    search: Option<HistorySearch>,
+    // candidates cycled through with Tab
+    completion: Option<Completion>,
+}
+
+struct Completion {
+    // texts the word being completed can be replaced with
+    candidates: Vec<String>,
+    // candidate written in the input box
+    selected: usize,
+    // position of the word being completed
+    row: usize,
+    col: usize,
+    // number of characters of the word currently written
+    len: usize,
+}
+
+struct HistorySearch {
+    // text looked for in the history
+    query: String,
+    // entry of the history matching the query
+    found: Option<usize>,
 }
 
 impl<'a> InputWidget<'a> {
-    pub fn new(mode: Mode) -> Self {
+    /// Number of lines the input box grows to before scrolling.
+    const MAX_LINES: usize = 8;
+
+    pub fn new(mode: Mode, theme: Theme) -> Self {
         Self {
             mode,
             handler: TextArea::default(),
+            theme,
+            title: String::from(" Input "),
+            history: Vec::new(),
+            history_pos: None,
+            stash: String::new(),
+            search: None,
+            completion: None,
         }
     }
 
@@ -28,9 +71,22 @@ impl<'a> InputWidget<'a> {
         self.mode = mode;
     }
 
+    pub fn set_title(&mut self, title: String) {
+        self.title = title;
+    }
+
+    pub fn insert_newline(&mut self) {
+        self.handler.insert_newline();
+    }
+
+    /// Height of the input box, growing with the message up to `MAX_LINES` lines.
+    pub fn height(&self) -> u16 {
+        self.handler.lines().len().min(Self::MAX_LINES) as u16 + 2
+    }
+
     pub fn get_message(&self) -> Option<String> {
-        let msg = self.handler.lines().join("");
-        if msg.is_empty() {
+        let msg = self.text();
+        if msg.trim().is_empty() {
             return None;
         }
 
@@ -38,7 +94,222 @@ impl<'a> InputWidget<'a> {
     }
 
     pub fn clear_input(&mut self) {
-        self.handler.delete_line_by_head();
+        self.handler = TextArea::default();
+        self.history_pos = None;
+        self.completion = None;
+    }
+
+    /// Text of the input box, with its line breaks.
+    pub fn text(&self) -> String {
+        self.handler.lines().join("\n")
+    }
+
+    /// Replaces the text of the input box, placing the cursor at its end.
+    pub fn set_text(&mut self, text: &str) {
+        self.handler = TextArea::new(text.split('\n').map(String::from).collect());
+        self.handler.move_cursor(CursorMove::Bottom);
+        self.handler.move_cursor(CursorMove::End);
+    }
+
+    /// Records a sent message so it can be recalled later.
+    pub fn push_history(&mut self, msg: String) {
+        if self.history.last() != Some(&msg) {
+            self.history.push(msg);
+        }
+    }
+
+    /// Moves the cursor up, or shows the previous history entry when the
+    /// cursor is already on the first line.
+    pub fn history_prev(&mut self) {
+        if self.handler.cursor().0 > 0 {
+            self.handler.move_cursor(CursorMove::Up);
+            return;
+        }
+
+        let pos = match self.history_pos {
+            Some(0) => return,
+            Some(pos) => pos - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.stash = self.text();
+                self.history.len() - 1
+            }
+        };
+        self.history_pos = Some(pos);
+        self.set_text(&self.history[pos].clone());
+    }
+
+    /// Moves the cursor down, or shows the next history entry when the cursor
+    /// is already on the last line. Going past the newest entry brings back
+    /// the text being written before browsing the history.
+    pub fn history_next(&mut self) {
+        if self.handler.cursor().0 + 1 < self.handler.lines().len() {
+            self.handler.move_cursor(CursorMove::Down);
+            return;
+        }
+
+        match self.history_pos {
+            Some(pos) if pos + 1 < self.history.len() => {
+                self.history_pos = Some(pos + 1);
+                self.set_text(&self.history[pos + 1].clone());
+            }
+            Some(_) => {
+                self.history_pos = None;
+                let stash = std::mem::take(&mut self.stash);
+                self.set_text(&stash);
+            }
+            None => {}
+        }
+    }
+
+    /// Word written right before the cursor, such as `@ali`, and whether it
+    /// starts the message.
+    pub fn current_word(&self) -> (String, bool) {
+        let (row, col) = self.handler.cursor();
+        let line: Vec<char> = self.handler.lines()[row].chars().take(col).collect();
+        let start = line
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |idx| idx + 1);
+        (line[start..].iter().collect(), row == 0 && start == 0)
+    }
+
+    /// Replaces the word before the cursor with the first of `candidates`,
+    /// the next ones being reached with `complete_next`.
+    pub fn start_completion(&mut self, candidates: Vec<String>) {
+        if candidates.is_empty() {
+            return;
+        }
+
+        let (row, col) = self.handler.cursor();
+        let len = self.current_word().0.chars().count();
+        self.completion = Some(Completion {
+            candidates,
+            selected: 0,
+            row,
+            col: col - len,
+            len,
+        });
+        self.write_completion();
+    }
+
+    pub fn is_completing(&self) -> bool {
+        self.completion.is_some()
+    }
+
+    pub fn complete_next(&mut self) {
+        if let Some(completion) = &mut self.completion {
+            completion.selected = (completion.selected + 1) % completion.candidates.len();
+            self.write_completion();
+        }
+    }
+
+    pub fn complete_prev(&mut self) {
+        if let Some(completion) = &mut self.completion {
+            let count = completion.candidates.len();
+            completion.selected = (completion.selected + count - 1) % count;
+            self.write_completion();
+        }
+    }
+
+    /// Candidates of the running completion, with the index of the one written.
+    pub fn completion(&self) -> Option<(&[String], usize)> {
+        self.completion
+            .as_ref()
+            .map(|completion| (completion.candidates.as_slice(), completion.selected))
+    }
+
+    /// Ends the completion, keeping the candidate written.
+    pub fn end_completion(&mut self) {
+        self.completion = None;
+    }
+
+    /// Writes the selected candidate in place of the word being completed.
+    fn write_completion(&mut self) {
+        let Some(completion) = &mut self.completion else {
+            return;
+        };
+
+        let candidate = &completion.candidates[completion.selected];
+        self.handler.move_cursor(CursorMove::Jump(
+            completion.row as u16,
+            completion.col as u16,
+        ));
+        self.handler.delete_str(completion.len);
+        self.handler.insert_str(candidate);
+        completion.len = candidate.chars().count();
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.search.is_some()
+    }
+
+    /// Starts a reverse history search, or looks for an older match when a
+    /// search is already running.
+    pub fn search_older(&mut self) {
+        match &self.search {
+            Some(search) => {
+                let before = search.found.unwrap_or(self.history.len());
+                self.search_from(before);
+            }
+            None => {
+                self.stash = self.text();
+                self.search = Some(HistorySearch {
+                    query: String::new(),
+                    found: None,
+                });
+            }
+        }
+    }
+
+    /// Edits the query of the running search with a typed key.
+    pub fn search_key(&mut self, key: KeyEvent) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+
+        match key.code {
+            KeyCode::Char(c) => search.query.push(c),
+            KeyCode::Backspace => {
+                search.query.pop();
+            }
+            _ => return,
+        }
+        self.search_from(self.history.len());
+    }
+
+    /// Shows the newest history entry before `before` that contains the query.
+    fn search_from(&mut self, before: usize) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+
+        let found = self.history[..before]
+            .iter()
+            .rposition(|entry| entry.contains(&search.query));
+        if found.is_none() && before < self.history.len() {
+            // keeps the current match when there are no older ones
+            return;
+        }
+
+        search.found = found;
+        let text = match found {
+            Some(idx) => self.history[idx].clone(),
+            None => self.stash.clone(),
+        };
+        self.set_text(&text);
+    }
+
+    /// Ends the search, keeping the match in the input box.
+    pub fn accept_search(&mut self) {
+        self.search = None;
+    }
+
+    /// Ends the search, bringing back the text written before it.
+    pub fn cancel_search(&mut self) {
+        self.search = None;
+        let stash = std::mem::take(&mut self.stash);
+        self.set_text(&stash);
     }
 }
 
@@ -48,14 +319,20 @@ impl<'a> Widget for &mut InputWidget<'a> {
         Self: Sized,
     {
         let mode = match self.mode {
-            Mode::InsertMode => Line::from(" INSERT ").light_green(),
-            Mode::NormalMode => Line::from(" NORMAL ").light_blue(),
+            Mode::InsertMode => Line::from(" INSERT ").fg(self.theme.insert_mode),
+            Mode::NormalMode => Line::from(" NORMAL ").fg(self.theme.normal_mode),
+        };
+
+        let title = match &self.search {
+            Some(search) => format!(" History search: {} ", search.query),
+            None => self.title.clone(),
         };
 
         let input_block = Block::bordered()
-            .title_top(" Input ")
+            .title_top(title)
             .title_bottom(mode)
-            .border_type(BorderType::Rounded);
+            .border_type(BorderType::Rounded)
+            .border_style(Style::new().fg(self.theme.border));
 
         self.handler.set_block(input_block);
         self.handler.render(area, buf);
@@ -1,30 +1,112 @@
 use std::time::SystemTime;
 
+use crate::search::SearchQuery;
+use crate::theme::Theme;
 use chrono::{DateTime, Local};
 use ratatui::{
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
-    text::Line,
+    text::{Line, Span},
     widgets::{Block, BorderType, Paragraph, Widget},
 };
-use server::event::Message;
+use server::event::{Message, Reaction};
 
 pub struct MessageWidget<'a> {
     author: &'a String,
     date: SystemTime,
     content: &'a String,
     color: Color,
+    theme: &'a Theme,
+    reply_to: Option<&'a Message>,
+    reactions: &'a [Reaction],
+    selected: bool,
+    search: Option<&'a SearchQuery>,
 }
 
 impl<'a> MessageWidget<'a> {
-    pub fn from_msg_with_color(msg: &'a Message, color: Color) -> Self {
+    /// Lines of a message body shown before it is cut.
+    const MAX_BODY_LINES: usize = 10;
+
+    /// Rows needed to draw `msg`: its body plus the borders, author and date lines.
+    pub fn height(msg: &Message) -> u16 {
+        msg.body.lines().count().clamp(1, Self::MAX_BODY_LINES) as u16 + 4
+    }
+
+    pub fn from_msg_with_color(msg: &'a Message, color: Color, theme: &'a Theme) -> Self {
         Self {
             author: &msg.author,
             date: msg.date,
             content: &msg.body,
             color,
+            theme,
+            reply_to: None,
+            reactions: &[],
+            selected: false,
+            search: None,
         }
     }
+
+    /// Message this one replies to.
+    pub fn reply_to(mut self, msg: Option<&'a Message>) -> Self {
+        self.reply_to = msg;
+        self
+    }
+
+    pub fn reactions(mut self, reactions: &'a [Reaction]) -> Self {
+        self.reactions = reactions;
+        self
+    }
+
+    pub fn selected(mut self, selected: bool) -> Self {
+        self.selected = selected;
+        self
+    }
+
+    /// Search whose matches are highlighted in the body.
+    pub fn search(mut self, search: Option<&'a SearchQuery>) -> Self {
+        self.search = search;
+        self
+    }
+
+    /// A line of the body, with the parts matching the search highlighted.
+    fn body_line(&self, line: &'a str) -> Line<'a> {
+        let Some(search) = self.search else {
+            return Line::from(line);
+        };
+
+        let highlight = Style::new()
+            .fg(self.theme.search_match)
+            .add_modifier(Modifier::REVERSED);
+        let mut spans = Vec::new();
+        let mut end = 0;
+        for range in search.ranges(line) {
+            spans.push(Span::raw(&line[end..range.start]));
+            spans.push(Span::styled(&line[range.clone()], highlight));
+            end = range.end;
+        }
+        spans.push(Span::raw(&line[end..]));
+        Line::from(spans)
+    }
+
+    /// Reactions grouped by emoji, such as `👍 2  🎉 1`.
+    fn reactions_line(&self) -> Line<'static> {
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for reaction in self.reactions {
+            match counts
+                .iter_mut()
+                .find(|(emoji, _)| *emoji == reaction.emoji)
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((&reaction.emoji, 1)),
+            }
+        }
+
+        let text: Vec<String> = counts
+            .iter()
+            .map(|(emoji, count)| format!("{emoji} {count}"))
+            .collect();
+        Line::from(text.join("  "))
+    }
 }
 
 impl<'a> Widget for MessageWidget<'a> {
@@ -35,32 +117,71 @@ impl<'a> Widget for MessageWidget<'a> {
         let datetime: DateTime<Local> = DateTime::from(self.date);
         let fmt_date = datetime.format("%d/%m %H:%M").to_string();
 
-        let block = Block::bordered().border_type(BorderType::Rounded);
+        let border_style = if self.selected {
+            Style::new()
+                .fg(self.theme.selection)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            Style::new().fg(self.theme.border)
+        };
+        let block = Block::bordered()
+            .border_type(BorderType::Rounded)
+            .border_style(border_style);
 
         let outer_layout = Layout::new(
             Direction::Vertical,
-            [Constraint::Percentage(60), Constraint::Percentage(30)],
+            [Constraint::Fill(1), Constraint::Length(1)],
         )
         .split(area);
 
         let inner_area = block.inner(outer_layout[0]);
         let inner_layout = Layout::new(
             Direction::Vertical,
-            [Constraint::Percentage(30), Constraint::Percentage(60)],
+            [Constraint::Length(1), Constraint::Fill(1)],
         )
         .split(inner_area);
 
+        let mut header = vec![Span::styled(
+            self.author.clone(),
+            Style::new().fg(self.color).add_modifier(Modifier::BOLD),
+        )];
+        if let Some(reply) = self.reply_to {
+            let snippet: String = reply
+                .body
+                .chars()
+                .take(30)
+                .map(|c| if c == '\n' { ' ' } else { c })
+                .collect();
+            header.push(Span::styled(
+                format!(" ↪ {}: {snippet}", reply.author),
+                Style::new().add_modifier(Modifier::DIM),
+            ));
+        }
+
         block.render(outer_layout[0], buf);
-        Paragraph::new(
-            Line::from(self.author.clone())
-                .style(Style::new().fg(self.color).add_modifier(Modifier::BOLD)),
+        Paragraph::new(Line::from(header)).render(inner_layout[0], buf);
+        let body: Vec<Line> = self
+            .content
+            .lines()
+            .map(|line| self.body_line(line))
+            .collect();
+        Paragraph::new(body)
+            .style(Style::new().fg(self.theme.text))
+            .render(inner_layout[1], buf);
+        let footer_layout = Layout::new(
+            Direction::Horizontal,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(fmt_date.len() as u16),
+            ],
         )
-        .render(inner_layout[0], buf);
-        Paragraph::new(Line::from(self.content.clone())).render(inner_layout[1], buf);
+        .split(outer_layout[1]);
+
+        Paragraph::new(self.reactions_line()).render(footer_layout[0], buf);
         Paragraph::new(
             Line::from(fmt_date).style(Style::new().fg(self.color).add_modifier(Modifier::BOLD)),
         )
         .right_aligned()
-        .render(outer_layout[1], buf);
+        .render(footer_layout[1], buf);
     }
 }
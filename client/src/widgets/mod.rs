@@ -1,4 +1,6 @@
+pub mod completion;
 pub mod help;
 pub mod input;
 pub mod message;
 pub mod msgs_container;
+pub mod welcome;
@@ -1,15 +1,34 @@
 use super::message::MessageWidget;
+use super::welcome::WelcomeBanner;
+use crate::search::SearchQuery;
 use crate::session::Session;
+use crate::theme::Theme;
 use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::Style;
 use ratatui::widgets::{Block, BorderType, Widget};
 
 pub struct MsgContainer<'a> {
     session: &'a Session,
+    theme: &'a Theme,
+    search: Option<&'a SearchQuery>,
 }
 
 impl<'a> MsgContainer<'a> {
-    pub fn new(session: &'a Session) -> Self {
-        Self { session }
+    /// Number of messages shown at once.
+    pub const CAPACITY: usize = 5;
+
+    pub fn new(session: &'a Session, theme: &'a Theme) -> Self {
+        Self {
+            session,
+            theme,
+            search: None,
+        }
+    }
+
+    /// Search whose matches are highlighted.
+    pub fn search(mut self, search: Option<&'a SearchQuery>) -> Self {
+        self.search = search;
+        self
     }
 }
 
@@ -18,7 +37,10 @@ impl<'a> Widget for MsgContainer<'a> {
     where
         Self: Sized,
     {
-        let block = Block::bordered().border_type(BorderType::Rounded);
+        let block = Block::bordered()
+            .border_type(BorderType::Rounded)
+            .border_style(Style::new().fg(self.theme.border))
+            .title_top(format!(" #{} ", self.session.room()));
         let inner_area = block.inner(area);
 
         let inner_layout = Layout::new(
@@ -27,18 +49,38 @@ impl<'a> Widget for MsgContainer<'a> {
         )
         .split(inner_area);
 
-        let constraints = vec![Constraint::Length(6); 5];
+        let msgs = self.session.visible_msgs(Self::CAPACITY);
+
+        // the greeting of the server fills the view until the room has messages
+        if msgs.is_empty()
+            && let Some(welcome) = self.session.welcome()
+        {
+            WelcomeBanner::new(welcome, self.theme).render(inner_area, buf);
+        }
+
+        // every message takes its own row, with an empty line below it
+        let constraints: Vec<Constraint> = msgs
+            .iter()
+            .map(|(_, msg)| Constraint::Length(MessageWidget::height(msg) + 1))
+            .collect();
         let left_layout = Layout::new(Direction::Vertical, &constraints).split(inner_layout[0]);
         let right_layout = Layout::new(Direction::Vertical, &constraints).split(inner_layout[1]);
 
-        for (idx, msg) in self.session.last_n_msgs(5) {
-            let widget =
-                MessageWidget::from_msg_with_color(msg, self.session.get_user_color(&msg.author));
-            let area = if msg.author == *self.session.user() {
+        let authors: Vec<&String> = msgs.iter().map(|(_, msg)| &msg.author).collect();
+        let colors = self.session.visible_colors(&authors);
+
+        for (idx, msg) in msgs {
+            let widget = MessageWidget::from_msg_with_color(msg, colors[&msg.author], self.theme)
+                .reply_to(msg.reply_to.and_then(|id| self.session.find_message(id)))
+                .reactions(self.session.reactions(msg.id))
+                .selected(self.session.selected() == Some(msg.id))
+                .search(self.search);
+            let mut area = if msg.author == *self.session.user() {
                 right_layout[idx]
             } else {
                 left_layout[idx]
             };
+            area.height = area.height.saturating_sub(1);
             widget.render(area, buf);
         }
 
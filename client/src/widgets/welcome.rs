@@ -0,0 +1,58 @@
+use crate::theme::Theme;
+use ratatui::prelude::Stylize;
+use ratatui::text::Line;
+use ratatui::widgets::{Paragraph, Widget, Wrap};
+use server::protocol::Welcome;
+
+/// Greeting of the server, shown at the top of a room without messages.
+pub struct WelcomeBanner<'a> {
+    welcome: &'a Welcome,
+    theme: &'a Theme,
+}
+
+impl<'a> WelcomeBanner<'a> {
+    pub fn new(welcome: &'a Welcome, theme: &'a Theme) -> Self {
+        Self { welcome, theme }
+    }
+}
+
+impl<'a> Widget for WelcomeBanner<'a> {
+    fn render(self, area: ratatui::prelude::Rect, buf: &mut ratatui::prelude::Buffer)
+    where
+        Self: Sized,
+    {
+        let welcome = self.welcome;
+        let mut lines = vec![
+            Line::from(""),
+            Line::from(format!("Welcome to {}", welcome.server))
+                .bold()
+                .fg(self.theme.title),
+            Line::from(format!("rschat {}", welcome.version)).fg(self.theme.hint),
+            Line::from(""),
+        ];
+        if let Some(motd) = &welcome.motd {
+            lines.extend(
+                motd.lines()
+                    .map(|line| Line::from(line.to_string()).fg(self.theme.text)),
+            );
+            lines.push(Line::from(""));
+        }
+
+        let users = match welcome.users {
+            1 => String::from("1 user online"),
+            n => format!("{n} users online"),
+        };
+        let rooms: Vec<String> = welcome
+            .rooms
+            .iter()
+            .map(|room| format!("#{room}"))
+            .collect();
+        lines.push(Line::from(users).fg(self.theme.hint));
+        lines.push(Line::from(format!("Rooms: {}", rooms.join(", "))).fg(self.theme.hint));
+
+        Paragraph::new(lines)
+            .centered()
+            .wrap(Wrap { trim: false })
+            .render(area, buf);
+    }
+}
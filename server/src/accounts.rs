@@ -0,0 +1,195 @@
+use crate::server::Result;
+use crate::{error, info};
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Registered users and the argon2 hash of their password. When an accounts
+/// file is configured they are kept in it as a JSON object, otherwise they
+/// only last until the server stops.
+pub struct Accounts {
+    // file the accounts are saved to
+    path: Option<PathBuf>,
+    // password hash of every user, in PHC string format
+    hashes: HashMap<String, String>,
+}
+
+impl Accounts {
+    /// Shortest password accepted at registration.
+    const MIN_PASSWORD: usize = 8;
+    /// Longest user name accepted at registration.
+    const MAX_USERNAME: usize = 32;
+
+    /// Reads the accounts saved in `path`, if given. A missing file holds no
+    /// accounts.
+    ///
+    /// # Returns
+    /// - `Ok(Accounts)`: If the file is missing or valid.
+    /// - `Err(())`: If the file is unreadable or malformed, with a message logged.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut accounts = Self {
+            path: path.map(Path::to_path_buf),
+            hashes: HashMap::new(),
+        };
+        let Some(path) = path else {
+            info!("No accounts file configured, accounts are lost when the server stops");
+            return Ok(accounts);
+        };
+
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                accounts.hashes = serde_json::from_str(&content)
+                    .map_err(|err| error!("Invalid accounts file {}: {err}", path.display()))?;
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                error!("Failed to read accounts file {}: {err}", path.display());
+                return Err(());
+            }
+        }
+        Ok(accounts)
+    }
+
+    /// Checks that a new account can be created with these credentials.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the name is valid and free, and the password long enough.
+    /// - `Err(reason)`: Otherwise, with the reason to report to the client.
+    pub fn check_new(&self, username: &str, password: &str) -> std::result::Result<(), String> {
+        if username.is_empty()
+            || username.chars().count() > Self::MAX_USERNAME
+            || username
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == '@')
+        {
+            // '@' is kept for users of linked servers, shown as user@server
+            return Err(format!(
+                "User names must have 1 to {} characters, without spaces or '@'",
+                Self::MAX_USERNAME
+            ));
+        }
+        if password.chars().count() < Self::MIN_PASSWORD {
+            return Err(format!(
+                "Passwords must have at least {} characters",
+                Self::MIN_PASSWORD
+            ));
+        }
+        if self.hashes.contains_key(username) {
+            return Err(format!("User '{username}' already exists"));
+        }
+        Ok(())
+    }
+
+    /// Stores a new account and saves the accounts file.
+    ///
+    /// # Arguments
+    /// - `hash`: Hash of the password, made with `hash_password`.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the account was created.
+    /// - `Err(reason)`: If the name was taken meanwhile or the file could not
+    ///   be written, with the reason to report to the client.
+    pub fn insert(&mut self, username: &str, hash: String) -> std::result::Result<(), String> {
+        if self.hashes.contains_key(username) {
+            return Err(format!("User '{username}' already exists"));
+        }
+
+        self.hashes.insert(username.to_string(), hash);
+        if self.save().is_err() {
+            self.hashes.remove(username);
+            return Err(String::from("The account could not be saved"));
+        }
+        info!("Registered user {username}");
+        Ok(())
+    }
+
+    /// Password hash of `username`, if registered.
+    pub fn hash(&self, username: &str) -> Option<String> {
+        self.hashes.get(username).cloned()
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let content = serde_json::to_string_pretty(&self.hashes)
+            .map_err(|err| error!("Failed to encode accounts: {err}"))?;
+        fs::write(path, content)
+            .map_err(|err| error!("Failed to save accounts file {}: {err}", path.display()))
+    }
+}
+
+/// Hashes a password with argon2 and a random salt. This is slow on purpose,
+/// so it should not run on the async runtime threads.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| error!("Failed to hash password: {err}"))
+}
+
+/// Checks a password against a hash made by `hash_password`. This is slow on
+/// purpose, so it should not run on the async runtime threads.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_accounts_are_checked() {
+        let mut accounts = Accounts::load(None).unwrap();
+        assert!(accounts.check_new("alice", "password").is_ok());
+        assert!(accounts.check_new("", "password").is_err());
+        assert!(accounts.check_new("al ice", "password").is_err());
+        assert!(accounts.check_new("alice@home", "password").is_err());
+        assert!(accounts.check_new(&"a".repeat(33), "password").is_err());
+        assert!(accounts.check_new("alice", "short").is_err());
+
+        accounts.insert("alice", String::from("hash")).unwrap();
+        assert!(accounts.check_new("alice", "password").is_err());
+        assert!(accounts.insert("alice", String::from("other")).is_err());
+        assert_eq!(accounts.hash("alice").as_deref(), Some("hash"));
+        assert_eq!(accounts.hash("bob"), None);
+    }
+
+    #[test]
+    fn accounts_are_saved() {
+        let path =
+            std::env::temp_dir().join(format!("rschat-accounts-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut accounts = Accounts::load(Some(&path)).unwrap();
+        assert_eq!(accounts.hash("alice"), None);
+        accounts.insert("alice", String::from("hash")).unwrap();
+        let accounts = Accounts::load(Some(&path)).unwrap();
+        assert_eq!(accounts.hash("alice").as_deref(), Some("hash"));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(Accounts::load(Some(&path)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn passwords_are_hashed() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(!hash.contains("correct horse"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::moderation::BanKind;
+use crate::peer::{Listener, Socket};
+use crate::protocol::FrameReader;
+use crate::server::Result;
+use crate::{error, info};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+/// Time an admin connection has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest request or reply exchanged on the admin socket.
+const MAX_LINE_SIZE: usize = 1024 * 1024;
+
+/// Request sent on the admin socket, one per connection. Also the
+/// subcommands of `server admin`.
+#[derive(clap::Subcommand, Serialize, Deserialize, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    /// List the connected clients
+    Clients,
+    /// List the rooms and the clients in each of them
+    Rooms,
+    /// Disconnect every client of a user, ending their login sessions
+    Kick {
+        user: String,
+        #[arg(long, help = "Reason shown to the user")]
+        reason: Option<String>,
+    },
+    /// Ban a user name or an IP address, disconnecting the matching clients
+    Ban {
+        kind: BanKind,
+        target: String,
+        #[arg(long, help = "Reason shown to the disconnected clients")]
+        reason: Option<String>,
+    },
+    /// Lift the ban of a user name or an IP address
+    Unban { kind: BanKind, target: String },
+    /// Send an announcement to every logged in client
+    Broadcast { text: String },
+    /// Read the configuration again, applying the settings that can change
+    /// while the server runs
+    Reload,
+    /// Show statistics of the server
+    Stats,
+}
+
+/// Answer of the server to an admin request.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminReply {
+    Done { text: String },
+    Failed { reason: String },
+}
+
+/// An admin request handed to the server loop, with where to send the reply.
+pub type AdminRequest = (AdminCommand, oneshot::Sender<AdminReply>);
+
+/// Accepts the connections of the admin socket, handing their requests to
+/// the server loop.
+pub async fn listen(listener: Listener, sender: Sender<AdminRequest>) {
+    loop {
+        match listener.accept().await {
+            Ok((Socket::Unix(stream), addr)) => {
+                tokio::spawn(serve(stream, sender.clone(), addr.to_string()));
+            }
+            Ok(_) => {}
+            Err(err) => error!("Failed to accept an admin connection: {err}"),
+        }
+    }
+}
+
+/// Reads the request of an admin connection and writes the reply of the
+/// server loop.
+async fn serve(mut stream: UnixStream, sender: Sender<AdminRequest>, addr: String) {
+    let line = match tokio::time::timeout(REQUEST_TIMEOUT, read_line(&mut stream)).await {
+        Ok(Ok(line)) => line,
+        Ok(Err(())) => return,
+        Err(_) => {
+            info!("Admin connection {addr} sent no request in time");
+            return;
+        }
+    };
+
+    let reply = match serde_json::from_slice::<AdminCommand>(&line) {
+        Ok(command) => {
+            let (reply, answer) = oneshot::channel();
+            if sender.send((command, reply)).await.is_err() {
+                return;
+            }
+            match answer.await {
+                Ok(reply) => reply,
+                Err(_) => return,
+            }
+        }
+        Err(err) => AdminReply::Failed {
+            reason: format!("Invalid request: {err}"),
+        },
+    };
+    let _ = stream.write_all(&encode(&reply)).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Sends `command` to the admin socket at `path` and waits for the reply.
+///
+/// # Returns
+/// - `Ok(AdminReply)`: If the server answered.
+/// - `Err(())`: If the server could not be reached, with a message logged.
+pub async fn request(path: &Path, command: &AdminCommand) -> Result<AdminReply> {
+    let mut stream = UnixStream::connect(path).await.map_err(|err| {
+        error!(
+            "Failed to connect to admin socket {}: {err}",
+            path.display()
+        )
+    })?;
+    stream
+        .write_all(&encode(command))
+        .await
+        .map_err(|err| error!("Failed to send the admin request: {err}"))?;
+    let line = read_line(&mut stream).await?;
+    serde_json::from_slice(&line).map_err(|err| error!("Invalid reply from the server: {err}"))
+}
+
+/// Reads a line of JSON, without its line break.
+async fn read_line(stream: &mut UnixStream) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; 4096];
+    let mut reader = FrameReader::default();
+    loop {
+        if let Some(line) = reader.next_line() {
+            return Ok(line);
+        }
+        if reader.pending() > MAX_LINE_SIZE {
+            error!("Admin line larger than {MAX_LINE_SIZE} bytes");
+            return Err(());
+        }
+        match stream.read(&mut buffer).await {
+            Ok(0) => {
+                error!("Admin connection closed before a full line");
+                return Err(());
+            }
+            Ok(n) => reader.push(&buffer[..n]),
+            Err(err) => {
+                error!("Failed to read from admin connection: {err}");
+                return Err(());
+            }
+        }
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(value).expect("admin requests are always serializable");
+    line.push(b'\n');
+    line
+}
@@ -0,0 +1,397 @@
+use crate::event::{Message, Reaction};
+use crate::protocol::FrameReader;
+use crate::server::Result;
+use crate::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+/// Time waited before connecting again to a broker that went away.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Time a new broker waits for the servers to connect before numbering
+/// messages, long enough for the servers of a broker that stopped to come
+/// back to the one taking over.
+const GATHER_TIME: Duration = Duration::from_secs(2);
+
+/// Room traffic shared by the servers using the same broker, each one
+/// sending it to its own clients.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Shared {
+    /// A message posted in a room. The broker gives it its identifier, the
+    /// same on every server.
+    Message(Message),
+    /// A reaction to a message of `room`.
+    React { room: String, reaction: Reaction },
+    /// A message of `room` was deleted.
+    Deleted { room: String, id: u64 },
+}
+
+/// Fans the room traffic out to every server sharing the same rooms.
+pub trait Broker {
+    /// Hands `shared` to every server using the broker, this one included.
+    fn publish(&mut self, shared: Shared) -> impl Future<Output = ()>;
+
+    /// Waits for the next traffic published by any server. Cancelling the
+    /// wait loses nothing.
+    fn next(&mut self) -> impl Future<Output = Shared>;
+}
+
+/// Broker of a server running alone, handing its traffic straight back.
+pub struct LocalBroker {
+    // traffic published and not yet handled
+    queue: VecDeque<Shared>,
+    // identifier of the next message
+    next_id: u64,
+}
+
+impl LocalBroker {
+    /// Creates the broker, numbering messages after `last_id`.
+    pub fn new(last_id: u64) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            next_id: last_id + 1,
+        }
+    }
+}
+
+impl Broker for LocalBroker {
+    async fn publish(&mut self, mut shared: Shared) {
+        if let Shared::Message(msg) = &mut shared {
+            msg.id = self.next_id;
+            self.next_id += 1;
+        }
+        self.queue.push_back(shared);
+    }
+
+    async fn next(&mut self) -> Shared {
+        match self.queue.pop_front() {
+            Some(shared) => shared,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Broker shared by the servers of a machine through a Unix socket. The
+/// first server started hosts it, and when it stops another one takes over.
+pub struct SocketBroker {
+    path: PathBuf,
+    // connection to the broker, `None` while it is unreachable
+    stream: Option<UnixStream>,
+    // when to try connecting again while the broker is unreachable
+    retry_at: Instant,
+    buffer: Vec<u8>,
+    reader: FrameReader,
+    // identifier of the newest message handled, telling a new broker where
+    // to number messages from
+    last_id: u64,
+}
+
+/// First line a server sends to the broker.
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    last_id: u64,
+}
+
+impl SocketBroker {
+    /// Connects to the broker at `path`, hosting it if no server does yet.
+    ///
+    /// # Returns
+    /// - `Ok(SocketBroker)`: If the broker could be reached or hosted.
+    /// - `Err(())`: If the socket is unusable, with a message logged.
+    pub async fn open(path: &Path, last_id: u64) -> Result<Self> {
+        let mut broker = Self {
+            path: path.to_path_buf(),
+            stream: None,
+            retry_at: Instant::now(),
+            buffer: vec![0u8; 4096],
+            reader: FrameReader::default(),
+            last_id,
+        };
+        broker.connect().await?;
+        Ok(broker)
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        let mut stream = match UnixStream::connect(&self.path).await {
+            Ok(stream) => stream,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::NotFound | ErrorKind::ConnectionRefused
+                ) =>
+            {
+                host(&self.path, err.kind() == ErrorKind::ConnectionRefused)?;
+                UnixStream::connect(&self.path).await.map_err(|err| {
+                    error!("Failed to connect to broker {}: {err}", self.path.display())
+                })?
+            }
+            Err(err) => {
+                error!("Failed to connect to broker {}: {err}", self.path.display());
+                return Err(());
+            }
+        };
+
+        let hello = encode(&Hello {
+            last_id: self.last_id,
+        });
+        stream
+            .write_all(&hello)
+            .await
+            .map_err(|err| error!("Failed to write to broker {}: {err}", self.path.display()))?;
+        self.stream = Some(stream);
+        self.reader = FrameReader::default();
+        Ok(())
+    }
+}
+
+impl Broker for SocketBroker {
+    async fn publish(&mut self, shared: Shared) {
+        let Some(stream) = &mut self.stream else {
+            error!("Broker unreachable, dropping room traffic");
+            return;
+        };
+        if let Err(err) = stream.write_all(&encode(&shared)).await {
+            error!("Failed to write to broker {}: {err}", self.path.display());
+            self.stream = None;
+            self.retry_at = Instant::now() + RETRY_DELAY;
+        }
+    }
+
+    async fn next(&mut self) -> Shared {
+        loop {
+            if let Some(line) = self.reader.next_line() {
+                match serde_json::from_slice(&line) {
+                    Ok(shared) => {
+                        if let Shared::Message(msg) = &shared {
+                            self.last_id = self.last_id.max(msg.id);
+                        }
+                        return shared;
+                    }
+                    Err(err) => {
+                        error!("Failed to parse traffic from broker: {err}");
+                        continue;
+                    }
+                }
+            }
+
+            let Some(stream) = &mut self.stream else {
+                // a deadline rather than a sleep, as waiting can be cancelled
+                tokio::time::sleep_until(self.retry_at).await;
+                self.retry_at = Instant::now() + RETRY_DELAY;
+                if let Ok(()) = self.connect().await {
+                    info!("Connected again to broker {}", self.path.display());
+                }
+                continue;
+            };
+            match stream.read(&mut self.buffer).await {
+                Ok(0) | Err(_) => {
+                    error!("Lost the connection to broker {}", self.path.display());
+                    self.stream = None;
+                    self.retry_at = Instant::now() + RETRY_DELAY;
+                }
+                Ok(n) => self.reader.push(&self.buffer[..n]),
+            }
+        }
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(value).expect("broker traffic is always serializable");
+    line.push(b'\n');
+    line
+}
+
+/// Hosts the broker at `path` in a task of this server, removing first the
+/// socket of a broker that stopped when `stale`.
+fn host(path: &Path, stale: bool) -> Result<()> {
+    if stale
+        && let Err(err) = fs::remove_file(path)
+        && err.kind() != ErrorKind::NotFound
+    {
+        error!("Failed to remove stale broker {}: {err}", path.display());
+        return Err(());
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        // another server started hosting it first
+        Err(err) if err.kind() == ErrorKind::AddrInUse => return Ok(()),
+        Err(err) => {
+            error!("Failed to host broker {}: {err}", path.display());
+            return Err(());
+        }
+    };
+    // any server connected can post as anyone, so only the user running
+    // the servers may connect
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|err| error!("Failed to set permissions of {}: {err}", path.display()))?;
+    info!("Hosting broker {}", path.display());
+    tokio::spawn(serve(listener, GATHER_TIME));
+    Ok(())
+}
+
+/// What a connection task passes to the broker loop.
+enum Inbound {
+    /// A server connected, its newest message being `last_id`.
+    Hello(u64, UnboundedSender<Vec<u8>>),
+    Shared(Shared),
+}
+
+/// Numbers the messages published by the servers and sends all the traffic
+/// to every one of them, in the same order. Messages are numbered after the
+/// newest one of every server connected, so the traffic published during
+/// the first `gather_time` is held until the servers had time to connect.
+async fn serve(listener: UnixListener, gather_time: Duration) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut servers: Vec<UnboundedSender<Vec<u8>>> = Vec::new();
+    let mut next_id = 1;
+    // traffic published while the servers are connecting
+    let mut held = Some(Vec::new());
+    let gathered = tokio::time::sleep(gather_time);
+    tokio::pin!(gathered);
+    loop {
+        let shared = tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok((stream, _)) => {
+                        tokio::spawn(connection(stream, sender.clone()));
+                    }
+                    Err(err) => error!("Failed to accept a server on the broker: {err}"),
+                }
+                continue;
+            }
+            () = &mut gathered, if held.is_some() => held.take().unwrap_or_default(),
+            Some(inbound) = receiver.recv() => match inbound {
+                Inbound::Hello(last_id, server) => {
+                    next_id = u64::max(next_id, last_id + 1);
+                    servers.push(server);
+                    continue;
+                }
+                Inbound::Shared(shared) => match &mut held {
+                    Some(held) => {
+                        held.push(shared);
+                        continue;
+                    }
+                    None => vec![shared],
+                },
+            },
+        };
+
+        for mut shared in shared {
+            if let Shared::Message(msg) = &mut shared {
+                msg.id = next_id;
+                next_id += 1;
+            }
+            let line = encode(&shared);
+            servers.retain(|server| server.send(line.clone()).is_ok());
+        }
+    }
+}
+
+/// Reads the traffic a server publishes on the broker, and writes it the
+/// traffic of all servers.
+async fn connection(stream: UnixStream, broker: UnboundedSender<Inbound>) {
+    let (mut read, mut write) = stream.into_split();
+    let (sender, mut receiver): (_, UnboundedReceiver<Vec<u8>>) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(line) = receiver.recv().await {
+            if write.write_all(&line).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut sender = Some(sender);
+    let mut buffer = vec![0u8; 4096];
+    let mut reader = FrameReader::default();
+    loop {
+        while let Some(line) = reader.next_line() {
+            let inbound = match sender.take() {
+                Some(sender) => match serde_json::from_slice::<Hello>(&line) {
+                    Ok(hello) => Inbound::Hello(hello.last_id, sender),
+                    Err(_) => return,
+                },
+                None => match serde_json::from_slice(&line) {
+                    Ok(shared) => Inbound::Shared(shared),
+                    Err(err) => {
+                        error!("Failed to parse traffic published on the broker: {err}");
+                        continue;
+                    }
+                },
+            };
+            if broker.send(inbound).is_err() {
+                return;
+            }
+        }
+        match read.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => reader.push(&buffer[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn message(body: &str) -> Shared {
+        let msg = Message::new(body.to_string(), SystemTime::now(), String::from("alice"));
+        Shared::Message(msg)
+    }
+
+    fn id(shared: &Shared) -> u64 {
+        match shared {
+            Shared::Message(msg) => msg.id,
+            _ => panic!("expected a message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn local_broker_numbers_after_the_history() {
+        let mut broker = LocalBroker::new(41);
+        broker.publish(message("first")).await;
+        broker.publish(message("second")).await;
+        assert_eq!(id(&broker.next().await), 42);
+        assert_eq!(id(&broker.next().await), 43);
+    }
+
+    #[tokio::test]
+    async fn socket_broker_numbers_after_every_server() {
+        let path = std::env::temp_dir().join(format!("rschat-broker-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(serve(listener, Duration::from_millis(300)));
+
+        let mut first = SocketBroker::open(&path, 5).await.unwrap();
+        first.publish(message("held")).await;
+        // a server further ahead connects after the message was published
+        let mut second = SocketBroker::open(&path, 40).await.unwrap();
+        second.publish(message("next")).await;
+
+        for broker in [&mut first, &mut second] {
+            assert_eq!(id(&broker.next().await), 41);
+            assert_eq!(id(&broker.next().await), 42);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hosted_brokers_are_private() {
+        let path = std::env::temp_dir().join(format!("rschat-private-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _broker = SocketBroker::open(&path, 0).await.unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+}
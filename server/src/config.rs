@@ -0,0 +1,389 @@
+use crate::error;
+use crate::macros::LogLevel;
+use crate::moderation::Role;
+use crate::server::Result;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Server settings, read from a TOML file. Every field has a default so a
+/// config file only needs to list the values it changes.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses the server listens on for client connections.
+    pub addresses: Vec<String>,
+    /// Addresses the server listens on for WebSocket connections.
+    pub ws_addresses: Vec<String>,
+    /// Whether the WebSocket addresses also serve a chat page to browsers.
+    pub web_client: bool,
+    /// Path of a Unix domain socket the server listens on for local clients.
+    pub unix_socket: Option<PathBuf>,
+    /// Whether Unix socket clients can log in as their system user without a
+    /// password, trusting the credentials the kernel gives for the socket.
+    pub peer_credentials: bool,
+    /// Name of the server, telling linked servers where messages come from.
+    pub server_name: String,
+    /// Addresses of the servers this server links to, sharing the messages of
+    /// the rooms they have in common.
+    pub links: Vec<String>,
+    /// Password servers give to link with this one, and this server gives to
+    /// the servers in `links`. Links are refused when it is not set.
+    pub link_password: Option<String>,
+    /// Unix socket of the broker shared by the servers of this machine, so
+    /// they serve the same rooms. The first server started hosts it.
+    pub broker_socket: Option<PathBuf>,
+    /// Unix socket `server admin` manages the running server through, only
+    /// usable by the user running the server.
+    pub admin_socket: Option<PathBuf>,
+    /// Address serving the metrics of the server in the Prometheus format on
+    /// `/metrics`.
+    pub metrics_address: Option<String>,
+    /// Maximum number of clients connected at the same time.
+    pub max_clients: usize,
+    /// Maximum number of clients connected at the same time from one IP address.
+    pub max_clients_per_ip: usize,
+    /// Seconds a new connection has to log in before being closed.
+    pub handshake_timeout: u64,
+    /// Seconds between two pings sent to each client.
+    pub heartbeat_interval: u64,
+    /// Seconds a client has to answer a ping before being disconnected.
+    pub heartbeat_timeout: u64,
+    /// Seconds a client can stay without sending anything before being
+    /// disconnected, `0` to never disconnect idle clients.
+    pub idle_timeout: u64,
+    /// Maximum size in bytes of a single message sent by a client.
+    pub max_message_size: usize,
+    /// Number of messages the server keeps in its history.
+    pub history_length: usize,
+    /// File every message is appended to, so the history survives restarts
+    /// and older messages can be searched.
+    pub history_file: Option<PathBuf>,
+    /// File the registered users and their password hashes are saved to.
+    pub accounts_file: Option<PathBuf>,
+    /// File the banned user names and IP addresses are saved to.
+    pub bans_file: Option<PathBuf>,
+    /// Seconds a login session can be resumed after its last use.
+    pub session_ttl: u64,
+    /// Rooms available on the server.
+    pub rooms: Vec<String>,
+    /// Message of the day shown to clients when they connect.
+    pub motd: Option<String>,
+    /// Colors advertised to clients for some users, such as `alice = "#ff8800"`.
+    pub user_colors: HashMap<String, String>,
+    /// Role of the users allowed to moderate, such as `alice = "owner"`.
+    pub roles: HashMap<String, Role>,
+    /// Flood protection of each connection.
+    pub rate_limit: RateLimitConfig,
+    /// Logging options.
+    pub log: LogConfig,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Frames a client can send per second, on average.
+    pub messages_per_sec: f64,
+    /// Frames a client can send at once after being quiet.
+    pub message_burst: u32,
+    /// Bytes a client can send per second, on average.
+    pub bytes_per_sec: u64,
+    /// Bytes a client can send at once after being quiet.
+    pub byte_burst: u64,
+    /// Login attempts a client can make per minute, on average.
+    pub logins_per_minute: f64,
+    /// Login attempts a client can make at once.
+    pub login_burst: u32,
+    /// Flooding warnings after which the client is muted, `0` to never mute.
+    pub mute_after: u32,
+    /// Seconds the frames of a muted client are dropped.
+    pub mute_seconds: u64,
+    /// Flooding warnings after which the client is disconnected, `0` to never
+    /// disconnect.
+    pub disconnect_after: u32,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Most verbose level printed by the server.
+    pub level: LogLevel,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addresses: vec![String::from("127.0.0.1:8080")],
+            ws_addresses: Vec::new(),
+            web_client: false,
+            unix_socket: None,
+            peer_credentials: false,
+            server_name: String::from("rschat"),
+            links: Vec::new(),
+            link_password: None,
+            broker_socket: None,
+            admin_socket: None,
+            metrics_address: None,
+            max_clients: 100,
+            max_clients_per_ip: 10,
+            handshake_timeout: 10,
+            idle_timeout: 0,
+            heartbeat_interval: 15,
+            heartbeat_timeout: 30,
+            max_message_size: 1024,
+            history_length: 1000,
+            history_file: None,
+            accounts_file: None,
+            bans_file: None,
+            session_ttl: 7 * 24 * 60 * 60,
+            rooms: vec![String::from("general")],
+            motd: None,
+            user_colors: HashMap::new(),
+            roles: HashMap::new(),
+            rate_limit: RateLimitConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: 5.0,
+            message_burst: 10,
+            bytes_per_sec: 4096,
+            byte_burst: 16384,
+            logins_per_minute: 6.0,
+            login_burst: 3,
+            mute_after: 3,
+            mute_seconds: 30,
+            disconnect_after: 5,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+        }
+    }
+}
+
+impl Config {
+    /// Reads and parses a configuration file.
+    ///
+    /// # Arguments
+    /// - `path`: Location of the TOML file.
+    ///
+    /// # Returns
+    /// - `Ok(Config)`: If the file could be read and parsed.
+    /// - `Err(())`: If the file is missing or malformed, with a message logged.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|err| error!("Failed to read config file {}: {err}", path.display()))?;
+        toml::from_str(&content)
+            .map_err(|err| error!("Invalid config file {}: {err}", path.display()))
+    }
+
+    /// Takes the settings of `other` that can change while the server runs:
+    /// rooms, roles, colors, message of the day, timeouts and log level.
+    pub fn apply_live(&mut self, other: Config) {
+        self.rooms = other.rooms;
+        self.roles = other.roles;
+        self.user_colors = other.user_colors;
+        self.motd = other.motd;
+        self.handshake_timeout = other.handshake_timeout;
+        self.idle_timeout = other.idle_timeout;
+        self.heartbeat_interval = other.heartbeat_interval;
+        self.heartbeat_timeout = other.heartbeat_timeout;
+        self.log = other.log;
+    }
+
+    /// Role of `user`, members unless configured otherwise.
+    pub fn role(&self, user: &str) -> Role {
+        self.roles.get(user).copied().unwrap_or_default()
+    }
+
+    /// Checks that the settings are usable, logging every problem found.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the configuration is valid.
+    /// - `Err(())`: If at least one setting is invalid.
+    pub fn validate(&self) -> Result<()> {
+        let mut valid = true;
+        let mut fail = |msg: String| {
+            error!("Invalid configuration: {msg}");
+            valid = false;
+        };
+
+        if self.addresses.is_empty() {
+            fail(String::from("at least one address is required"));
+        }
+        if (self.addresses.iter())
+            .chain(&self.ws_addresses)
+            .any(|addr| addr.trim().is_empty())
+        {
+            fail(String::from("addresses cannot be empty"));
+        }
+        if self.peer_credentials && self.unix_socket.is_none() {
+            fail(String::from("peer_credentials requires a unix_socket"));
+        }
+        if self.server_name.is_empty() || self.server_name.contains(['@', ' ']) {
+            fail(String::from(
+                "server_name must be non-empty and without spaces or '@'",
+            ));
+        }
+        if !self.links.is_empty() && self.link_password.is_none() {
+            fail(String::from("links require a link_password"));
+        }
+        let sockets = [&self.unix_socket, &self.broker_socket, &self.admin_socket];
+        let paths: Vec<&PathBuf> = sockets.into_iter().flatten().collect();
+        if paths.iter().collect::<HashSet<_>>().len() < paths.len() {
+            fail(String::from(
+                "unix_socket, broker_socket and admin_socket must be different paths",
+            ));
+        }
+        if self.web_client && self.ws_addresses.is_empty() {
+            fail(String::from(
+                "web_client requires at least one WebSocket address",
+            ));
+        }
+        if self.max_clients == 0 || self.max_clients_per_ip == 0 {
+            fail(String::from(
+                "max_clients and max_clients_per_ip must be greater than 0",
+            ));
+        }
+        if self.handshake_timeout == 0 {
+            fail(String::from("handshake_timeout must be greater than 0"));
+        }
+        if self.heartbeat_interval == 0 || self.heartbeat_timeout == 0 {
+            fail(String::from(
+                "heartbeat_interval and heartbeat_timeout must be greater than 0",
+            ));
+        }
+        if self.max_message_size < 64 {
+            fail(String::from("max_message_size must be at least 64 bytes"));
+        }
+        if self.session_ttl == 0 {
+            fail(String::from("session_ttl must be greater than 0"));
+        }
+        let rate_limit = &self.rate_limit;
+        if rate_limit.messages_per_sec <= 0.0
+            || rate_limit.bytes_per_sec == 0
+            || rate_limit.logins_per_minute <= 0.0
+        {
+            fail(String::from("rate limits must be greater than 0"));
+        }
+        if rate_limit.message_burst == 0 || rate_limit.login_burst == 0 {
+            fail(String::from(
+                "message_burst and login_burst must be greater than 0",
+            ));
+        }
+        // a frame larger than the burst could never be sent
+        if rate_limit.byte_burst < self.max_message_size as u64 {
+            fail(String::from("byte_burst must be at least max_message_size"));
+        }
+        if self.rooms.is_empty() {
+            fail(String::from("at least one room is required"));
+        }
+
+        let mut rooms = HashSet::new();
+        for room in &self.rooms {
+            if room.is_empty() || room.chars().any(char::is_whitespace) {
+                fail(format!(
+                    "room name '{room}' must be non-empty and without spaces"
+                ));
+            }
+            if !rooms.insert(room) {
+                fail(format!("room '{room}' is declared more than once"));
+            }
+        }
+
+        for (user, color) in &self.user_colors {
+            if !is_color(color) {
+                fail(format!(
+                    "color '{color}' of user '{user}' must be a color name or #rrggbb"
+                ));
+            }
+        }
+
+        if valid { Ok(()) } else { Err(()) }
+    }
+}
+
+/// Checks that `color` is a color name, such as `"light-red"`, or a `#rrggbb` value.
+fn is_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic() || c == '-'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Config {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+        assert!(parse("").validate().is_ok());
+    }
+
+    #[test]
+    fn files_only_list_changed_values() {
+        let config = parse(
+            "max_clients = 5\nrooms = [\"a\", \"b\"]\n[rate_limit]\nmute_after = 0\n[log]\nlevel = \"debug\"",
+        );
+        assert_eq!(config.max_clients, 5);
+        assert_eq!(config.rooms, ["a", "b"]);
+        assert_eq!(config.rate_limit.mute_after, 0);
+        assert_eq!(config.rate_limit.message_burst, 10);
+        assert_eq!(config.max_clients_per_ip, 10);
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let invalid: [fn(&mut Config); 10] = [
+            |config| config.addresses.clear(),
+            |config| config.ws_addresses.push(String::from(" ")),
+            |config| config.peer_credentials = true,
+            |config| config.server_name = String::from("a@b"),
+            |config| config.links.push(String::from("127.0.0.1:9000")),
+            |config| {
+                config.unix_socket = Some(PathBuf::from("chat.sock"));
+                config.admin_socket = Some(PathBuf::from("chat.sock"));
+            },
+            |config| config.max_clients_per_ip = 0,
+            |config| config.rate_limit.byte_burst = 100,
+            |config| config.rooms.push(String::from("general")),
+            |config| {
+                config
+                    .user_colors
+                    .insert(String::from("bob"), String::from("#12345"));
+            },
+        ];
+        for change in invalid {
+            let mut config = Config::default();
+            change(&mut config);
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn colors() {
+        assert!(is_color("light-red"));
+        assert!(is_color("#FF8800"));
+        assert!(!is_color("#ff880"));
+        assert!(!is_color("#gg8800"));
+        assert!(!is_color("red2"));
+        assert!(!is_color(""));
+    }
+}
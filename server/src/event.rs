@@ -1,23 +1,103 @@
 use serde::{Deserialize, Serialize};
 
+use crate::admin::{AdminCommand, AdminReply};
+use crate::federation::Relay;
+use crate::history::HistoryQuery;
+use crate::moderation::Moderation;
 use crate::server::Connection;
 use std::time::SystemTime;
+use tokio::sync::oneshot;
 
 pub enum ServerEvent {
     ClientConnected(Connection),
     ClientDisconnected(Connection),
+    LoggedIn(Connection, String),
+    Resume(Connection, String),
+    Logout(Connection),
     NewMessage(Connection, Message),
+    JoinRoom(Connection, String),
+    React(Connection, Reaction),
+    Search(Connection, HistoryQuery),
+    Sync(Connection, String, u64),
+    Moderate(Connection, Moderation),
+    Pong(Connection, u64),
+    /// A server asking to link with this one, giving its name and the link password.
+    Link(Connection, String, String),
+    /// A server accepted the link this server opened, giving its name.
+    Linked(Connection, String),
+    /// A message relayed by a linked server.
+    Relay(Connection, Relay),
+    /// A request received on the admin socket, with where to send the reply.
+    Admin(AdminCommand, oneshot::Sender<AdminReply>),
 }
 
-#[derive(Serialize, Deserialize)]
+impl ServerEvent {
+    /// Connection of the client the event comes from, if it comes from one.
+    pub fn connection(&self) -> Option<&Connection> {
+        let conn = match self {
+            ServerEvent::ClientConnected(conn)
+            | ServerEvent::ClientDisconnected(conn)
+            | ServerEvent::LoggedIn(conn, _)
+            | ServerEvent::Resume(conn, _)
+            | ServerEvent::Logout(conn)
+            | ServerEvent::NewMessage(conn, _)
+            | ServerEvent::JoinRoom(conn, _)
+            | ServerEvent::React(conn, _)
+            | ServerEvent::Search(conn, _)
+            | ServerEvent::Sync(conn, _, _)
+            | ServerEvent::Moderate(conn, _)
+            | ServerEvent::Pong(conn, _)
+            | ServerEvent::Link(conn, _, _)
+            | ServerEvent::Linked(conn, _)
+            | ServerEvent::Relay(conn, _) => conn,
+            ServerEvent::Admin(..) => return None,
+        };
+        Some(conn)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Message {
+    /// Identifier assigned by the server, `0` until the server accepts the message.
+    #[serde(default)]
+    pub id: u64,
+    /// Room the message was posted in, assigned by the server.
+    #[serde(default)]
+    pub room: String,
     pub body: String,
     pub date: SystemTime,
     pub author: String,
+    /// Identifier of the message this one replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    /// Color of the author advertised by the server, such as `"red"` or `"#ff8800"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    /// Name of the server the message was posted on, when it was relayed
+    /// from a linked server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+/// An emoji (or any short text) added by `author` to the message `id`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    pub id: u64,
+    pub emoji: String,
+    pub author: String,
 }
 
 impl Message {
     pub fn new(body: String, date: SystemTime, author: String) -> Self {
-        Self { body, date, author }
+        Self {
+            id: 0,
+            room: String::new(),
+            body,
+            date,
+            author,
+            reply_to: None,
+            color: None,
+            origin: None,
+        }
     }
 }
@@ -0,0 +1,458 @@
+use crate::event::{Message, ServerEvent};
+use crate::metrics::{Failure, METRICS};
+use crate::peer::{Peer, PeerAddr, Protocol, Socket};
+use crate::protocol::{Frame, FrameReader};
+use crate::server::{Connection, Result};
+use crate::{config::Config, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+
+/// Largest frame a linked server can send, `max_message_size` being the
+/// largest frame of a client: a relayed message also carries the fields set by
+/// its origin server and the servers it went through.
+pub fn frame_limit(max_message_size: usize) -> usize {
+    max_message_size * 2 + 1024
+}
+
+/// Time waited before connecting again to a server whose link broke.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A chat message passed from a server to the servers it is linked to.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Relay {
+    /// The message as posted on its origin server, with the identifier it
+    /// has there.
+    pub message: Message,
+    /// Servers the message went through, starting with its origin. A server
+    /// never relays a message to the servers listed here.
+    pub via: Vec<String>,
+}
+
+/// Links to other servers, sharing the messages of the rooms they have in
+/// common. Each server keeps its own history and identifiers, messages from
+/// other servers being shown as posted by `user@server`.
+pub struct Federation {
+    // name of this server, tagging the messages posted here
+    name: String,
+    // password the other servers give to link with this one
+    password: Option<String>,
+    // connections to the linked servers
+    links: HashMap<PeerAddr, Link>,
+    // origin and identifier of the messages recently relayed here, so the
+    // ones coming through several links are shown once
+    seen: HashSet<(String, u64)>,
+    // order the messages were seen in, to forget the oldest ones
+    seen_order: VecDeque<(String, u64)>,
+}
+
+/// Connection to a linked server, pinged like clients are so that a link
+/// left half-open is closed, and connected again when this server opened it.
+struct Link {
+    conn: Connection,
+    // when the server was last pinged
+    last_ping: Instant,
+    // nonce of the ping waiting for an answer, and when it was sent
+    ping: Option<(u64, Instant)>,
+}
+
+impl Federation {
+    /// Messages remembered to drop duplicates.
+    const MAX_SEEN: usize = 10_000;
+
+    pub fn new(name: &str, password: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            password,
+            links: HashMap::new(),
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+        }
+    }
+
+    /// Checks the password given by a server asking to link, taking the same
+    /// time whatever the characters it has right.
+    pub fn check_password(&self, password: &str) -> bool {
+        let Some(expected) = &self.password else {
+            return false;
+        };
+        expected.len() == password.len()
+            && (expected.bytes().zip(password.bytes())).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// Starts relaying messages to and from `server`, at the other end of
+    /// `conn`. Only then is `conn.link` set, so a refused connection is not
+    /// treated as a link.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the server was not linked yet.
+    /// - `Err(reason)`: Otherwise, with the reason to report to it.
+    pub fn add(&mut self, server: String, conn: Connection) -> std::result::Result<(), String> {
+        if server == self.name {
+            return Err(format!("This server is already named '{server}'"));
+        }
+        if (self.links.values()).any(|link| link.conn.link.get() == Some(&server)) {
+            return Err(format!("Server '{server}' is already linked"));
+        }
+        info!("Linked to server {server} at {}", conn.addr);
+        let _ = conn.link.set(server);
+        let link = Link {
+            conn: Arc::clone(&conn),
+            last_ping: Instant::now(),
+            ping: None,
+        };
+        self.links.insert(conn.addr, link);
+        Ok(())
+    }
+
+    /// Forgets the link with `addr` once its connection is closed.
+    ///
+    /// # Returns
+    /// Whether `addr` was a linked server.
+    pub fn remove(&mut self, addr: &PeerAddr) -> bool {
+        let Some(link) = self.links.remove(addr) else {
+            return false;
+        };
+        let server = link.conn.link.get().map_or("?", String::as_str);
+        info!("Link to server {server} at {addr} closed");
+        true
+    }
+
+    /// Number of linked servers.
+    pub fn link_count(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Whether `addr` is the connection of a linked server.
+    pub fn is_link(&self, addr: &PeerAddr) -> bool {
+        self.links.contains_key(addr)
+    }
+
+    /// Pings the linked servers every `config.heartbeat_interval`, and closes
+    /// the links that did not answer within `config.heartbeat_timeout`.
+    pub async fn heartbeat(&mut self, config: &Config) {
+        let interval = Duration::from_secs(config.heartbeat_interval);
+        let timeout = Duration::from_secs(config.heartbeat_timeout);
+        let mut dead = Vec::new();
+        for (addr, link) in &mut self.links {
+            if let Some((_, at)) = link.ping {
+                if at.elapsed() > timeout {
+                    dead.push(*addr);
+                }
+                continue;
+            }
+
+            if link.last_ping.elapsed() >= interval {
+                let (ping, nonce) = Frame::ping();
+                link.conn.send(&ping).await;
+                link.ping = Some((nonce, Instant::now()));
+                link.last_ping = Instant::now();
+            }
+        }
+
+        for addr in dead {
+            // the connection may be half-open, so its task might never end
+            if let Some(link) = self.links.remove(&addr) {
+                let server = link.conn.link.get().map_or("?", String::as_str);
+                info!(
+                    "Closing link to server {server} at {addr}: no answer to ping for {} seconds",
+                    config.heartbeat_timeout
+                );
+                METRICS.failure(Failure::Timeout);
+                link.conn.close();
+            }
+        }
+    }
+
+    /// Records the answer of a linked server to a ping.
+    pub fn pong(&mut self, addr: &PeerAddr, nonce: u64) {
+        if let Some(link) = self.links.get_mut(addr)
+            && link.ping.is_some_and(|(sent, _)| sent == nonce)
+        {
+            link.ping = None;
+        }
+    }
+
+    /// Sends a message posted on this server to every linked server.
+    pub async fn publish(&self, msg: &Message) {
+        let mut message = msg.clone();
+        message.origin = Some(self.name.clone());
+        let relay = Relay {
+            message,
+            via: vec![self.name.clone()],
+        };
+        self.forward(relay).await;
+    }
+
+    /// Handles a message relayed by a linked server, passing it on to the
+    /// other linked servers.
+    ///
+    /// # Returns
+    /// The message to show on this server, or `None` if it was already seen
+    /// or went through this server before.
+    pub async fn receive(&mut self, mut relay: Relay) -> Option<Message> {
+        let origin = relay.message.origin.clone()?;
+        if relay.via.contains(&self.name) {
+            return None;
+        }
+        let key = (origin.clone(), relay.message.id);
+        if !self.seen.insert(key.clone()) {
+            return None;
+        }
+        self.seen_order.push_back(key);
+        if self.seen_order.len() > Self::MAX_SEEN
+            && let Some(oldest) = self.seen_order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+
+        let mut msg = relay.message.clone();
+        relay.via.push(self.name.clone());
+        self.forward(relay).await;
+
+        msg.author = format!("{}@{origin}", msg.author);
+        // the replied message has another identifier here, if it is known at all
+        msg.reply_to = None;
+        Some(msg)
+    }
+
+    /// Sends a relayed message to the linked servers it did not go through.
+    async fn forward(&self, relay: Relay) {
+        let targets: Vec<&Connection> = (self.links.values())
+            .map(|link| &link.conn)
+            .filter(|conn| (conn.link.get()).is_some_and(|server| !relay.via.contains(server)))
+            .collect();
+        let frame = Frame::Relay(relay);
+        for conn in targets {
+            conn.send(&frame).await;
+        }
+    }
+}
+
+/// Keeps a link open with the server at `addr`, connecting again whenever it
+/// breaks. Messages relayed by the server are handed to the server loop.
+pub async fn connect(addr: String, sender: Sender<ServerEvent>, config: Config) {
+    loop {
+        let _ = link(&addr, &sender, &config).await;
+        info!(
+            "Connecting again to server {addr} in {} seconds",
+            RETRY_DELAY.as_secs()
+        );
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+/// Opens a link with the server at `addr` and reads its frames until the
+/// connection closes.
+///
+/// # Returns
+/// - `Ok(())`: If the link was established and then closed.
+/// - `Err(())`: If the server could not be reached or refused the link, with
+///   a message logged.
+async fn link(addr: &str, sender: &Sender<ServerEvent>, config: &Config) -> Result<()> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|err| error!("Failed to connect to server {addr}: {err}"))?;
+    let peer_addr = stream
+        .peer_addr()
+        .map_err(|err| error!("Failed to connect to server {addr}: {err}"))?;
+    let timeout = Duration::from_secs(config.handshake_timeout);
+    let (peer, mut incoming) = Peer::accept(
+        Socket::Tcp(stream),
+        Protocol::Tcp,
+        PeerAddr::Ip(peer_addr),
+        timeout,
+        frame_limit(config.max_message_size),
+        false,
+    )
+    .await?;
+    let conn = Arc::new(peer);
+    conn.send(&Frame::Link {
+        server: config.server_name.clone(),
+        password: config.link_password.clone().unwrap_or_default(),
+    })
+    .await;
+
+    let max_frame_size = frame_limit(config.max_message_size);
+    let mut buffer = vec![0u8; max_frame_size];
+    let mut reader = FrameReader::default();
+    let mut result = Ok(());
+    // whether the server answered the link request
+    let mut linked = false;
+    'receive: loop {
+        let receive = incoming.receive(&conn, &mut buffer, &mut reader);
+        // once linked, the heartbeat of the server loop closes a dead link
+        let received = match linked {
+            true => receive.await,
+            false => match tokio::time::timeout(timeout, receive).await {
+                Ok(received) => received,
+                Err(_) => {
+                    error!("Server {addr} did not answer the link request in time");
+                    result = Err(());
+                    break;
+                }
+            },
+        };
+        if !matches!(received, Ok(true)) {
+            break;
+        }
+
+        while let Some(frame) = reader.next_frame() {
+            let event = match frame {
+                Ok(Frame::Linked { server }) if !linked => {
+                    linked = true;
+                    ServerEvent::Linked(Arc::clone(&conn), server)
+                }
+                Ok(Frame::Relay(relay)) if linked => ServerEvent::Relay(Arc::clone(&conn), relay),
+                Ok(Frame::Ping { nonce }) => {
+                    conn.send(&Frame::Pong { nonce }).await;
+                    continue;
+                }
+                Ok(Frame::Pong { nonce }) if linked => ServerEvent::Pong(Arc::clone(&conn), nonce),
+                Ok(Frame::Error { reason } | Frame::Disconnected { reason }) => {
+                    error!("Server {addr} closed the link: {reason}");
+                    result = Err(());
+                    break 'receive;
+                }
+                Ok(_) => continue,
+                Err(err) => {
+                    error!("Failed to parse frame from server {addr}: {err}");
+                    result = Err(());
+                    break 'receive;
+                }
+            };
+            if sender.send(event).await.is_err() {
+                break 'receive;
+            }
+        }
+        if reader.pending() > max_frame_size {
+            error!("Server {addr} sent a frame larger than {max_frame_size} bytes");
+            result = Err(());
+            break;
+        }
+    }
+    conn.close();
+    incoming.finish(&conn).await;
+
+    if conn.link.get().is_some() {
+        let _ = sender.send(ServerEvent::ClientDisconnected(conn)).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    /// Message `id` posted by bob on `origin`, relayed through `via`.
+    fn relay(origin: &str, id: u64, via: &[&str]) -> Relay {
+        let mut message = Message::new(
+            String::from("Hello"),
+            SystemTime::now(),
+            String::from("bob"),
+        );
+        message.id = id;
+        message.room = String::from("general");
+        message.origin = Some(origin.to_string());
+        message.reply_to = Some(id - 1);
+        Relay {
+            message,
+            via: via.iter().map(|server| server.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn relayed_messages_are_shown_once() {
+        let mut federation = Federation::new("paris", None);
+        let msg = federation
+            .receive(relay("lyon", 7, &["lyon"]))
+            .await
+            .unwrap();
+        assert_eq!(msg.author, "bob@lyon");
+        assert_eq!(msg.origin.as_deref(), Some("lyon"));
+        assert_eq!(msg.reply_to, None);
+
+        // the same message coming through another server
+        assert!(
+            federation
+                .receive(relay("lyon", 7, &["lyon", "nice"]))
+                .await
+                .is_none()
+        );
+        // identifiers are only unique on their origin server
+        assert!(
+            federation
+                .receive(relay("nice", 7, &["nice"]))
+                .await
+                .is_some()
+        );
+        assert!(
+            federation
+                .receive(relay("lyon", 8, &["lyon"]))
+                .await
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_do_not_loop() {
+        let mut federation = Federation::new("paris", None);
+        // posted here, and relayed back by a linked server
+        assert!(
+            federation
+                .receive(relay("paris", 3, &["paris", "lyon"]))
+                .await
+                .is_none()
+        );
+        assert!(
+            federation
+                .receive(relay("nice", 3, &["nice", "paris", "lyon"]))
+                .await
+                .is_none()
+        );
+
+        let mut orphan = relay("lyon", 4, &["lyon"]);
+        orphan.message.origin = None;
+        assert!(federation.receive(orphan).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn oldest_seen_messages_are_forgotten() {
+        let mut federation = Federation::new("paris", None);
+        for id in 1..=Federation::MAX_SEEN as u64 + 1 {
+            assert!(
+                federation
+                    .receive(relay("lyon", id, &["lyon"]))
+                    .await
+                    .is_some()
+            );
+        }
+        assert_eq!(federation.seen.len(), Federation::MAX_SEEN);
+        assert!(
+            federation
+                .receive(relay("lyon", 1, &["lyon"]))
+                .await
+                .is_some()
+        );
+        assert!(
+            federation
+                .receive(relay("lyon", 3, &["lyon"]))
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn link_passwords() {
+        let federation = Federation::new("paris", Some(String::from("secret")));
+        assert!(federation.check_password("secret"));
+        assert!(!federation.check_password("secreT"));
+        assert!(!federation.check_password("secrets"));
+        assert!(!federation.check_password(""));
+        assert!(!Federation::new("paris", None).check_password(""));
+    }
+}
@@ -0,0 +1,591 @@
+use crate::event::Message;
+use crate::server::Result;
+use crate::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A search over the history of the server. Every field is optional, a query
+/// without any returns the newest messages.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HistoryQuery {
+    /// Words that must all appear in the body, ignoring case.
+    pub text: String,
+    /// Author of the messages.
+    pub author: Option<String>,
+    /// Room the messages were posted in.
+    pub room: Option<String>,
+    /// Only messages posted at or after this date.
+    pub after: Option<SystemTime>,
+    /// Only messages posted before this date.
+    pub before: Option<SystemTime>,
+    /// Only messages older than this identifier, used to fetch the next page.
+    pub before_id: Option<u64>,
+    /// Only messages newer than this identifier.
+    pub after_id: Option<u64>,
+    /// Number of messages wanted, capped by the server.
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    fn matches(&self, msg: &Message, words: &[String]) -> bool {
+        let body = msg.body.to_lowercase();
+        self.author
+            .as_ref()
+            .is_none_or(|author| msg.author == *author)
+            && self.room.as_ref().is_none_or(|room| msg.room == *room)
+            && self.after.is_none_or(|after| msg.date >= after)
+            && self.before.is_none_or(|before| msg.date < before)
+            && self.before_id.is_none_or(|id| msg.id < id)
+            && self.after_id.is_none_or(|id| msg.id > id)
+            && words.iter().all(|word| body.contains(word))
+    }
+}
+
+/// A page of search results, newest message first.
+pub struct HistoryPage {
+    pub messages: Vec<Message>,
+    /// Identifier to use as `before_id` to get the next page, if there is one.
+    pub next: Option<u64>,
+}
+
+/// Messages of a room a client missed, oldest first.
+pub struct Missed {
+    pub messages: Vec<Message>,
+    /// Whether the client must forget the messages it has of the room, as
+    /// they do not come from this history, or more messages were missed than
+    /// fit in `messages` and the client would be left with a gap.
+    pub reset: bool,
+}
+
+/// Messages posted on the server. The newest ones are kept in memory, and
+/// when a history file is configured every message is also appended to it as
+/// a line of JSON, so that it survives restarts and can be searched.
+pub struct History {
+    // newest messages, oldest first
+    recent: VecDeque<Message>,
+    // number of messages kept in memory
+    capacity: usize,
+    // file every message is appended to
+    file: Option<HistoryFile>,
+    // identifier of the next message
+    next_id: u64,
+}
+
+/// The file a history is persisted to, with where each message lies in it, so
+/// that searches and deletions do not go through all of it.
+struct HistoryFile {
+    path: PathBuf,
+    file: File,
+    // every message of the file, in the order of the file and so of the identifiers
+    lines: Vec<Line>,
+}
+
+/// Position of a message in the history file.
+#[derive(Clone, Copy)]
+struct Line {
+    id: u64,
+    offset: u64,
+    // length of the line, newline included
+    len: u64,
+}
+
+impl History {
+    /// Messages returned by a search when the query does not ask for a number.
+    const DEFAULT_PAGE: usize = 20;
+    /// Most messages returned by a single search.
+    pub const MAX_PAGE: usize = 100;
+
+    /// Creates the history, loading the messages persisted in `path` if given.
+    ///
+    /// # Arguments
+    /// - `capacity`: Number of messages kept in memory.
+    /// - `path`: File the messages are persisted to, created if missing.
+    ///
+    /// # Returns
+    /// - `Ok(History)`: If the file could be read and opened for writing.
+    /// - `Err(())`: If the file is unusable, with a message logged.
+    pub fn open(capacity: usize, path: Option<&Path>) -> Result<Self> {
+        let mut history = Self {
+            recent: VecDeque::with_capacity(capacity),
+            capacity,
+            file: None,
+            next_id: 1,
+        };
+        let Some(path) = path else {
+            return Ok(history);
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| error!("Failed to open history file {}: {err}", path.display()))?;
+
+        let mut lines = Vec::new();
+        let mut reader = BufReader::new(&file);
+        let mut buffer = Vec::new();
+        let mut offset = 0;
+        loop {
+            buffer.clear();
+            let len = reader
+                .read_until(b'\n', &mut buffer)
+                .map_err(|err| error!("Failed to read history file {}: {err}", path.display()))?
+                as u64;
+            if len == 0 {
+                break;
+            }
+            // deleted messages are blanked out, and malformed lines skipped
+            if let Ok(msg) = serde_json::from_slice::<Message>(&buffer) {
+                history.next_id = history.next_id.max(msg.id + 1);
+                lines.push(Line {
+                    id: msg.id,
+                    offset,
+                    len,
+                });
+                history.remember(msg);
+            }
+            offset += len;
+        }
+        info!("Loaded {} messages from {}", lines.len(), path.display());
+
+        history.file = Some(HistoryFile {
+            path: path.to_path_buf(),
+            file,
+            lines,
+        });
+        Ok(history)
+    }
+
+    /// Messages persisted in `path` from `offset` on, oldest first. A missing
+    /// file holds no messages, and blank or malformed lines are skipped.
+    fn read(path: &Path, offset: u64) -> Result<impl Iterator<Item = Message>> {
+        let file = match File::open(path) {
+            Ok(mut file) => {
+                file.seek(SeekFrom::Start(offset)).map_err(|err| {
+                    error!("Failed to read history file {}: {err}", path.display())
+                })?;
+                Some(file)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                error!("Failed to read history file {}: {err}", path.display());
+                return Err(());
+            }
+        };
+
+        Ok(file.into_iter().flat_map(|file| {
+            BufReader::new(file)
+                .lines()
+                .map_while(std::result::Result::ok)
+                .filter_map(|line| serde_json::from_str(&line).ok())
+        }))
+    }
+
+    /// Identifier of the newest message, `0` when there are none.
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    /// Records a message, numbered by the broker, persisting it when a
+    /// history file is configured.
+    pub fn push(&mut self, msg: Message) {
+        self.next_id = self.next_id.max(msg.id + 1);
+        if let Some(file) = &mut self.file {
+            file.append(&msg);
+        }
+        self.remember(msg);
+    }
+
+    fn remember(&mut self, msg: Message) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.recent.len() == self.capacity {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(msg);
+    }
+
+    /// Finds a message among the ones kept in memory, or else in the history
+    /// file, reading only its line.
+    pub fn find(&self, id: u64) -> Option<Message> {
+        match self.recent.iter().find(|msg| msg.id == id) {
+            Some(msg) => Some(msg.clone()),
+            None => self.file.as_ref()?.find(id),
+        }
+    }
+
+    /// Deletes a message, blanking out its line of the history file when there
+    /// is one.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the message is gone.
+    /// - `Err(())`: If the history file could not be written, with a message logged.
+    pub fn delete(&mut self, id: u64) -> Result<()> {
+        self.recent.retain(|msg| msg.id != id);
+        match &mut self.file {
+            Some(file) => file.erase(id),
+            None => Ok(()),
+        }
+    }
+
+    /// Searches every persisted message, or the ones kept in memory when there
+    /// is no history file. The file is read on a blocking thread, from the
+    /// first message newer than `after_id`, and the returned future does not
+    /// borrow the history so that the server loop need not wait for it.
+    pub fn search(
+        &self,
+        query: HistoryQuery,
+    ) -> impl Future<Output = Result<HistoryPage>> + Send + use<> {
+        let file = self.file.as_ref().map(|file| {
+            let offset = file.offset_after(query.after_id.unwrap_or_default());
+            (file.path.clone(), offset)
+        });
+        let recent: Vec<Message> = match file {
+            Some(_) => Vec::new(),
+            None => self.recent.iter().cloned().collect(),
+        };
+
+        async move {
+            let Some((path, offset)) = file else {
+                return Ok(Self::page(recent.into_iter(), &query));
+            };
+            tokio::task::spawn_blocking(move || Ok(Self::page(Self::read(&path, offset)?, &query)))
+                .await
+                .map_err(|err| error!("Failed to search the history: {err}"))?
+        }
+    }
+
+    /// Messages of `room` newer than `after_id`, the newest message a client
+    /// has, up to a page of the newest ones.
+    pub fn missed(
+        &self,
+        room: String,
+        after_id: u64,
+    ) -> impl Future<Output = Result<Missed>> + Send + use<> {
+        // the client knows of messages this history never had
+        let unknown = after_id > self.last_id();
+        let query = HistoryQuery {
+            room: Some(room),
+            after_id: Some(if unknown { 0 } else { after_id }),
+            limit: Some(Self::MAX_PAGE),
+            ..HistoryQuery::default()
+        };
+        let search = self.search(query);
+
+        async move {
+            let page = search.await?;
+            Ok(Missed {
+                reset: unknown || page.next.is_some(),
+                messages: page.messages.into_iter().rev().collect(),
+            })
+        }
+    }
+
+    /// The page of `messages`, oldest first, matching `query`.
+    fn page(messages: impl Iterator<Item = Message>, query: &HistoryQuery) -> HistoryPage {
+        let limit = query
+            .limit
+            .unwrap_or(Self::DEFAULT_PAGE)
+            .clamp(1, Self::MAX_PAGE);
+        let words: Vec<String> = query
+            .text
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect();
+
+        // keeps one match more than the page, to know if there is a next one
+        let mut found: VecDeque<Message> = VecDeque::with_capacity(limit + 1);
+        // identifiers only grow, nothing is left past `before_id`
+        let older = |msg: &Message| query.before_id.is_none_or(|id| msg.id < id);
+        for msg in messages.take_while(older) {
+            if query.matches(&msg, &words) {
+                if found.len() > limit {
+                    found.pop_front();
+                }
+                found.push_back(msg);
+            }
+        }
+
+        let next = if found.len() > limit {
+            found.pop_front();
+            found.front().map(|msg| msg.id)
+        } else {
+            None
+        };
+        HistoryPage {
+            messages: found.into_iter().rev().collect(),
+            next,
+        }
+    }
+}
+
+impl HistoryFile {
+    /// Appends a message as a new line.
+    fn append(&mut self, msg: &Message) {
+        let mut line = serde_json::to_vec(msg).expect("messages are always serializable");
+        line.push(b'\n');
+        let written = self.file.seek(SeekFrom::End(0)).and_then(|offset| {
+            self.file.write_all(&line)?;
+            Ok(offset)
+        });
+        match written {
+            Ok(offset) => self.lines.push(Line {
+                id: msg.id,
+                offset,
+                len: line.len() as u64,
+            }),
+            Err(err) => error!(
+                "Failed to write to history file {}: {err}",
+                self.path.display()
+            ),
+        }
+    }
+
+    /// Blanks out the line of a message, keeping its length so that the
+    /// following lines stay where they are.
+    fn erase(&mut self, id: u64) -> Result<()> {
+        let Ok(index) = self.lines.binary_search_by_key(&id, |line| line.id) else {
+            return Ok(());
+        };
+        let line = self.lines[index];
+
+        let mut blank = vec![b' '; line.len as usize - 1];
+        blank.push(b'\n');
+        self.file
+            .seek(SeekFrom::Start(line.offset))
+            .and_then(|_| self.file.write_all(&blank))
+            .map_err(|err| {
+                error!(
+                    "Failed to delete from history file {}: {err}",
+                    self.path.display()
+                )
+            })?;
+        self.lines.remove(index);
+        Ok(())
+    }
+
+    /// Reads the message `id` from its line, if it is in the file.
+    fn find(&self, id: u64) -> Option<Message> {
+        let index = self.lines.binary_search_by_key(&id, |line| line.id).ok()?;
+        let line = self.lines[index];
+        let mut buffer = vec![0; line.len as usize];
+        if let Err(err) = self.file.read_exact_at(&mut buffer, line.offset) {
+            error!("Failed to read history file {}: {err}", self.path.display());
+            return None;
+        }
+        serde_json::from_slice(&buffer).ok()
+    }
+
+    /// Offset of the first message newer than `after_id`.
+    fn offset_after(&self, after_id: u64) -> u64 {
+        let newer = self.lines.partition_point(|line| line.id <= after_id);
+        match self.lines.get(newer) {
+            Some(line) => line.offset,
+            // past the newest message, where the next one will be appended
+            None => self.lines.last().map_or(0, |line| line.offset + line.len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    /// Message `id` of the `room`, posted `id` seconds after the epoch.
+    fn message(id: u64, room: &str, author: &str, body: &str) -> Message {
+        let date = SystemTime::UNIX_EPOCH + Duration::from_secs(id);
+        let mut msg = Message::new(body.to_string(), date, author.to_string());
+        msg.id = id;
+        msg.room = room.to_string();
+        msg
+    }
+
+    /// A history of 25 messages, alternating between two rooms and authors.
+    fn history(path: Option<&Path>) -> History {
+        let mut history = History::open(10, path).unwrap();
+        for id in 1..=25 {
+            let (room, author) = if id % 2 == 0 {
+                ("general", "alice")
+            } else {
+                ("random", "bob")
+            };
+            history.push(message(id, room, author, &format!("Message number {id}")));
+        }
+        history
+    }
+
+    fn ids(page: &HistoryPage) -> Vec<u64> {
+        page.messages.iter().map(|msg| msg.id).collect()
+    }
+
+    /// Path of a history file unique to `test`, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(test: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rschat-{test}-{}.jsonl", std::process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn pages_go_from_newest_to_oldest() {
+        let file = TempFile::new("pages");
+        let history = history(Some(&file.0));
+        let mut query = HistoryQuery {
+            limit: Some(10),
+            ..HistoryQuery::default()
+        };
+
+        let page = history.search(query.clone()).await.unwrap();
+        assert_eq!(ids(&page), (16..=25).rev().collect::<Vec<_>>());
+        assert_eq!(page.next, Some(16));
+
+        query.before_id = page.next;
+        let page = history.search(query.clone()).await.unwrap();
+        assert_eq!(ids(&page), (6..=15).rev().collect::<Vec<_>>());
+        assert_eq!(page.next, Some(6));
+
+        query.before_id = page.next;
+        let page = history.search(query).await.unwrap();
+        assert_eq!(ids(&page), (1..=5).rev().collect::<Vec<_>>());
+        assert_eq!(page.next, None);
+    }
+
+    #[tokio::test]
+    async fn missed_messages() {
+        let file = TempFile::new("missed");
+        let mut history = History::open(10, Some(&file.0)).unwrap();
+        for id in 1..=250 {
+            history.push(message(id, "general", "alice", "Hello"));
+        }
+
+        let missed = history.missed(String::from("general"), 200).await.unwrap();
+        let ids: Vec<u64> = missed.messages.iter().map(|msg| msg.id).collect();
+        assert_eq!(ids, (201..=250).collect::<Vec<_>>());
+        assert!(!missed.reset);
+
+        // more than a page was missed: the newest page replaces what the client has
+        let missed = history.missed(String::from("general"), 20).await.unwrap();
+        let ids: Vec<u64> = missed.messages.iter().map(|msg| msg.id).collect();
+        assert_eq!(ids, (151..=250).collect::<Vec<_>>());
+        assert!(missed.reset);
+
+        let missed = history.missed(String::from("general"), 250).await.unwrap();
+        assert!(missed.messages.is_empty());
+        assert!(!missed.reset);
+
+        // the client has messages this history never had
+        let missed = history.missed(String::from("general"), 300).await.unwrap();
+        assert_eq!(missed.messages.len(), History::MAX_PAGE);
+        assert!(missed.reset);
+
+        let missed = history.missed(String::from("random"), 0).await.unwrap();
+        assert!(missed.messages.is_empty());
+        assert!(!missed.reset);
+    }
+
+    #[tokio::test]
+    async fn queries_filter_messages() {
+        let file = TempFile::new("filters");
+        let history = history(Some(&file.0));
+
+        let query = HistoryQuery {
+            text: String::from("NUMBER 1"),
+            room: Some(String::from("general")),
+            ..HistoryQuery::default()
+        };
+        let page = history.search(query).await.unwrap();
+        assert_eq!(ids(&page), vec![18, 16, 14, 12, 10]);
+
+        let query = HistoryQuery {
+            author: Some(String::from("bob")),
+            after_id: Some(20),
+            ..HistoryQuery::default()
+        };
+        let page = history.search(query).await.unwrap();
+        assert_eq!(ids(&page), vec![25, 23, 21]);
+
+        let query = HistoryQuery {
+            after: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(3)),
+            before: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(6)),
+            ..HistoryQuery::default()
+        };
+        let page = history.search(query).await.unwrap();
+        assert_eq!(ids(&page), vec![5, 4, 3]);
+    }
+
+    #[tokio::test]
+    async fn limits_are_capped() {
+        let history = history(None);
+        let query = HistoryQuery {
+            limit: Some(0),
+            ..HistoryQuery::default()
+        };
+        let page = history.search(query).await.unwrap();
+        assert_eq!(ids(&page), vec![25]);
+        assert_eq!(page.next, Some(25));
+    }
+
+    #[tokio::test]
+    async fn without_a_file_only_recent_messages_are_searched() {
+        let history = history(None);
+        let page = history.search(HistoryQuery::default()).await.unwrap();
+        assert_eq!(ids(&page), (16..=25).rev().collect::<Vec<_>>());
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn older_messages_are_found_in_the_file() {
+        let file = TempFile::new("find");
+        let history = history(Some(&file.0));
+        assert_eq!(history.find(25).unwrap().body, "Message number 25");
+        // only the 10 newest messages are kept in memory
+        assert_eq!(history.find(2).unwrap().body, "Message number 2");
+        assert!(history.find(26).is_none());
+
+        let history = self::history(None);
+        assert!(history.find(2).is_none());
+    }
+
+    #[tokio::test]
+    async fn deleted_messages_stay_deleted() {
+        let file = TempFile::new("deleted");
+        let mut history = history(Some(&file.0));
+        history.delete(24).unwrap();
+        history.delete(3).unwrap();
+        assert!(history.find(24).is_none());
+        assert!(history.find(3).is_none());
+        assert!(history.delete(3).is_ok());
+
+        let history = History::open(10, Some(&file.0)).unwrap();
+        assert_eq!(history.last_id(), 25);
+        let query = HistoryQuery {
+            limit: Some(History::MAX_PAGE),
+            ..HistoryQuery::default()
+        };
+        let page = history.search(query).await.unwrap();
+        assert_eq!(page.messages.len(), 23);
+        assert!(!ids(&page).contains(&24));
+        assert!(!ids(&page).contains(&3));
+    }
+}
@@ -1,3 +1,19 @@
+// Errors are logged where they happen, so fallible functions return `Result<T, ()>`.
+#![allow(clippy::result_unit_err)]
+
+pub mod accounts;
+pub mod admin;
+pub mod broker;
+pub mod config;
 pub mod event;
+pub mod federation;
+pub mod history;
 pub mod macros;
+pub mod metrics;
+pub mod moderation;
+pub mod peer;
+pub mod protocol;
+pub mod rate_limit;
 pub mod server;
+pub mod sessions;
+pub mod web;
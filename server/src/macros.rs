@@ -1,21 +1,51 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, PartialOrd, Debug, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Debug as u8);
+
+/// Sets the most verbose level printed by the logging macros.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns whether messages of `level` should currently be printed.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
 // Requires 'colored' create
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => {
-        eprintln!("{} {}", ::colored::Colorize::red("ERROR:"), format!($($arg)*))
+        if $crate::macros::log_enabled($crate::macros::LogLevel::Error) {
+            eprintln!("{} {}", ::colored::Colorize::red("ERROR:"), format!($($arg)*))
+        }
     };
 }
 
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {
-        eprintln!("{} {}", ::colored::Colorize::blue("INFO:"), format!($($arg)*))
+        if $crate::macros::log_enabled($crate::macros::LogLevel::Info) {
+            eprintln!("{} {}", ::colored::Colorize::blue("INFO:"), format!($($arg)*))
+        }
     };
 }
 
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {
-        eprintln!("{} {}", ::colored::Colorize::yellow("DEBUG:"), format!($($arg)*))
+        if $crate::macros::log_enabled($crate::macros::LogLevel::Debug) {
+            eprintln!("{} {}", ::colored::Colorize::yellow("DEBUG:"), format!($($arg)*))
+        }
     };
 }
@@ -1,22 +1,261 @@
 use clap::Parser;
+use server::admin::{self, AdminCommand, AdminReply};
+use server::config::Config;
+use server::error;
+use server::macros::{LogLevel, set_log_level};
 use server::server::{Result, Server};
+use std::path::PathBuf;
 
-#[derive(clap::Parser)]
+#[derive(clap::Parser, Clone)]
 #[command(version, about = "Real time multi-user chat backend", long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(long, short, help = "Path to a TOML configuration file")]
+    config: Option<PathBuf>,
+
     #[arg(
         long,
         short,
-        help = "Network PORT used by the server", 
-        default_value_t = String::from("127.0.0.1:8080")
+        help = "Network PORT used by the server, can be repeated (default: 127.0.0.1:8080)"
+    )]
+    address: Vec<String>,
+
+    #[arg(long, help = "Address listening to WebSocket clients, can be repeated")]
+    ws_address: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Serve a chat page to browsers on the WebSocket addresses"
+    )]
+    web_client: bool,
+
+    #[arg(
+        long,
+        help = "Path of a Unix domain socket to listen on for local clients"
+    )]
+    unix_socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Let Unix socket clients log in as their system user without a password"
+    )]
+    peer_credentials: bool,
+
+    #[arg(
+        long,
+        help = "Name of the server, shown with the messages relayed to linked servers"
+    )]
+    server_name: Option<String>,
+
+    #[arg(long, help = "Address of a server to link to, can be repeated")]
+    link: Vec<String>,
+
+    #[arg(long, help = "Password of the links between servers")]
+    link_password: Option<String>,
+
+    #[arg(
+        long,
+        help = "Unix socket of a broker letting the servers of this machine share rooms"
+    )]
+    broker_socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Unix socket `server admin` manages the running server through"
+    )]
+    admin_socket: Option<PathBuf>,
+
+    #[arg(long, help = "Address serving Prometheus metrics on /metrics")]
+    metrics_address: Option<String>,
+
+    #[arg(long, help = "Maximum number of connected clients")]
+    max_clients: Option<usize>,
+
+    #[arg(long, help = "Maximum number of clients connected from one IP address")]
+    max_clients_per_ip: Option<usize>,
+
+    #[arg(long, help = "Seconds a new connection has to log in")]
+    handshake_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Seconds before disconnecting a client that sends nothing, 0 to disable"
+    )]
+    idle_timeout: Option<u64>,
+
+    #[arg(long, help = "Seconds between two pings sent to each client")]
+    heartbeat_interval: Option<u64>,
+
+    #[arg(long, help = "Seconds a client has to answer a ping")]
+    heartbeat_timeout: Option<u64>,
+
+    #[arg(long, help = "Maximum size in bytes of a single message")]
+    max_message_size: Option<usize>,
+
+    #[arg(long, help = "Number of messages kept in the server history")]
+    history_length: Option<usize>,
+
+    #[arg(long, help = "File the message history is persisted to")]
+    history_file: Option<PathBuf>,
+
+    #[arg(long, help = "File the user accounts are saved to")]
+    accounts_file: Option<PathBuf>,
+
+    #[arg(long, help = "File the banned users and IP addresses are saved to")]
+    bans_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Seconds a login session can be resumed after its last use"
     )]
-    address: String,
+    session_ttl: Option<u64>,
+
+    #[arg(long, help = "Message of the day shown to connecting clients")]
+    motd: Option<String>,
+
+    #[arg(long, value_enum, help = "Most verbose log level printed")]
+    log_level: Option<LogLevel>,
+}
+
+#[derive(clap::Subcommand, Clone)]
+enum Command {
+    /// Manage the running server through its admin socket
+    Admin {
+        #[arg(
+            long,
+            help = "Path of the admin socket (default: admin_socket of the configuration)"
+        )]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+impl Cli {
+    /// Builds the server configuration from the config file, if any, with the
+    /// command line flags taking precedence over the file values.
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if !self.address.is_empty() {
+            config.addresses = self.address;
+        }
+        if !self.ws_address.is_empty() {
+            config.ws_addresses = self.ws_address;
+        }
+        if self.web_client {
+            config.web_client = true;
+        }
+        if let Some(unix_socket) = self.unix_socket {
+            config.unix_socket = Some(unix_socket);
+        }
+        if self.peer_credentials {
+            config.peer_credentials = true;
+        }
+        if let Some(server_name) = self.server_name {
+            config.server_name = server_name;
+        }
+        if !self.link.is_empty() {
+            config.links = self.link;
+        }
+        if let Some(link_password) = self.link_password {
+            config.link_password = Some(link_password);
+        }
+        if let Some(broker_socket) = self.broker_socket {
+            config.broker_socket = Some(broker_socket);
+        }
+        if let Some(admin_socket) = self.admin_socket {
+            config.admin_socket = Some(admin_socket);
+        }
+        if let Some(metrics_address) = self.metrics_address {
+            config.metrics_address = Some(metrics_address);
+        }
+        if let Some(max_clients) = self.max_clients {
+            config.max_clients = max_clients;
+        }
+        if let Some(max_clients_per_ip) = self.max_clients_per_ip {
+            config.max_clients_per_ip = max_clients_per_ip;
+        }
+        if let Some(handshake_timeout) = self.handshake_timeout {
+            config.handshake_timeout = handshake_timeout;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            config.idle_timeout = idle_timeout;
+        }
+        if let Some(heartbeat_interval) = self.heartbeat_interval {
+            config.heartbeat_interval = heartbeat_interval;
+        }
+        if let Some(heartbeat_timeout) = self.heartbeat_timeout {
+            config.heartbeat_timeout = heartbeat_timeout;
+        }
+        if let Some(max_message_size) = self.max_message_size {
+            config.max_message_size = max_message_size;
+        }
+        if let Some(history_length) = self.history_length {
+            config.history_length = history_length;
+        }
+        if let Some(history_file) = self.history_file {
+            config.history_file = Some(history_file);
+        }
+        if let Some(accounts_file) = self.accounts_file {
+            config.accounts_file = Some(accounts_file);
+        }
+        if let Some(bans_file) = self.bans_file {
+            config.bans_file = Some(bans_file);
+        }
+        if let Some(session_ttl) = self.session_ttl {
+            config.session_ttl = session_ttl;
+        }
+        if let Some(motd) = self.motd {
+            config.motd = Some(motd);
+        }
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    let mut server = Server::build(cli.address).await?;
+    if let Some(Command::Admin { socket, command }) = cli.command.clone() {
+        return administrate(cli, socket, command).await;
+    }
+
+    let config = cli.clone().into_config()?;
+    set_log_level(config.log.level);
+    let server = Server::build(config).await?;
+    // reloading reads the config file again, the flags still taking precedence
+    let server = server.reload_with(move || cli.clone().into_config());
     server.run().await?;
     Ok(())
 }
+
+/// Sends `command` to the admin socket of the running server and prints its reply.
+async fn administrate(cli: Cli, socket: Option<PathBuf>, command: AdminCommand) -> Result<()> {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => cli.into_config()?.admin_socket.ok_or_else(|| {
+            error!("No admin socket given: use --socket or set admin_socket in the configuration")
+        })?,
+    };
+    match admin::request(&socket, &command).await? {
+        AdminReply::Done { text } => {
+            println!("{text}");
+            Ok(())
+        }
+        AdminReply::Failed { reason } => {
+            error!("{reason}");
+            Err(())
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use crate::peer::PeerAddr;
+use crate::{debug, error, web};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+/// Counters and gauges of the server, updated where things happen and
+/// exposed in the Prometheus text format.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Most metrics connections served at once, others waiting to be accepted.
+const MAX_CONNECTIONS: usize = 16;
+/// Upper bounds in seconds of the buckets of the broadcast durations.
+const BROADCAST_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Kinds of errors counted by the server.
+#[derive(Clone, Copy)]
+pub enum Failure {
+    /// A client sent a frame that could not be parsed or is reserved to the server.
+    MalformedFrame,
+    /// A client sent a frame larger than allowed.
+    OversizedFrame,
+    /// A client was disconnected for flooding.
+    Flooding,
+    /// A login or registration was refused.
+    LoginFailed,
+    /// A connection was refused: banned address or too many clients.
+    Refused,
+    /// A client was disconnected for not logging in, going idle or not
+    /// answering pings.
+    Timeout,
+    /// Reading from a connection failed.
+    Read,
+    /// Writing to a connection failed.
+    Write,
+}
+
+impl Failure {
+    const ALL: [Failure; 8] = [
+        Failure::MalformedFrame,
+        Failure::OversizedFrame,
+        Failure::Flooding,
+        Failure::LoginFailed,
+        Failure::Refused,
+        Failure::Timeout,
+        Failure::Read,
+        Failure::Write,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Failure::MalformedFrame => "malformed_frame",
+            Failure::OversizedFrame => "oversized_frame",
+            Failure::Flooding => "flooding",
+            Failure::LoginFailed => "login_failed",
+            Failure::Refused => "refused",
+            Failure::Timeout => "timeout",
+            Failure::Read => "read",
+            Failure::Write => "write",
+        }
+    }
+}
+
+pub struct Metrics {
+    connections: AtomicU64,
+    clients: AtomicU64,
+    users: AtomicU64,
+    links: AtomicU64,
+    messages: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    event_queue: AtomicU64,
+    // broadcasts in each bucket, the last one counting the slower ones
+    broadcast_buckets: [AtomicU64; BROADCAST_BUCKETS.len() + 1],
+    broadcast_nanos: AtomicU64,
+    failures: [AtomicU64; Failure::ALL.len()],
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            clients: AtomicU64::new(0),
+            users: AtomicU64::new(0),
+            links: AtomicU64::new(0),
+            messages: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            event_queue: AtomicU64::new(0),
+            broadcast_buckets: [const { AtomicU64::new(0) }; BROADCAST_BUCKETS.len() + 1],
+            broadcast_nanos: AtomicU64::new(0),
+            failures: [const { AtomicU64::new(0) }; Failure::ALL.len()],
+        }
+    }
+
+    /// Counts a connection accepted by a listener.
+    pub fn connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the number of connected clients, logged in users and linked
+    /// servers, and of events waiting for the server loop.
+    pub fn set_state(&self, clients: usize, users: usize, links: usize, event_queue: usize) {
+        self.clients.store(clients as u64, Ordering::Relaxed);
+        self.users.store(users as u64, Ordering::Relaxed);
+        self.links.store(links as u64, Ordering::Relaxed);
+        self.event_queue
+            .store(event_queue as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a message posted in a room.
+    pub fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records how long sending a frame to the members of a room took.
+    pub fn broadcast(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = (BROADCAST_BUCKETS.iter())
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BROADCAST_BUCKETS.len());
+        self.broadcast_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.broadcast_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn failure(&self, kind: Failure) {
+        self.failures[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        };
+        metric(
+            "rschat_connections_total",
+            "counter",
+            "Connections accepted.",
+            load(&self.connections),
+        );
+        metric(
+            "rschat_clients",
+            "gauge",
+            "Clients connected.",
+            load(&self.clients),
+        );
+        metric(
+            "rschat_users",
+            "gauge",
+            "Clients logged in.",
+            load(&self.users),
+        );
+        metric(
+            "rschat_linked_servers",
+            "gauge",
+            "Servers linked to this one.",
+            load(&self.links),
+        );
+        metric(
+            "rschat_messages_total",
+            "counter",
+            "Messages posted in the rooms.",
+            load(&self.messages),
+        );
+        metric(
+            "rschat_received_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+            load(&self.bytes_received),
+        );
+        metric(
+            "rschat_sent_bytes_total",
+            "counter",
+            "Bytes sent to clients.",
+            load(&self.bytes_sent),
+        );
+        metric(
+            "rschat_event_queue_depth",
+            "gauge",
+            "Events waiting for the server loop.",
+            load(&self.event_queue),
+        );
+
+        let name = "rschat_broadcast_duration_seconds";
+        let _ = writeln!(
+            text,
+            "# HELP {name} Time taken to send a frame to the members of a room.\n\
+             # TYPE {name} histogram"
+        );
+        let mut count = 0;
+        for (bound, bucket) in BROADCAST_BUCKETS.iter().zip(&self.broadcast_buckets) {
+            count += load(bucket);
+            let _ = writeln!(text, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        count += load(&self.broadcast_buckets[BROADCAST_BUCKETS.len()]);
+        let sum = load(&self.broadcast_nanos) as f64 / 1e9;
+        let _ = writeln!(
+            text,
+            "{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {sum}\n{name}_count {count}"
+        );
+
+        let name = "rschat_errors_total";
+        let _ = writeln!(text, "# HELP {name} Errors by kind.\n# TYPE {name} counter");
+        for kind in Failure::ALL {
+            let value = load(&self.failures[kind as usize]);
+            let _ = writeln!(text, "{name}{{kind=\"{}\"}} {value}", kind.label());
+        }
+        text
+    }
+}
+
+/// Serves the metrics over HTTP on `/metrics`, to at most
+/// `MAX_CONNECTIONS` connections at a time.
+pub async fn listen(listener: TcpListener) {
+    let slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let Ok(slot) = Arc::clone(&slots).acquire_owned().await else {
+            return;
+        };
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(async move {
+                    serve(stream, PeerAddr::Ip(addr)).await;
+                    drop(slot);
+                });
+            }
+            Err(err) => error!("Failed to accept a metrics connection: {err}"),
+        }
+    }
+}
+
+async fn serve(mut stream: TcpStream, addr: PeerAddr) {
+    let Some((method, path)) = web::read_request(&mut stream).await else {
+        return;
+    };
+    debug!("Client {addr} requested {method} {path}");
+
+    let response = match (method.as_str(), path.as_str()) {
+        ("GET", "/metrics") => web::response(
+            "200 OK",
+            "text/plain; version=0.0.4",
+            &METRICS.render(),
+            false,
+        ),
+        ("GET", _) => web::response("404 Not Found", "text/plain", "Not found\n", false),
+        _ => web::response(
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n",
+            false,
+        ),
+    };
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        error!("Failed to send the metrics to {addr}: {err}");
+        return;
+    }
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,225 @@
+use crate::error;
+use crate::server::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Privileges of a user, assigned in the server configuration. Each role can
+/// moderate the users of the roles below it.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Member,
+    /// Can kick, ban, mute and delete the messages of members.
+    Moderator,
+    /// Can moderate members and moderators.
+    Owner,
+}
+
+/// What the target of a ban is.
+#[derive(Serialize, Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BanKind {
+    /// A user name.
+    User,
+    /// An IP address, refusing every connection coming from it.
+    Ip,
+}
+
+/// A user name or an IP address to ban.
+pub enum BanTarget {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    /// Reads `target` as told by `kind`.
+    ///
+    /// # Returns
+    /// - `Ok(BanTarget)`: If `target` is valid for its kind.
+    /// - `Err(reason)`: If an IP address is malformed, with the reason to
+    ///   report to the client.
+    pub fn new(kind: BanKind, target: &str) -> std::result::Result<Self, String> {
+        match kind {
+            BanKind::User => Ok(Self::User(target.to_string())),
+            BanKind::Ip => target
+                .parse()
+                .map(Self::Ip)
+                .map_err(|_| format!("'{target}' is not an IP address")),
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(user) => write!(f, "user {user}"),
+            Self::Ip(ip) => write!(f, "IP address {ip}"),
+        }
+    }
+}
+
+/// An action a moderator takes against a user or a message.
+pub enum Moderation {
+    /// Disconnects every client of `user`, ending their login sessions.
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    /// Bans a user name or an IP address, and kicks the matching clients.
+    Ban {
+        kind: BanKind,
+        target: String,
+        reason: Option<String>,
+    },
+    /// Lifts the ban of a user name or an IP address.
+    Unban { kind: BanKind, target: String },
+    /// Prevents `user` from posting for `seconds`.
+    Mute { user: String, seconds: u64 },
+    /// Deletes a message. Users can also delete their own messages.
+    Delete { id: u64 },
+}
+
+/// Banned user names and IP addresses. When a bans file is configured they
+/// are kept in it as a JSON object, otherwise they only last until the server
+/// stops.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Bans {
+    // file the bans are saved to
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    users: HashSet<String>,
+    ips: HashSet<IpAddr>,
+}
+
+impl Bans {
+    /// Reads the bans saved in `path`, if given. A missing file holds no bans.
+    ///
+    /// # Returns
+    /// - `Ok(Bans)`: If the file is missing or valid.
+    /// - `Err(())`: If the file is unreadable or malformed, with a message logged.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let mut bans = match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|err| error!("Invalid bans file {}: {err}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Self::default(),
+            Err(err) => {
+                error!("Failed to read bans file {}: {err}", path.display());
+                return Err(());
+            }
+        };
+        bans.path = Some(path.to_path_buf());
+        Ok(bans)
+    }
+
+    pub fn is_user_banned(&self, user: &str) -> bool {
+        self.users.contains(user)
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.ips.contains(&ip)
+    }
+
+    /// Bans `target` and saves the bans file.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the ban was recorded.
+    /// - `Err(reason)`: If the file could not be written, with the reason to
+    ///   report to the client.
+    pub fn ban(&mut self, target: &BanTarget) -> std::result::Result<(), String> {
+        match target {
+            BanTarget::User(user) => self.users.insert(user.clone()),
+            BanTarget::Ip(ip) => self.ips.insert(*ip),
+        };
+        self.save()
+            .map_err(|()| String::from("The ban could not be saved"))
+    }
+
+    /// Lifts the ban of `target` and saves the bans file.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the ban was lifted.
+    /// - `Err(reason)`: If `target` is not banned or the file could not be
+    ///   written, with the reason to report to the client.
+    pub fn unban(&mut self, target: &BanTarget) -> std::result::Result<(), String> {
+        let removed = match target {
+            BanTarget::User(user) => self.users.remove(user),
+            BanTarget::Ip(ip) => self.ips.remove(ip),
+        };
+        if !removed {
+            return Err(format!("The {target} is not banned"));
+        }
+        self.save()
+            .map_err(|()| String::from("The ban could not be saved"))
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|err| error!("Failed to encode bans: {err}"))?;
+        fs::write(path, content)
+            .map_err(|err| error!("Failed to save bans file {}: {err}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_are_ordered() {
+        assert!(Role::Member < Role::Moderator);
+        assert!(Role::Moderator < Role::Owner);
+        assert_eq!(Role::default(), Role::Member);
+        let role: Role = serde_json::from_str("\"moderator\"").unwrap();
+        assert_eq!(role, Role::Moderator);
+    }
+
+    #[test]
+    fn ban_targets() {
+        let target = BanTarget::new(BanKind::User, "10.0.0.1").unwrap();
+        assert_eq!(target.to_string(), "user 10.0.0.1");
+        let target = BanTarget::new(BanKind::Ip, "10.0.0.1").unwrap();
+        assert_eq!(target.to_string(), "IP address 10.0.0.1");
+        let target = BanTarget::new(BanKind::Ip, "::1").unwrap();
+        assert_eq!(target.to_string(), "IP address ::1");
+        assert!(BanTarget::new(BanKind::Ip, "alice").is_err());
+        assert!(BanTarget::new(BanKind::Ip, "10.0.0.256").is_err());
+    }
+
+    #[test]
+    fn bans_are_saved() {
+        let path = std::env::temp_dir().join(format!("rschat-bans-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let user = BanTarget::new(BanKind::User, "alice").unwrap();
+        let ip = BanTarget::new(BanKind::Ip, "10.0.0.1").unwrap();
+
+        let mut bans = Bans::load(Some(&path)).unwrap();
+        bans.ban(&user).unwrap();
+        bans.ban(&ip).unwrap();
+        let mut bans = Bans::load(Some(&path)).unwrap();
+        assert!(bans.is_user_banned("alice"));
+        assert!(!bans.is_user_banned("bob"));
+        assert!(bans.is_ip_banned("10.0.0.1".parse().unwrap()));
+        assert!(!bans.is_ip_banned("10.0.0.2".parse().unwrap()));
+
+        bans.unban(&user).unwrap();
+        assert!(bans.unban(&user).is_err());
+        let bans = Bans::load(Some(&path)).unwrap();
+        assert!(!bans.is_user_banned("alice"));
+        assert!(bans.is_ip_banned("10.0.0.1".parse().unwrap()));
+        fs::remove_file(&path).unwrap();
+    }
+}
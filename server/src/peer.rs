@@ -0,0 +1,662 @@
+use crate::metrics::{Failure, METRICS};
+use crate::protocol::{Frame, FrameReader};
+use crate::server::Result;
+use crate::web::ReadAhead;
+use crate::{debug, error, info};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use nix::unistd::{Uid, User};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::os::fd::AsFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+/// Time a client has to acknowledge the end of the connection before the
+/// socket is closed anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time a single frame can take to be written before the client is
+/// considered gone.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Frames waiting to be written to a client before it is considered too slow
+/// and disconnected.
+const QUEUE_SIZE: usize = 256;
+
+/// Number given to the next Unix socket client.
+static NEXT_UNIX_CLIENT: AtomicU64 = AtomicU64::new(1);
+
+/// Protocol spoken by the clients of a listener.
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    /// Frames as lines of JSON over a raw TCP connection.
+    Tcp,
+    /// Frames as JSON text messages over a WebSocket connection.
+    WebSocket,
+    /// Frames as lines of JSON over a Unix domain socket.
+    Unix,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "TCP"),
+            Self::WebSocket => write!(f, "WebSocket"),
+            Self::Unix => write!(f, "Unix socket"),
+        }
+    }
+}
+
+/// Where a client connected from. Unix socket clients have no address, so
+/// each one is numbered instead.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Unix(u64),
+}
+
+impl PeerAddr {
+    /// IP address of the client, unless it connected through a Unix socket.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Ip(addr) => Some(addr.ip()),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{addr}"),
+            Self::Unix(id) => write!(f, "unix#{id}"),
+        }
+    }
+}
+
+/// Socket accepting the connections of clients.
+pub enum Listener {
+    Tcp(TcpListener, Protocol),
+    Unix(UnixListener),
+}
+
+/// Connection just accepted by a listener.
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// Connection to a WebSocket listener whose request head was read ahead.
+    ReadAhead(ReadAhead),
+}
+
+impl Listener {
+    /// Listens to `protocol` clients on a TCP address.
+    ///
+    /// # Returns
+    /// - `Ok(Listener)`: If the address could be bound.
+    /// - `Err(())`: If the bind failed, with a message logged.
+    pub async fn tcp(addr: &str, protocol: Protocol) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|err| error!("Could not bind server to {addr}: {err}"))?;
+        Ok(Self::Tcp(listener, protocol))
+    }
+
+    /// Listens to clients on a Unix domain socket, which the local users
+    /// allowed by `mode` can connect to. The socket file left by a previous
+    /// run is replaced, unless another server still answers on it.
+    ///
+    /// # Returns
+    /// - `Ok(Listener)`: If the path could be bound.
+    /// - `Err(())`: If the bind failed or the path is in use, with a message logged.
+    pub fn unix(path: &Path, mode: u32) -> Result<Self> {
+        let stale = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+        if stale {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                error!(
+                    "Could not bind server to {}: already in use",
+                    path.display()
+                );
+                return Err(());
+            }
+            fs::remove_file(path)
+                .map_err(|err| error!("Failed to remove stale socket {}: {err}", path.display()))?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .map_err(|err| error!("Could not bind server to {}: {err}", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|err| error!("Failed to set permissions of {}: {err}", path.display()))?;
+        Ok(Self::Unix(listener))
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Self::Tcp(_, protocol) => *protocol,
+            Self::Unix(_) => Protocol::Unix,
+        }
+    }
+
+    /// Address or path the listener is bound to, for logging.
+    pub fn local_addr(&self) -> Result<String> {
+        let addr = match self {
+            Self::Tcp(listener, _) => listener.local_addr().map(|addr| addr.to_string()),
+            Self::Unix(listener) => listener.local_addr().map(|addr| match addr.as_pathname() {
+                Some(path) => path.display().to_string(),
+                None => String::from("unnamed socket"),
+            }),
+        };
+        addr.map_err(|err| error!("Failed to get listener address: {err}"))
+    }
+
+    pub async fn accept(&self) -> io::Result<(Socket, PeerAddr)> {
+        match self {
+            Self::Tcp(listener, _) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Socket::Tcp(stream), PeerAddr::Ip(addr)))
+            }
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let id = NEXT_UNIX_CLIENT.fetch_add(1, Ordering::Relaxed);
+                Ok((Socket::Unix(stream), PeerAddr::Unix(id)))
+            }
+        }
+    }
+}
+
+/// A connected client, reached over TCP, WebSocket or a Unix socket, along
+/// with where it connected from.
+pub struct Peer {
+    pub addr: PeerAddr,
+    /// System user running a Unix socket client, known when peer credentials
+    /// are trusted. The client can log in as this user without a password.
+    pub system_user: Option<String>,
+    /// Name of the server at the other end, once the connection is a link
+    /// between two servers rather than a client.
+    pub link: OnceLock<String>,
+    transport: Transport,
+}
+
+/// How frames reach a client. Each connection has a task writing to its
+/// socket, so that sending never waits on a slow or half-open client.
+enum Transport {
+    /// Frames are queued as lines of JSON for the task writing the socket.
+    Tcp(Arc<TcpStream>, Sender<Outgoing>),
+    /// Frames are queued as lines of JSON for the task writing the socket.
+    Unix(Arc<UnixStream>, Sender<Outgoing>),
+    /// Frames are queued as text messages for the task writing the socket,
+    /// which gives up when notified.
+    WebSocket(Sender<Message>, Arc<Notify>),
+}
+
+/// What the task writing a TCP or Unix socket is asked to do.
+enum Outgoing {
+    /// Write an encoded frame.
+    Frame(Vec<u8>),
+    /// Shut the socket down once the frames before are written.
+    Shutdown(Shutdown),
+}
+
+/// Receiving half of a connection, read by the client task only.
+pub enum Incoming {
+    /// Data is read from the socket of the peer.
+    Socket,
+    /// Each text message holds one frame.
+    WebSocket(SplitStream<WebSocketStream<ReadAhead>>),
+}
+
+/// Sockets frames are read from and written to directly.
+trait Stream: AsFd {
+    async fn readable(&self) -> io::Result<()>;
+    fn try_read(&self, buffer: &mut [u8]) -> io::Result<usize>;
+    // written from a task of its own
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send;
+    fn try_write(&self, bytes: &[u8]) -> io::Result<usize>;
+}
+
+macro_rules! impl_stream {
+    ($($stream:ty),*) => {
+        $(impl Stream for $stream {
+            async fn readable(&self) -> io::Result<()> {
+                <$stream>::readable(self).await
+            }
+            fn try_read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+                <$stream>::try_read(self, buffer)
+            }
+            async fn writable(&self) -> io::Result<()> {
+                <$stream>::writable(self).await
+            }
+            fn try_write(&self, bytes: &[u8]) -> io::Result<usize> {
+                <$stream>::try_write(self, bytes)
+            }
+        })*
+    };
+}
+
+impl_stream!(TcpStream, UnixStream);
+
+impl Peer {
+    /// Sets up a connection accepted on a listener of `protocol`. WebSocket
+    /// clients must complete their handshake within `timeout` and cannot send
+    /// messages larger than `max_message_size`, and Unix socket clients get
+    /// their system user when `peer_credentials` is set.
+    ///
+    /// # Returns
+    /// - `Ok((Peer, Incoming))`: If the connection is ready to carry frames.
+    /// - `Err(())`: If the handshake failed, with a message logged.
+    pub async fn accept(
+        socket: Socket,
+        protocol: Protocol,
+        addr: PeerAddr,
+        timeout: Duration,
+        max_message_size: usize,
+        peer_credentials: bool,
+    ) -> Result<(Self, Incoming)> {
+        let peer = |transport| Self {
+            addr,
+            system_user: None,
+            link: OnceLock::new(),
+            transport,
+        };
+        let socket = match (socket, protocol) {
+            (Socket::Tcp(stream), Protocol::WebSocket) => Socket::ReadAhead(ReadAhead::new(stream)),
+            (socket, _) => socket,
+        };
+        match socket {
+            Socket::ReadAhead(stream) => {
+                let handshake = Self::websocket(addr, stream, max_message_size);
+                let handshake = tokio::time::timeout(timeout, handshake);
+                let Ok(result) = handshake.await else {
+                    info!("Client {addr} did not complete the WebSocket handshake in time");
+                    return Err(());
+                };
+                let (sender, abort, incoming) = result?;
+                Ok((peer(Transport::WebSocket(sender, abort)), incoming))
+            }
+            Socket::Tcp(stream) => {
+                let (stream, sender) = Self::writer(addr, stream);
+                Ok((peer(Transport::Tcp(stream, sender)), Incoming::Socket))
+            }
+            Socket::Unix(stream) => {
+                let system_user = if peer_credentials {
+                    Self::system_user(&stream, addr)
+                } else {
+                    None
+                };
+                let (stream, sender) = Self::writer(addr, stream);
+                let peer = Self {
+                    system_user,
+                    ..peer(Transport::Unix(stream, sender))
+                };
+                Ok((peer, Incoming::Socket))
+            }
+        }
+    }
+
+    /// Name of the system user running the process at the other end of a
+    /// Unix socket, as told by the kernel.
+    fn system_user(stream: &UnixStream, addr: PeerAddr) -> Option<String> {
+        let uid = stream
+            .peer_cred()
+            .map_err(|err| error!("Failed to get the credentials of client {addr}: {err}"))
+            .ok()?
+            .uid();
+        let user = User::from_uid(Uid::from_raw(uid))
+            .map_err(|err| error!("Failed to look up user {uid}: {err}"))
+            .ok()?;
+        let name = user.map(|user| user.name);
+        if let Some(name) = &name {
+            debug!("Client {addr} is run by system user {name}");
+        }
+        name
+    }
+
+    /// Performs the WebSocket handshake and spawns the task writing to the
+    /// connection.
+    async fn websocket(
+        addr: PeerAddr,
+        stream: ReadAhead,
+        max_message_size: usize,
+    ) -> Result<(Sender<Message>, Arc<Notify>, Incoming)> {
+        // a message holds a single frame
+        let config = WebSocketConfig::default()
+            .max_message_size(Some(max_message_size))
+            .max_frame_size(Some(max_message_size));
+        let websocket = tokio_tungstenite::accept_async_with_config(stream, Some(config))
+            .await
+            .map_err(|err| info!("WebSocket handshake with {addr} failed: {err}"))?;
+        let (sink, stream) = websocket.split();
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let abort = Arc::new(Notify::new());
+        let writer = Self::write_messages(addr, sink, receiver);
+        let aborted = Arc::clone(&abort);
+        tokio::spawn(async move {
+            tokio::select! {
+                () = writer => {}
+                () = aborted.notified() => {}
+            }
+        });
+        Ok((sender, abort, Incoming::WebSocket(stream)))
+    }
+
+    /// Writes the messages queued for a WebSocket client until the
+    /// connection is closed. Once this task ends, the client task stops
+    /// reading too.
+    async fn write_messages(
+        addr: PeerAddr,
+        mut sink: SplitSink<WebSocketStream<ReadAhead>, Message>,
+        mut messages: Receiver<Message>,
+    ) {
+        while let Some(message) = messages.recv().await {
+            let close = matches!(message, Message::Close(_));
+            match tokio::time::timeout(WRITE_TIMEOUT, sink.send(message)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    debug!("Failed to send message to {addr}: {err}");
+                    break;
+                }
+                Err(_) => {
+                    error!("Timed out sending message to {addr}");
+                    METRICS.failure(Failure::Write);
+                    break;
+                }
+            }
+            if close {
+                break;
+            }
+        }
+    }
+
+    /// Spawns the task writing the frames queued for a TCP or Unix socket
+    /// client, sharing the socket with the client task reading it.
+    fn writer<S: Stream + Send + Sync + 'static>(
+        addr: PeerAddr,
+        stream: S,
+    ) -> (Arc<S>, Sender<Outgoing>) {
+        let stream = Arc::new(stream);
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write_frames(addr, Arc::clone(&stream), receiver));
+        (stream, sender)
+    }
+
+    /// Queues a frame for the client, without waiting for it to be written.
+    ///
+    /// Failures are logged, the client task notices broken connections by
+    /// itself. A client whose queue is full does not read what it is sent,
+    /// and its connection is dropped.
+    pub async fn send(&self, frame: &Frame) {
+        let full = match &self.transport {
+            Transport::Tcp(_, sender) | Transport::Unix(_, sender) => matches!(
+                sender.try_send(Outgoing::Frame(frame.encode())),
+                Err(TrySendError::Full(_))
+            ),
+            Transport::WebSocket(sender, _) => {
+                let text = serde_json::to_string(frame).expect("frames are always serializable");
+                METRICS.sent(text.len());
+                // fails once the connection is closed, which the client task handles
+                matches!(
+                    sender.try_send(Message::text(text)),
+                    Err(TrySendError::Full(_))
+                )
+            }
+        };
+        if full {
+            error!("Client {} does not read its frames, dropping it", self.addr);
+            METRICS.failure(Failure::Write);
+            self.abort();
+        }
+    }
+
+    /// Closes the connection once the frames queued are written. The client
+    /// task notices it and reports the disconnection.
+    pub fn close(&self) {
+        self.shutdown(Shutdown::Both);
+    }
+
+    /// Shuts the socket down once the frames queued are written, or right
+    /// away when the queue is full. WebSocket connections are closed
+    /// whatever `how` is.
+    fn shutdown(&self, how: Shutdown) {
+        match &self.transport {
+            Transport::Tcp(_, sender) | Transport::Unix(_, sender) => {
+                if sender.try_send(Outgoing::Shutdown(how)).is_err() {
+                    self.abort();
+                }
+            }
+            Transport::WebSocket(sender, _) => {
+                let frame = CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "".into(),
+                };
+                if sender.try_send(Message::Close(Some(frame))).is_err() {
+                    self.abort();
+                }
+            }
+        }
+    }
+
+    /// Drops the connection without writing what is still queued.
+    fn abort(&self) {
+        match &self.transport {
+            Transport::Tcp(stream, _) => shutdown(&**stream, Shutdown::Both),
+            Transport::Unix(stream, _) => shutdown(&**stream, Shutdown::Both),
+            Transport::WebSocket(_, abort) => abort.notify_one(),
+        }
+    }
+
+    /// Tells the client why its connection is being closed, from the task
+    /// that reads it. Whatever the client sends meanwhile is read and
+    /// discarded for a moment, as closing a socket with unread data resets
+    /// the connection and could lose the reason.
+    pub async fn refuse(&self, incoming: &mut Incoming, reason: &str) {
+        let frame = Frame::Disconnected {
+            reason: reason.to_string(),
+        };
+        self.send(&frame).await;
+
+        match &self.transport {
+            Transport::Tcp(stream, _) => {
+                self.shutdown(Shutdown::Write);
+                drain(&**stream).await;
+            }
+            Transport::Unix(stream, _) => {
+                self.shutdown(Shutdown::Write);
+                drain(&**stream).await;
+            }
+            Transport::WebSocket(..) => incoming.finish(self).await,
+        }
+    }
+}
+
+impl Incoming {
+    /// Waits for data from the client and appends it to `reader`.
+    ///
+    /// # Returns
+    /// - `Ok(true)`: If data was received.
+    /// - `Ok(false)`: If the connection was closed.
+    /// - `Err(())`: If the connection failed, with a message logged.
+    pub async fn receive(
+        &mut self,
+        peer: &Peer,
+        buffer: &mut [u8],
+        reader: &mut FrameReader,
+    ) -> Result<bool> {
+        let addr = peer.addr;
+        match (self, &peer.transport) {
+            (Self::Socket, Transport::Tcp(stream, _)) => {
+                read(&**stream, addr, buffer, reader).await
+            }
+            (Self::Socket, Transport::Unix(stream, _)) => {
+                read(&**stream, addr, buffer, reader).await
+            }
+            (Self::WebSocket(stream), Transport::WebSocket(sender, _)) => loop {
+                let message = tokio::select! {
+                    message = stream.next() => message,
+                    // the writing task ended, the connection is being closed
+                    () = sender.closed() => return Ok(false),
+                };
+
+                match message {
+                    Some(Ok(Message::Text(text))) => {
+                        METRICS.received(text.len());
+                        reader.push(text.as_bytes());
+                    }
+                    Some(Ok(Message::Binary(bytes))) => {
+                        METRICS.received(bytes.len());
+                        reader.push(&bytes);
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(false),
+                    // pings are answered by the WebSocket library
+                    Some(Ok(_)) => continue,
+                    Some(Err(WsError::Capacity(err))) => {
+                        error!("Client {addr} sent a message too large: {err}");
+                        METRICS.failure(Failure::OversizedFrame);
+                        return Err(());
+                    }
+                    Some(Err(err)) => {
+                        error!("Failed to read from client {addr}: {err}");
+                        METRICS.failure(Failure::Read);
+                        return Err(());
+                    }
+                }
+                reader.push(b"\n");
+                return Ok(true);
+            },
+            _ => unreachable!("the incoming half always matches the transport of its peer"),
+        }
+    }
+
+    /// Waits for a WebSocket client to acknowledge the end of the
+    /// connection, for a moment at most, so the socket is not reset while
+    /// the client still reads what was last sent to it.
+    pub async fn finish(&mut self, peer: &Peer) {
+        let Self::WebSocket(stream) = self else {
+            return;
+        };
+
+        peer.close();
+        let drain = async { while let Some(Ok(_)) = stream.next().await {} };
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, drain).await;
+    }
+}
+
+async fn read<S: Stream>(
+    stream: &S,
+    addr: PeerAddr,
+    buffer: &mut [u8],
+    reader: &mut FrameReader,
+) -> Result<bool> {
+    loop {
+        if let Err(err) = stream.readable().await {
+            error!("Failed waiting for socket to become readable {addr}: {err}");
+            METRICS.failure(Failure::Read);
+            return Err(());
+        }
+
+        match stream.try_read(buffer) {
+            Ok(0) => return Ok(false),
+            Ok(n) => {
+                METRICS.received(n);
+                reader.push(&buffer[..n]);
+                return Ok(true);
+            }
+            // Tries to read from socket again
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+            Err(err) => {
+                error!("Failed to read from client {addr}: {err}");
+                METRICS.failure(Failure::Read);
+                return Err(());
+            }
+        }
+    }
+}
+
+/// Writes the frames queued for a TCP or Unix socket client until the
+/// socket is shut down. A write that fails or takes longer than
+/// `WRITE_TIMEOUT` shuts the socket down, so the client task stops too.
+async fn write_frames<S: Stream>(addr: PeerAddr, stream: Arc<S>, mut queue: Receiver<Outgoing>) {
+    while let Some(outgoing) = queue.recv().await {
+        let bytes = match outgoing {
+            Outgoing::Frame(bytes) => bytes,
+            Outgoing::Shutdown(how) => {
+                shutdown(&*stream, how);
+                return;
+            }
+        };
+        match tokio::time::timeout(WRITE_TIMEOUT, write(&*stream, addr, &bytes)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(())) => break,
+            Err(_) => {
+                error!("Timed out sending frame to {addr}");
+                METRICS.failure(Failure::Write);
+                break;
+            }
+        }
+    }
+    shutdown(&*stream, Shutdown::Both);
+}
+
+async fn write<S: Stream>(stream: &S, addr: PeerAddr, bytes: &[u8]) -> Result<()> {
+    let mut written = 0;
+
+    while written < bytes.len() {
+        if let Err(err) = stream.writable().await {
+            error!("Failed waiting for socket to become available: {err}");
+            METRICS.failure(Failure::Write);
+            return Err(());
+        }
+
+        match stream.try_write(&bytes[written..]) {
+            Ok(n) => {
+                METRICS.sent(n);
+                written += n;
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+            Err(err) => {
+                error!("Failed to send frame to {addr}: {err}");
+                METRICS.failure(Failure::Write);
+                return Err(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Shuts a socket down. The client may already be gone, so failures are
+/// only worth a debug message.
+fn shutdown<S: Stream>(stream: &S, how: Shutdown) {
+    if let Err(err) = socket2::SockRef::from(stream).shutdown(how) {
+        debug!("Failed to shut down connection: {err}");
+    }
+}
+
+/// Discards what the client sends until it closes its side, for a moment at
+/// most, once writing to the socket was stopped.
+async fn drain<S: Stream>(stream: &S) {
+    let mut buffer = [0u8; 1024];
+    let drain = async {
+        loop {
+            if stream.readable().await.is_err() {
+                break;
+            }
+            match stream.try_read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => break,
+            }
+        }
+    };
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, drain).await;
+}
@@ -0,0 +1,278 @@
+use crate::event::{Message, Reaction};
+use crate::federation::Relay;
+use crate::history::HistoryQuery;
+use crate::moderation::BanKind;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Frames exchanged between clients and the server. Each frame is sent as a
+/// single line of JSON, tagged by its `type` field.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    /// Greets a client as soon as it connects, before it logs in.
+    Welcome(Welcome),
+    /// Creates an account and logs the client in with it.
+    Register { username: String, password: String },
+    /// Logs the client in. Clients must log in before anything but
+    /// registering or logging in.
+    Login { username: String, password: String },
+    /// Logs the client in with the token of a previous login, bringing it
+    /// back to the room it was in.
+    Resume { token: String },
+    /// Tells the client it is logged in as `username`. The token lets it log
+    /// in again with `Resume` until it expires or the client logs out.
+    LoggedIn { username: String, token: String },
+    /// Logs the client out, revoking its token.
+    Logout,
+    /// A chat message. Sent by clients, and broadcast by the server to the
+    /// members of the room it was posted in.
+    Message(Message),
+    /// Asks the server to move the client to another room.
+    Join { room: String },
+    /// Tells the client which room it is in.
+    Joined { room: String },
+    /// Rooms available on the server.
+    Rooms { rooms: Vec<String> },
+    /// A reaction to a message. Sent by clients, and broadcast by the server to
+    /// the members of the room the message was posted in.
+    React(Reaction),
+    /// Asks the server to search its history.
+    Search(HistoryQuery),
+    /// Messages matching a search, newest first. `next` is the `before_id` to
+    /// search with to get the following page, when there is one.
+    SearchResults {
+        messages: Vec<Message>,
+        next: Option<u64>,
+    },
+    /// Asks the server for the messages of `room` newer than `after_id`, the
+    /// newest message the client already has.
+    Sync { room: String, after_id: u64 },
+    /// Answer to `Sync`: the newest messages of `room` the client is missing,
+    /// oldest first. `reset` tells that the client must forget the messages
+    /// it has of the room: they do not come from this history, for instance
+    /// after the server lost it, or more messages were missed than are sent.
+    History {
+        room: String,
+        messages: Vec<Message>,
+        reset: bool,
+    },
+    /// Asks the server to disconnect `user`. Reserved to moderators.
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    /// Asks the server to ban a user name or an IP address, as told by `kind`,
+    /// disconnecting the matching clients. Reserved to moderators, and to
+    /// owners for IP addresses.
+    Ban {
+        kind: BanKind,
+        target: String,
+        reason: Option<String>,
+    },
+    /// Asks the server to lift the ban of a user name or an IP address.
+    Unban { kind: BanKind, target: String },
+    /// Asks the server to prevent `user` from posting for `seconds`, `0`
+    /// lifting a previous mute. Reserved to moderators.
+    Mute { user: String, seconds: u64 },
+    /// Asks the server to delete a message. Users can delete their own
+    /// messages, moderators the ones of other users.
+    Delete { id: u64 },
+    /// Tells the members of a room that a message was deleted.
+    Deleted { id: u64 },
+    /// Tells the client the server is closing its connection, and why: a
+    /// moderator kicked it, it was idle for too long, the server is full...
+    Disconnected { reason: String },
+    /// Information from the server, such as the result of a moderation action.
+    Notice { text: String },
+    /// Checks that the other side is still there. Sent both by clients and
+    /// the server, and answered with a `Pong` carrying the same `nonce`.
+    Ping { nonce: u64 },
+    /// Answer to a `Ping`.
+    Pong { nonce: u64 },
+    /// Tells the client it is sending too fast and its frames are dropped.
+    Warning { reason: String },
+    /// A request the server could not fulfill.
+    Error { reason: String },
+    /// Sent instead of logging in by a server linking to another one, named
+    /// `server` and knowing the link password of the other server.
+    Link { server: String, password: String },
+    /// Tells a server its link is accepted by the server named `server`.
+    Linked { server: String },
+    /// A message passed between linked servers.
+    Relay(Relay),
+}
+
+/// What the server tells a client about itself when it connects.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Welcome {
+    /// Name of the server.
+    pub server: String,
+    /// Version of the server.
+    pub version: String,
+    /// Message of the day, when the server has one.
+    pub motd: Option<String>,
+    /// Rooms available on the server.
+    pub rooms: Vec<String>,
+    /// Number of users logged in.
+    pub users: usize,
+}
+
+impl Frame {
+    /// Encodes the frame as a line of JSON.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = serde_json::to_vec(self).expect("frames are always serializable");
+        bytes.push(b'\n');
+        bytes
+    }
+
+    /// A ping with a new nonce, the current time in milliseconds, returned
+    /// along with it.
+    pub fn ping() -> (Self, u64) {
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        (Self::Ping { nonce }, nonce)
+    }
+
+    /// Decodes a line of JSON received without its line break.
+    pub fn decode(line: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(line)
+    }
+
+    /// Whether the frame counts against the rate limits of a client. Chat
+    /// and moderation traffic does, but not pings and pongs, so that a
+    /// flooding client still notices when the server is gone and answers
+    /// the heartbeat. Logins have limits of their own.
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(
+            self,
+            Self::Message(_)
+                | Self::React(_)
+                | Self::Join { .. }
+                | Self::Search(_)
+                | Self::Sync { .. }
+                | Self::Kick { .. }
+                | Self::Ban { .. }
+                | Self::Unban { .. }
+                | Self::Mute { .. }
+                | Self::Delete { .. }
+        )
+    }
+
+    /// Whether the frame tries a password or a token, counting against the
+    /// login attempts of a client.
+    pub fn is_login(&self) -> bool {
+        matches!(
+            self,
+            Self::Register { .. } | Self::Login { .. } | Self::Resume { .. } | Self::Link { .. }
+        )
+    }
+}
+
+/// Splits a byte stream into frames.
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Appends bytes read from the socket.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete frame, if a whole line has been received.
+    ///
+    /// # Returns
+    /// - `Some(Ok(Frame))`: If a complete line holds a valid frame.
+    /// - `Some(Err(err))`: If a complete line could not be decoded.
+    /// - `None`: If more bytes are needed.
+    pub fn next_frame(&mut self) -> Option<serde_json::Result<Frame>> {
+        self.next_line().map(|line| Frame::decode(&line))
+    }
+
+    /// Returns the next complete line, without its line break and undecoded.
+    pub fn next_line(&mut self) -> Option<Vec<u8>> {
+        let end = self.buffer.iter().position(|&byte| byte == b'\n')?;
+        let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+        line.pop();
+        Some(line)
+    }
+
+    /// Number of bytes received that are not yet part of a complete frame.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_split_across_reads() {
+        let encoded = Frame::Join {
+            room: String::from("general"),
+        }
+        .encode();
+        let (start, end) = encoded.split_at(5);
+
+        let mut reader = FrameReader::default();
+        reader.push(start);
+        assert!(reader.next_frame().is_none());
+        assert_eq!(reader.pending(), 5);
+
+        reader.push(end);
+        assert!(matches!(
+            reader.next_frame(),
+            Some(Ok(Frame::Join { room })) if room == "general"
+        ));
+        assert!(reader.next_frame().is_none());
+        assert_eq!(reader.pending(), 0);
+    }
+
+    #[test]
+    fn frames_read_together() {
+        let mut bytes = Frame::Logout.encode();
+        bytes.extend(
+            Frame::Sync {
+                room: String::from("random"),
+                after_id: 7,
+            }
+            .encode(),
+        );
+        bytes.extend(b"{\"type\":");
+
+        let mut reader = FrameReader::default();
+        reader.push(&bytes);
+        assert!(matches!(reader.next_frame(), Some(Ok(Frame::Logout))));
+        assert!(matches!(
+            reader.next_frame(),
+            Some(Ok(Frame::Sync { room, after_id: 7 })) if room == "random"
+        ));
+        assert!(reader.next_frame().is_none());
+        assert_eq!(reader.pending(), 8);
+    }
+
+    #[test]
+    fn malformed_lines_do_not_stop_the_reader() {
+        let mut reader = FrameReader::default();
+        reader.push(b"not json\n{\"type\":\"unknown\"}\n");
+        reader.push(&Frame::Logout.encode());
+        assert!(matches!(reader.next_frame(), Some(Err(_))));
+        assert!(matches!(reader.next_frame(), Some(Err(_))));
+        assert!(matches!(reader.next_frame(), Some(Ok(Frame::Logout))));
+    }
+
+    #[test]
+    fn lines_are_returned_without_their_break() {
+        let mut reader = FrameReader::default();
+        reader.push(b"first\n\nsecond");
+        assert_eq!(reader.next_line().as_deref(), Some(&b"first"[..]));
+        assert_eq!(reader.next_line().as_deref(), Some(&b""[..]));
+        assert_eq!(reader.next_line(), None);
+        assert_eq!(reader.pending(), 6);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::config::RateLimitConfig;
+use std::time::{Duration, Instant};
+
+/// Time after which a client that stopped flooding has its strikes forgiven.
+const STRIKE_MEMORY: Duration = Duration::from_secs(60);
+
+/// A token bucket: holds up to `capacity` tokens, refilled at `rate` tokens
+/// per second.
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    // when the tokens were last refilled
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Whether the bucket holds `amount` tokens, once refilled.
+    fn holds(&mut self, amount: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        self.tokens >= amount
+    }
+
+    /// Takes `amount` tokens, which the bucket must hold.
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+/// What to do with a frame received from a client.
+pub enum Verdict {
+    /// Forward the frame.
+    Allow,
+    /// Drop the frame silently.
+    Drop,
+    /// Drop the frame and warn the client with the given reason.
+    Warn(String),
+    /// Disconnect the client for the given reason.
+    Disconnect(String),
+}
+
+/// Flood protection of a single connection. Frames beyond the configured
+/// rates are dropped; every burst of dropped frames is a strike, and enough
+/// strikes get the connection muted for a while, then disconnected. Login
+/// attempts have a bucket of their own, and refused ones are strikes too.
+pub struct RateLimiter {
+    frames: TokenBucket,
+    bytes: TokenBucket,
+    logins: TokenBucket,
+    // bursts of dropped frames since the last time strikes were forgiven
+    strikes: u32,
+    // when the last strike happened
+    last_strike: Option<Instant>,
+    // whether the last frame was dropped, so the current burst is already a strike
+    flooding: bool,
+    // when the mute of the connection ends
+    muted_until: Option<Instant>,
+    config: RateLimitConfig,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            frames: TokenBucket::new(config.messages_per_sec, config.message_burst as f64),
+            bytes: TokenBucket::new(config.bytes_per_sec as f64, config.byte_burst as f64),
+            logins: TokenBucket::new(config.logins_per_minute / 60.0, config.login_burst as f64),
+            strikes: 0,
+            last_strike: None,
+            flooding: false,
+            muted_until: None,
+            config: config.clone(),
+        }
+    }
+
+    /// Checks a frame of `size` bytes against the limits of the connection.
+    pub fn check(&mut self, size: usize) -> Verdict {
+        let now = Instant::now();
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Verdict::Drop;
+            }
+            self.muted_until = None;
+            self.flooding = false;
+        }
+
+        // a frame is only counted when both buckets let it through
+        let size = size as f64;
+        if self.frames.holds(1.0) && self.bytes.holds(size) {
+            self.frames.take(1.0);
+            self.bytes.take(size);
+            self.flooding = false;
+            return Verdict::Allow;
+        }
+        if self.flooding {
+            return Verdict::Drop;
+        }
+
+        self.flooding = true;
+        self.strike(now, "You are sending too fast, some messages were dropped")
+    }
+
+    /// Checks a login attempt against the limits of the connection. Muted
+    /// connections can still log in.
+    pub fn check_login(&mut self) -> Verdict {
+        if self.logins.holds(1.0) {
+            self.logins.take(1.0);
+            return Verdict::Allow;
+        }
+        self.strike(Instant::now(), "Too many login attempts, try again later")
+    }
+
+    /// Records a strike, warning the client with `warning` unless it gets
+    /// muted or disconnected.
+    fn strike(&mut self, now: Instant, warning: &str) -> Verdict {
+        if self
+            .last_strike
+            .is_some_and(|last| now.duration_since(last) > STRIKE_MEMORY)
+        {
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+        self.last_strike = Some(now);
+
+        let config = &self.config;
+        if config.disconnect_after > 0 && self.strikes >= config.disconnect_after {
+            Verdict::Disconnect(String::from("Disconnected for flooding"))
+        } else if config.mute_after > 0 && self.strikes >= config.mute_after {
+            self.muted_until = Some(now + Duration::from_secs(config.mute_seconds));
+            Verdict::Warn(format!(
+                "You are sending too fast, muted for {} seconds",
+                config.mute_seconds
+            ))
+        } else {
+            Verdict::Warn(warning.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Limits whose buckets never refill, so that tests do not depend on time.
+    fn config(message_burst: u32, byte_burst: u64) -> RateLimitConfig {
+        RateLimitConfig {
+            messages_per_sec: 0.0,
+            message_burst,
+            bytes_per_sec: 0,
+            byte_burst,
+            logins_per_minute: 0.0,
+            login_burst: 2,
+            mute_after: 0,
+            mute_seconds: 60,
+            disconnect_after: 0,
+        }
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        assert!(bucket.holds(2.0));
+        bucket.take(2.0);
+        assert!(!bucket.holds(1.0));
+
+        bucket.updated -= Duration::from_millis(150);
+        assert!(bucket.holds(1.0));
+        bucket.updated -= Duration::from_secs(10);
+        assert!(bucket.holds(2.0));
+        assert!(!bucket.holds(2.5));
+    }
+
+    #[test]
+    fn frames_beyond_the_burst_are_dropped() {
+        let mut limiter = RateLimiter::new(&config(2, 1000));
+        assert!(matches!(limiter.check(10), Verdict::Allow));
+        assert!(matches!(limiter.check(10), Verdict::Allow));
+        assert!(matches!(limiter.check(10), Verdict::Warn(_)));
+        // the rest of the burst is part of the same strike
+        assert!(matches!(limiter.check(10), Verdict::Drop));
+        assert_eq!(limiter.strikes, 1);
+    }
+
+    #[test]
+    fn frames_too_large_do_not_spend_frame_tokens() {
+        let mut limiter = RateLimiter::new(&config(2, 100));
+        assert!(matches!(limiter.check(500), Verdict::Warn(_)));
+        assert!(matches!(limiter.check(50), Verdict::Allow));
+        assert!(matches!(limiter.check(50), Verdict::Allow));
+        assert!(matches!(limiter.check(0), Verdict::Warn(_)));
+    }
+
+    #[test]
+    fn login_attempts_have_their_own_bucket() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            disconnect_after: 2,
+            ..config(1, 100)
+        });
+        assert!(matches!(limiter.check(10), Verdict::Allow));
+        assert!(matches!(limiter.check_login(), Verdict::Allow));
+        assert!(matches!(limiter.check_login(), Verdict::Allow));
+        match limiter.check_login() {
+            Verdict::Warn(reason) => assert!(reason.contains("login attempts")),
+            _ => panic!("logins beyond the burst should be refused"),
+        }
+        assert!(matches!(limiter.check_login(), Verdict::Disconnect(_)));
+    }
+
+    #[test]
+    fn muted_connections_can_log_in() {
+        let mut limiter = RateLimiter::new(&config(1, 100));
+        limiter.muted_until = Some(Instant::now() + Duration::from_secs(60));
+        assert!(matches!(limiter.check(1), Verdict::Drop));
+        assert!(matches!(limiter.check_login(), Verdict::Allow));
+    }
+
+    #[test]
+    fn strikes_mute_the_connection() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            mute_after: 2,
+            ..config(10, 100)
+        });
+        assert!(matches!(limiter.check(500), Verdict::Warn(_)));
+        assert!(matches!(limiter.check(1), Verdict::Allow));
+        match limiter.check(500) {
+            Verdict::Warn(reason) => assert!(reason.contains("muted for 60 seconds")),
+            _ => panic!("the second strike should mute"),
+        }
+        // frames that fit the limits are dropped too while muted
+        assert!(matches!(limiter.check(1), Verdict::Drop));
+
+        limiter.muted_until = Some(Instant::now());
+        assert!(matches!(limiter.check(1), Verdict::Allow));
+    }
+
+    #[test]
+    fn strikes_disconnect_the_connection() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            mute_after: 2,
+            disconnect_after: 3,
+            ..config(10, 100)
+        });
+        assert!(matches!(limiter.check(500), Verdict::Warn(_)));
+        assert!(matches!(limiter.check(1), Verdict::Allow));
+        assert!(matches!(limiter.check(500), Verdict::Warn(_)));
+        limiter.muted_until = Some(Instant::now());
+        assert!(matches!(limiter.check(500), Verdict::Disconnect(_)));
+    }
+
+    #[test]
+    fn strikes_are_forgiven_after_a_while() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            disconnect_after: 2,
+            ..config(10, 100)
+        });
+        assert!(matches!(limiter.check(500), Verdict::Warn(_)));
+        assert!(matches!(limiter.check(1), Verdict::Allow));
+
+        limiter.last_strike = limiter.last_strike.map(|last| last - STRIKE_MEMORY * 2);
+        assert!(matches!(limiter.check(500), Verdict::Warn(_)));
+        assert_eq!(limiter.strikes, 1);
+    }
+}
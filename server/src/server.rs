@@ -1,56 +1,299 @@
-use crate::{error, event::ServerEvent, info};
-use std::{collections::HashMap, fmt::Display, io::ErrorKind, str::from_utf8, sync::Arc};
-use tokio::net::ToSocketAddrs;
-use tokio::net::{TcpListener, TcpStream};
+use crate::accounts::{Accounts, hash_password, verify_password};
+use crate::admin::{self, AdminCommand, AdminReply, AdminRequest};
+use crate::broker::{Broker, LocalBroker, Shared, SocketBroker};
+use crate::event::ServerEvent;
+use crate::federation::{self, Federation};
+use crate::history::History;
+use crate::macros::set_log_level;
+use crate::metrics::{self, Failure, METRICS};
+use crate::moderation::{BanTarget, Bans, Moderation, Role};
+use crate::peer::{Incoming, Listener, Peer, PeerAddr, Protocol, Socket};
+use crate::protocol::{Frame, FrameReader, Welcome};
+use crate::rate_limit::{RateLimiter, Verdict};
+use crate::sessions::Sessions;
+use crate::web::{self, Request};
+use crate::{config::Config, debug, error, info};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{Receiver, Sender};
 
 pub type Result<T> = std::result::Result<T, ()>;
-pub type Connection = Arc<TcpStream>;
+pub type Connection = Arc<Peer>;
+
+/// Reason given to clients that send requests before logging in.
+const LOGIN_REQUIRED: &str = "You must log in first";
+/// Who kicks and bans users through the admin socket.
+const ADMINISTRATOR: &str = "the administrator";
+
+/// Builds the configuration again, for the admin socket to reload it.
+pub type Reload = Box<dyn Fn() -> Result<Config> + Send>;
 
 pub struct Server {
-    listener: TcpListener,
+    listeners: Vec<Listener>,
+    admin: Option<Listener>,
+    metrics: Option<TcpListener>,
+    reload: Option<Reload>,
+    config: Config,
 }
 
 impl Server {
     /// Tries to create a new instance of the server.
     ///
     /// # Arguments
-    /// - `addr`: A type that can be converted into a socket address, such as a string like
-    /// `"127.0.0.1:8080"` or a tuple like `("0.0.0.0", 8000)`
+    /// - `config`: Server settings. Every address in `config.addresses` and
+    ///   `config.ws_addresses` must be something that can be converted into a
+    ///   socket address, such as `"127.0.0.1:8080"`. `config.unix_socket` and
+    ///   `config.admin_socket` are bound as well when given, and so is
+    ///   `config.metrics_address`.
     ///
     /// # Returns
-    /// - `Ok(Server)`: If the listener successfully binds to the address.
-    /// - `Err(())`: If the bind is unsuccessful, with a message logged.
-    pub async fn build<A: ToSocketAddrs + Display>(addr: A) -> Result<Self> {
-        TcpListener::bind(&addr)
-            .await
-            .map(|listener| Self { listener })
-            .map_err(|err| error!("Could not bind server to {addr}: {err}"))
+    /// - `Ok(Server)`: If the listeners successfully bind to every address.
+    /// - `Err(())`: If a bind is unsuccessful, with a message logged.
+    pub async fn build(config: Config) -> Result<Self> {
+        let addresses = (config.addresses.iter().map(|addr| (addr, Protocol::Tcp))).chain(
+            config
+                .ws_addresses
+                .iter()
+                .map(|addr| (addr, Protocol::WebSocket)),
+        );
+        let mut listeners = Vec::new();
+        for (addr, protocol) in addresses {
+            listeners.push(Listener::tcp(addr, protocol).await?);
+        }
+        if let Some(path) = &config.unix_socket {
+            listeners.push(Listener::unix(path, 0o666)?);
+        }
+        let admin = match &config.admin_socket {
+            // only the user running the server can manage it
+            Some(path) => Some(Listener::unix(path, 0o600)?),
+            None => None,
+        };
+        let metrics = match &config.metrics_address {
+            Some(addr) => Some(
+                TcpListener::bind(addr)
+                    .await
+                    .map_err(|err| error!("Could not bind metrics to {addr}: {err}"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            listeners,
+            admin,
+            metrics,
+            reload: None,
+            config,
+        })
+    }
+
+    /// Sets how the admin socket builds the configuration again when asked
+    /// to reload it, such as reading the configuration file anew.
+    pub fn reload_with(mut self, reload: impl Fn() -> Result<Config> + Send + 'static) -> Self {
+        self.reload = Some(Box::new(reload));
+        self
     }
 
-    /// Starts the server, accepting incoming client connections and
-    /// spawning a thread for each client.
+    /// Starts the server, accepting incoming client connections on every
+    /// listener and spawning a task for each client.
     ///
     /// # Returns
     /// - `Ok(())`: If the server runs without fatal errors.
     /// - `Err(())`: If an error occurs during initialization or runtime.
-    pub async fn run(&mut self) -> Result<()> {
+    pub async fn run(self) -> Result<()> {
         let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        let active = Arc::new(AtomicUsize::new(0));
+        let per_ip = Arc::new(Mutex::new(HashMap::new()));
+        let accounts = Arc::new(Mutex::new(Accounts::load(
+            self.config.accounts_file.as_deref(),
+        )?));
+        let bans = Arc::new(Mutex::new(Bans::load(self.config.bans_file.as_deref())?));
+
+        for listener in self.listeners {
+            let port = listener.local_addr()?;
+            info!(
+                "Listening to {} connections on port {port}",
+                listener.protocol()
+            );
+
+            tokio::spawn(Self::accept(
+                listener,
+                sender.clone(),
+                Arc::clone(&active),
+                Arc::clone(&per_ip),
+                Arc::clone(&accounts),
+                Arc::clone(&bans),
+                self.config.clone(),
+            ));
+        }
+
+        let (admin_sender, admin_receiver) = tokio::sync::mpsc::channel(10);
+        if let Some(listener) = self.admin {
+            info!("Listening to admin requests on {}", listener.local_addr()?);
+            tokio::spawn(admin::listen(listener, admin_sender));
+        }
+        if let Some(listener) = self.metrics {
+            let addr = listener
+                .local_addr()
+                .map_err(|err| error!("Failed to get listener address: {err}"))?;
+            info!("Serving metrics on http://{addr}/metrics");
+            tokio::spawn(metrics::listen(listener));
+        }
 
-        tokio::spawn(Self::server(receiver));
+        for addr in &self.config.links {
+            tokio::spawn(federation::connect(
+                addr.clone(),
+                sender.clone(),
+                self.config.clone(),
+            ));
+        }
 
-        let port = self
-            .listener
-            .local_addr()
-            .map_err(|err| error!("Failed to get listener address: {err}"))?;
+        let history = History::open(
+            self.config.history_length,
+            self.config.history_file.as_deref(),
+        )?;
+        let events = (receiver, admin_receiver);
+        match self.config.broker_socket.clone() {
+            Some(path) => {
+                let broker = SocketBroker::open(&path, history.last_id()).await?;
+                Self::server(events, self.config, self.reload, history, bans, broker).await;
+            }
+            None => {
+                let broker = LocalBroker::new(history.last_id());
+                Self::server(events, self.config, self.reload, history, bans, broker).await;
+            }
+        }
+        Ok(())
+    }
 
-        info!("Listening to connections on port {port}");
+    /// Accepts connections from a single listener.
+    ///
+    /// # Arguments
+    /// - `listener`: Socket the connections are accepted from.
+    /// - `sender`: Sender used by client tasks to communicate with the server loop.
+    /// - `active`: Number of clients currently connected, shared by all listeners.
+    /// - `per_ip`: Number of clients currently connected from each IP address.
+    /// - `accounts`: Registered users, shared by all clients.
+    /// - `bans`: Banned users and IP addresses, shared with the server loop.
+    /// - `config`: Server settings.
+    ///
+    /// Connections from banned IP addresses or beyond `config.max_clients`
+    /// and `config.max_clients_per_ip` are closed right away, telling the
+    /// client why.
+    async fn accept(
+        listener: Listener,
+        sender: Sender<ServerEvent>,
+        active: Arc<AtomicUsize>,
+        per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+        accounts: Arc<Mutex<Accounts>>,
+        bans: Arc<Mutex<Bans>>,
+        config: Config,
+    ) {
         loop {
-            match self.listener.accept().await {
-                Ok((stream, _)) => {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    METRICS.connection();
+                    // Unix socket clients have no IP address to ban or count
+                    let ip = addr.ip();
+                    let refusal = if ip.is_some_and(|ip| bans.lock().unwrap().is_ip_banned(ip)) {
+                        Some("You are banned from this server")
+                    } else if active.load(Ordering::SeqCst) >= config.max_clients {
+                        Some("The server is full")
+                    } else if ip.is_some_and(|ip| {
+                        per_ip.lock().unwrap().get(&ip).copied().unwrap_or_default()
+                            >= config.max_clients_per_ip
+                    }) {
+                        Some("Too many connections from your address")
+                    } else {
+                        None
+                    };
+                    match refusal {
+                        Some(reason) => {
+                            info!("Refused client {addr}: {reason}");
+                            METRICS.failure(Failure::Refused);
+                        }
+                        None => {
+                            active.fetch_add(1, Ordering::SeqCst);
+                            if let Some(ip) = ip {
+                                *per_ip.lock().unwrap().entry(ip).or_default() += 1;
+                            }
+                        }
+                    }
+
                     let sender = sender.clone();
-                    let stream = Arc::new(stream);
-                    tokio::spawn(Self::client(sender, stream));
+                    let active = Arc::clone(&active);
+                    let per_ip = Arc::clone(&per_ip);
+                    let accounts = Arc::clone(&accounts);
+                    let handshake_timeout = Duration::from_secs(config.handshake_timeout);
+                    let protocol = listener.protocol();
+                    let web_client = config.web_client && matches!(protocol, Protocol::WebSocket);
+                    let peer_credentials = config.peer_credentials;
+                    let max_message_size = config.max_message_size;
+                    let limiter = RateLimiter::new(&config.rate_limit);
+                    tokio::spawn(async move {
+                        let accept = |socket| {
+                            Peer::accept(
+                                socket,
+                                protocol,
+                                addr,
+                                handshake_timeout,
+                                max_message_size,
+                                peer_credentials,
+                            )
+                        };
+                        let peer = match socket {
+                            Socket::Tcp(stream) if web_client => {
+                                let request = web::read_ahead(stream);
+                                let request = tokio::time::timeout(handshake_timeout, request);
+                                match request.await.ok().flatten() {
+                                    Some(Request::Page(stream, method, path)) => {
+                                        web::serve(stream, addr, &method, &path).await;
+                                        Err(())
+                                    }
+                                    Some(Request::WebSocket(stream)) => {
+                                        accept(Socket::ReadAhead(stream)).await
+                                    }
+                                    None => Err(()),
+                                }
+                            }
+                            socket => accept(socket).await,
+                        };
+                        if let Ok((peer, mut incoming)) = peer {
+                            match refusal {
+                                Some(reason) => peer.refuse(&mut incoming, reason).await,
+                                None => {
+                                    let stream = Arc::new(peer);
+                                    let _ = Self::client(
+                                        sender,
+                                        stream,
+                                        incoming,
+                                        accounts,
+                                        max_message_size,
+                                        limiter,
+                                    )
+                                    .await;
+                                }
+                            }
+                        }
+                        if refusal.is_some() {
+                            return;
+                        }
+
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        let Some(ip) = ip else {
+                            return;
+                        };
+                        let mut per_ip = per_ip.lock().unwrap();
+                        if let Some(count) = per_ip.get_mut(&ip) {
+                            *count -= 1;
+                            if *count == 0 {
+                                per_ip.remove(&ip);
+                            }
+                        }
+                    });
                 }
                 Err(err) => error!("Failed to connect to client: {err}"),
             }
@@ -60,125 +303,1236 @@ impl Server {
     /// Main server loop that processes messages from connected clients.
     ///
     /// # Arguments
-    /// - `messages`: Receiver end of the channel used to receive messages from clients.
+    /// - `events`: Receiver ends of the channels used to receive messages from
+    ///   clients, and requests from the admin socket.
+    /// - `config`: Server settings.
+    /// - `reload`: Builds the settings again when the admin socket asks to.
+    /// - `history`: Messages posted so far.
+    /// - `bans`: Banned users and IP addresses, shared with the listeners.
+    /// - `broker`: Fans the room traffic out to the servers sharing the rooms.
     ///
-    /// Handles new connections, disconnections, and broadcasting messages
-    /// to all connected clients except the sender.
-    async fn server(mut messages: Receiver<ServerEvent>) {
-        let mut clients = HashMap::new();
+    /// Handles new connections, disconnections, room changes, history searches,
+    /// moderation, and broadcasting messages and reactions to the members of
+    /// the room they belong to.
+    async fn server(
+        (mut messages, mut admin): (Receiver<ServerEvent>, Receiver<AdminRequest>),
+        mut config: Config,
+        reload: Option<Reload>,
+        mut history: History,
+        bans: Arc<Mutex<Bans>>,
+        mut broker: impl Broker,
+    ) {
+        let started = Instant::now();
+        let mut clients: HashMap<PeerAddr, Member> = HashMap::new();
+        let mut sessions = Sessions::new(Duration::from_secs(config.session_ttl));
+        // when the mute of each muted user ends
+        let mut mutes: HashMap<String, SystemTime> = HashMap::new();
+        let mut federation = Federation::new(&config.server_name, config.link_password.clone());
+        // pings clients and looks for the ones that did not log in in time,
+        // went idle or stopped answering
+        let mut timeouts = tokio::time::interval(Duration::from_secs(1));
 
         loop {
-            match messages.recv().await {
-                Some(msg) => {
-                    match msg {
-                        ServerEvent::ClientConnected(stream) => {
-                            let client_addr = stream.peer_addr().unwrap();
-                            info!("Client connected: {client_addr}");
-                            clients.insert(client_addr, Arc::clone(&stream));
-                        }
-                        ServerEvent::ClientDisconnected(stream) => {
-                            let client_addr = stream.peer_addr().unwrap();
-                            info!("Client disconnected: {client_addr}");
-                            clients.remove(&client_addr);
-                        }
-                        ServerEvent::NewMessage(conn, msg) => {
-                            let author_addr = conn.peer_addr().unwrap();
-                            let bytes_msg =
-                                serde_json::to_string(&msg).unwrap().as_bytes().to_vec();
-                            info!("Client {author_addr} sent: {} bytes", bytes_msg.len());
-                            for (client, stream) in &clients {
-                                let _ = stream.writable().await.map_err(|err| {
-                                    error!("Failed waiting for socket to become available: {err}")
-                                });
+            let event = tokio::select! {
+                event = messages.recv() => event,
+                Some((command, reply)) = admin.recv() => Some(ServerEvent::Admin(command, reply)),
+                shared = broker.next() => {
+                    Self::share(shared, &clients, &mut history, &federation).await;
+                    continue;
+                }
+                _ = timeouts.tick() => {
+                    Self::close_late(&mut clients, &config).await;
+                    sessions.prune(clients.values().filter_map(|member| member.token.as_ref()));
+                    federation.heartbeat(&config).await;
+                    for addr in Self::heartbeat(&mut clients, &config).await {
+                        // the connection may be half-open, so its task might never end
+                        if let Some(member) = clients.remove(&addr)
+                            && let Some(token) = member.token
+                        {
+                            sessions.update(&token, &member.room, history.last_id());
+                        }
+                    }
+                    let users = clients.values().filter(|member| member.user.is_some()).count();
+                    METRICS.set_state(clients.len(), users, federation.link_count(), messages.len());
+                    continue;
+                }
+            };
+            // answering pings does not keep a client from being idle
+            if let Some(event) = &event
+                && !matches!(event, ServerEvent::Pong(..))
+                && let Some(conn) = event.connection()
+                && let addr = conn.addr
+                && let Some(member) = clients.get_mut(&addr)
+            {
+                member.last_active = Instant::now();
+            }
+
+            match event {
+                Some(msg) => match msg {
+                    ServerEvent::ClientConnected(stream) => {
+                        let client_addr = stream.addr;
+                        info!("Client connected: {client_addr}");
+                        // clients join a room once logged in
+                        let member = Member {
+                            stream,
+                            room: String::new(),
+                            user: None,
+                            token: None,
+                            connected: Instant::now(),
+                            last_active: Instant::now(),
+                            closed: false,
+                            last_ping: Instant::now(),
+                            ping: None,
+                        };
+                        member.stream.send(&Self::welcome(&clients, &config)).await;
+                        clients.insert(client_addr, member);
+                    }
+                    ServerEvent::LoggedIn(stream, user) => {
+                        let client_addr = stream.addr;
+                        let Some(member) = clients.get_mut(&client_addr) else {
+                            continue;
+                        };
+
+                        if bans.lock().unwrap().is_user_banned(&user) {
+                            let reason = format!("User '{user}' is banned");
+                            stream.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+
+                        info!("Client {client_addr} logged in as {user}");
+                        // logging in again replaces the session of the connection
+                        if let Some(token) = member.token.take() {
+                            sessions.revoke(&token);
+                        }
+                        let room = config.rooms[0].clone();
+                        let token = sessions.create(&user, &room, history.last_id());
+                        Self::log_in(member, &config, user, room, token).await;
+                    }
+                    ServerEvent::Resume(stream, token) => {
+                        let client_addr = stream.addr;
+                        let Some(member) = clients.get_mut(&client_addr) else {
+                            continue;
+                        };
+                        let Some(session) = sessions.resume(&token) else {
+                            let reason = String::from("The session has expired, log in again");
+                            stream.send(&Frame::Error { reason }).await;
+                            continue;
+                        };
+                        if bans.lock().unwrap().is_user_banned(&session.user) {
+                            sessions.revoke(&token);
+                            let reason = format!("User '{}' is banned", session.user);
+                            stream.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+                        if let Some(previous) = member.token.take()
+                            && previous != token
+                        {
+                            sessions.revoke(&previous);
+                        }
+
+                        info!(
+                            "Client {client_addr} resumed the session of {}",
+                            session.user
+                        );
+                        let room = if config.rooms.contains(&session.room) {
+                            session.room
+                        } else {
+                            config.rooms[0].clone()
+                        };
+                        Self::log_in(member, &config, session.user, room.clone(), token).await;
+
+                        // messages posted in the room while the client was away
+                        let missed = history.missed(room.clone(), session.last_read);
+                        tokio::spawn(async move {
+                            if let Ok(missed) = missed.await {
+                                let frame = Frame::History {
+                                    room,
+                                    messages: missed.messages,
+                                    reset: missed.reset,
+                                };
+                                stream.send(&frame).await;
+                            }
+                        });
+                    }
+                    ServerEvent::Logout(stream) => {
+                        let client_addr = stream.addr;
+                        let Some(member) = clients.get_mut(&client_addr) else {
+                            continue;
+                        };
+
+                        if let Some(token) = member.token.take() {
+                            sessions.revoke(&token);
+                        }
+                        if let Some(user) = member.user.take() {
+                            info!("Client {client_addr} logged out of {user}");
+                        }
+                        member.room.clear();
+                    }
+                    ServerEvent::ClientDisconnected(stream) => {
+                        let client_addr = stream.addr;
+                        // links closed by the heartbeat are already forgotten
+                        if federation.remove(&client_addr) || stream.link.get().is_some() {
+                            continue;
+                        }
+                        info!("Client disconnected: {client_addr}");
+                        if let Some(member) = clients.remove(&client_addr)
+                            && let Some(token) = member.token
+                        {
+                            sessions.update(&token, &member.room, history.last_id());
+                        }
+                    }
+                    ServerEvent::JoinRoom(stream, room) => {
+                        let client_addr = stream.addr;
+                        let Some(member) = clients.get_mut(&client_addr) else {
+                            continue;
+                        };
+                        if member.user.is_none() {
+                            let reason = String::from(LOGIN_REQUIRED);
+                            stream.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+
+                        if !config.rooms.contains(&room) {
+                            let reason = format!("Room '{room}' does not exist");
+                            stream.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+
+                        info!("Client {client_addr} joined room {room}");
+                        member.room = room.clone();
+                        stream.send(&Frame::Joined { room }).await;
+                    }
+                    ServerEvent::NewMessage(conn, mut msg) => {
+                        let author_addr = conn.addr;
+                        let Some(member) = clients.get(&author_addr) else {
+                            continue;
+                        };
+                        let Some(user) = &member.user else {
+                            let reason = String::from(LOGIN_REQUIRED);
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        };
+                        if let Err(reason) = Self::check_mute(&mut mutes, user) {
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
 
-                                let _ = stream.try_write(&bytes_msg).map_err(|err| {
-                                    error!("Failed to send message from {author_addr} to {client}: {err}")});
+                        // only the broker numbers messages, only links relay them,
+                        // and searches by date trust the time set here
+                        msg.id = 0;
+                        msg.origin = None;
+                        msg.date = SystemTime::now();
+                        msg.author = user.clone();
+                        msg.room = member.room.clone();
+                        msg.color = config.user_colors.get(&msg.author).cloned();
+                        if msg.reply_to.is_some_and(|id| history.find(id).is_none()) {
+                            msg.reply_to = None;
+                            let text = String::from(
+                                "The message replied to is unknown or too old, sent without the reply",
+                            );
+                            conn.send(&Frame::Notice { text }).await;
+                        }
+
+                        let frame = Frame::Message(msg.clone());
+                        info!("Client {author_addr} sent: {} bytes", frame.encode().len());
+                        broker.publish(Shared::Message(msg)).await;
+                    }
+                    ServerEvent::Link(conn, server, password) => {
+                        let addr = conn.addr;
+                        let Some(member) = clients.get_mut(&addr) else {
+                            continue;
+                        };
+                        if member.user.is_some() || !federation.check_password(&password) {
+                            info!("Refused link from {addr} as server {server}");
+                            let reason = String::from("Invalid link password");
+                            Self::disconnect(member, &reason).await;
+                            continue;
+                        }
+
+                        match federation.add(server, Arc::clone(&conn)) {
+                            Ok(()) => {
+                                // links are not clients: no room, timeouts nor pings
+                                clients.remove(&addr);
+                                let server = config.server_name.clone();
+                                conn.send(&Frame::Linked { server }).await;
+                            }
+                            Err(reason) => {
+                                info!("Refused link from {addr}: {reason}");
+                                Self::disconnect(member, &reason).await;
                             }
                         }
                     }
-                }
+                    ServerEvent::Linked(conn, server) => {
+                        if let Err(reason) = federation.add(server, Arc::clone(&conn)) {
+                            error!("Failed to link to server {}: {reason}", conn.addr);
+                            conn.close();
+                        }
+                    }
+                    ServerEvent::Relay(conn, relay) => {
+                        if !federation.is_link(&conn.addr) {
+                            continue;
+                        }
+                        let Some(msg) = federation.receive(relay).await else {
+                            continue;
+                        };
+                        if config.rooms.contains(&msg.room) {
+                            broker.publish(Shared::Message(msg)).await;
+                        }
+                    }
+                    ServerEvent::React(conn, mut reaction) => {
+                        let addr = conn.addr;
+                        let Some(user) = clients.get(&addr).and_then(|m| m.user.clone()) else {
+                            let reason = String::from(LOGIN_REQUIRED);
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        };
+                        if let Err(reason) = Self::check_mute(&mut mutes, &user) {
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+                        reaction.author = user;
+
+                        let Some(msg) = history.find(reaction.id) else {
+                            let reason =
+                                String::from("Cannot react to a message unknown or too old");
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        };
+
+                        let room = msg.room.clone();
+                        broker.publish(Shared::React { room, reaction }).await;
+                    }
+                    ServerEvent::Search(conn, query) => {
+                        let addr = conn.addr;
+                        if clients.get(&addr).is_none_or(|m| m.user.is_none()) {
+                            let reason = String::from(LOGIN_REQUIRED);
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+
+                        let search = history.search(query);
+                        tokio::spawn(async move {
+                            let frame = match search.await {
+                                Ok(page) => Frame::SearchResults {
+                                    messages: page.messages,
+                                    next: page.next,
+                                },
+                                Err(()) => Frame::Error {
+                                    reason: String::from("The history could not be searched"),
+                                },
+                            };
+                            conn.send(&frame).await;
+                        });
+                    }
+                    ServerEvent::Sync(conn, room, after_id) => {
+                        let addr = conn.addr;
+                        if clients.get(&addr).is_none_or(|m| m.user.is_none()) {
+                            let reason = String::from(LOGIN_REQUIRED);
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+
+                        if !config.rooms.contains(&room) {
+                            let reason = format!("Room '{room}' does not exist");
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
+
+                        let missed = history.missed(room.clone(), after_id);
+                        tokio::spawn(async move {
+                            let frame = match missed.await {
+                                Ok(missed) => Frame::History {
+                                    room,
+                                    messages: missed.messages,
+                                    reset: missed.reset,
+                                },
+                                Err(()) => Frame::Error {
+                                    reason: String::from("The history could not be read"),
+                                },
+                            };
+                            conn.send(&frame).await;
+                        });
+                    }
+                    ServerEvent::Pong(conn, nonce) => {
+                        let addr = conn.addr;
+                        if let Some(member) = clients.get_mut(&addr)
+                            && let Some((sent, at)) = member.ping
+                            && sent == nonce
+                        {
+                            debug!("Client {addr} answered in {} ms", at.elapsed().as_millis());
+                            member.ping = None;
+                        } else {
+                            federation.pong(&addr, nonce);
+                        }
+                    }
+                    ServerEvent::Moderate(conn, action) => {
+                        let addr = conn.addr;
+                        let Some(user) = clients.get(&addr).and_then(|m| m.user.clone()) else {
+                            let reason = String::from(LOGIN_REQUIRED);
+                            conn.send(&Frame::Error { reason }).await;
+                            continue;
+                        };
+
+                        let result = match action {
+                            Moderation::Kick {
+                                user: target,
+                                reason,
+                            } => match Self::check_rank(&config, &user, &target) {
+                                Ok(()) => {
+                                    let reason = Self::kick_reason(&user, reason);
+                                    let kicked = Self::kick(
+                                        &mut clients,
+                                        &mut sessions,
+                                        |member| member.user.as_ref() == Some(&target),
+                                        &reason,
+                                    )
+                                    .await;
+                                    if kicked == 0 {
+                                        Err(format!("User '{target}' is not connected"))
+                                    } else {
+                                        info!("{user} kicked {target}");
+                                        Ok(format!("Kicked {target}"))
+                                    }
+                                }
+                                Err(reason) => Err(reason),
+                            },
+                            Moderation::Ban {
+                                kind,
+                                target,
+                                reason,
+                            } => {
+                                let target = BanTarget::new(kind, &target);
+                                let allowed = target.and_then(|target| match target {
+                                    BanTarget::Ip(_) if config.role(&user) < Role::Owner => {
+                                        Err(String::from("Only owners can ban IP addresses"))
+                                    }
+                                    BanTarget::Ip(_) => Ok(target),
+                                    BanTarget::User(ref name) => {
+                                        Self::check_rank(&config, &user, name).map(|()| target)
+                                    }
+                                });
+                                match allowed.and_then(|target| {
+                                    bans.lock().unwrap().ban(&target).map(|()| target)
+                                }) {
+                                    Ok(target) => {
+                                        let reason = Self::kick_reason(&user, reason);
+                                        Self::kick(
+                                            &mut clients,
+                                            &mut sessions,
+                                            |member| match &target {
+                                                // spares the clients that cannot be moderated
+                                                BanTarget::Ip(ip) => {
+                                                    member.stream.addr.ip() == Some(*ip)
+                                                        && member.user.as_ref().is_none_or(
+                                                            |other| {
+                                                                config.role(other)
+                                                                    < config.role(&user)
+                                                            },
+                                                        )
+                                                }
+                                                BanTarget::User(name) => {
+                                                    member.user.as_ref() == Some(name)
+                                                }
+                                            },
+                                            &reason,
+                                        )
+                                        .await;
+                                        info!("{user} banned {target}");
+                                        Ok(format!("Banned {target}"))
+                                    }
+                                    Err(reason) => Err(reason),
+                                }
+                            }
+                            Moderation::Unban { kind, target } => {
+                                if config.role(&user) < Role::Moderator {
+                                    Err(String::from("Only moderators can lift bans"))
+                                } else {
+                                    BanTarget::new(kind, &target).and_then(|target| {
+                                        bans.lock().unwrap().unban(&target)?;
+                                        info!("{user} lifted the ban of {target}");
+                                        Ok(format!("Lifted the ban of {target}"))
+                                    })
+                                }
+                            }
+                            Moderation::Mute {
+                                user: target,
+                                seconds,
+                            } => match Self::check_rank(&config, &user, &target) {
+                                Ok(()) if seconds == 0 => {
+                                    mutes.remove(&target);
+                                    Ok(format!("Unmuted {target}"))
+                                }
+                                Ok(()) => {
+                                    let until = SystemTime::now() + Duration::from_secs(seconds);
+                                    mutes.insert(target.clone(), until);
+                                    let text =
+                                        format!("You were muted for {seconds} seconds by {user}");
+                                    for member in clients
+                                        .values()
+                                        .filter(|m| m.user.as_ref() == Some(&target))
+                                    {
+                                        member
+                                            .stream
+                                            .send(&Frame::Notice { text: text.clone() })
+                                            .await;
+                                    }
+                                    info!("{user} muted {target} for {seconds} seconds");
+                                    Ok(format!("Muted {target} for {seconds} seconds"))
+                                }
+                                Err(reason) => Err(reason),
+                            },
+                            Moderation::Delete { id } => match history.find(id) {
+                                None => {
+                                    Err(String::from("Cannot delete a message unknown or too old"))
+                                }
+                                Some(msg) => {
+                                    let allowed = if msg.author == user {
+                                        Ok(())
+                                    } else {
+                                        Self::check_rank(&config, &user, &msg.author)
+                                    };
+                                    match allowed.and_then(|()| {
+                                        history.delete(id).map_err(|()| {
+                                            String::from("The message could not be deleted")
+                                        })
+                                    }) {
+                                        Ok(()) => {
+                                            info!("{user} deleted message {id} of {}", msg.author);
+                                            let room = msg.room;
+                                            broker.publish(Shared::Deleted { room, id }).await;
+                                            continue;
+                                        }
+                                        Err(reason) => Err(reason),
+                                    }
+                                }
+                            },
+                        };
+                        let frame = match result {
+                            Ok(text) => Frame::Notice { text },
+                            Err(reason) => Frame::Error { reason },
+                        };
+                        conn.send(&frame).await;
+                    }
+                    ServerEvent::Admin(command, reply) => {
+                        let result = match command {
+                            AdminCommand::Clients => Ok(Self::list_clients(&clients)),
+                            AdminCommand::Rooms => Ok(Self::list_rooms(&clients, &config)),
+                            AdminCommand::Kick { user, reason } => {
+                                let reason = Self::kick_reason(ADMINISTRATOR, reason);
+                                let kicked = Self::kick(
+                                    &mut clients,
+                                    &mut sessions,
+                                    |member| member.user.as_ref() == Some(&user),
+                                    &reason,
+                                )
+                                .await;
+                                if kicked == 0 {
+                                    Err(format!("User '{user}' is not connected"))
+                                } else {
+                                    info!("The administrator kicked {user}");
+                                    Ok(format!("Kicked {user}"))
+                                }
+                            }
+                            AdminCommand::Ban {
+                                kind,
+                                target,
+                                reason,
+                            } => {
+                                let banned = BanTarget::new(kind, &target).and_then(|target| {
+                                    bans.lock().unwrap().ban(&target).map(|()| target)
+                                });
+                                match banned {
+                                    Ok(target) => {
+                                        let reason = Self::kick_reason(ADMINISTRATOR, reason);
+                                        let kicked = Self::kick(
+                                            &mut clients,
+                                            &mut sessions,
+                                            |member| match &target {
+                                                BanTarget::Ip(ip) => {
+                                                    member.stream.addr.ip() == Some(*ip)
+                                                }
+                                                BanTarget::User(name) => {
+                                                    member.user.as_ref() == Some(name)
+                                                }
+                                            },
+                                            &reason,
+                                        )
+                                        .await;
+                                        info!("The administrator banned {target}");
+                                        Ok(format!(
+                                            "Banned {target}, disconnecting {kicked} clients"
+                                        ))
+                                    }
+                                    Err(reason) => Err(reason),
+                                }
+                            }
+                            AdminCommand::Unban { kind, target } => BanTarget::new(kind, &target)
+                                .and_then(|target| {
+                                    bans.lock().unwrap().unban(&target)?;
+                                    info!("The administrator lifted the ban of {target}");
+                                    Ok(format!("Lifted the ban of {target}"))
+                                }),
+                            AdminCommand::Broadcast { text } => {
+                                let frame = Frame::Notice { text };
+                                let mut sent = 0;
+                                for member in clients.values().filter(|m| m.user.is_some()) {
+                                    member.stream.send(&frame).await;
+                                    sent += 1;
+                                }
+                                Ok(format!("Sent the announcement to {sent} clients"))
+                            }
+                            AdminCommand::Reload => match reload.as_ref().map(|reload| reload()) {
+                                Some(Ok(new)) => {
+                                    config.apply_live(new);
+                                    set_log_level(config.log.level);
+                                    Self::update_rooms(&mut clients, &config).await;
+                                    info!("Reloaded the configuration");
+                                    Ok(String::from(
+                                        "Reloaded the configuration. Listeners, files, links and \
+                                         connection limits change when the server restarts",
+                                    ))
+                                }
+                                Some(Err(())) => Err(String::from(
+                                    "The configuration is invalid, see the server log",
+                                )),
+                                None => {
+                                    Err(String::from("This server cannot reload its configuration"))
+                                }
+                            },
+                            AdminCommand::Stats => Ok(Self::stats(
+                                started,
+                                &clients,
+                                &config,
+                                &history,
+                                &federation,
+                            )),
+                        };
+                        let _ = reply.send(match result {
+                            Ok(text) => AdminReply::Done { text },
+                            Err(reason) => AdminReply::Failed { reason },
+                        });
+                    }
+                },
                 None => eprintln!("The server channel has been closed"),
             }
         }
     }
 
+    /// Puts a client that just logged in in `room`, telling it the token of
+    /// its session and the available rooms.
+    async fn log_in(
+        member: &mut Member,
+        config: &Config,
+        user: String,
+        room: String,
+        token: String,
+    ) {
+        let logged_in = Frame::LoggedIn {
+            username: user.clone(),
+            token: token.clone(),
+        };
+        let rooms = Frame::Rooms {
+            rooms: config.rooms.clone(),
+        };
+        member.stream.send(&logged_in).await;
+        member.stream.send(&rooms).await;
+        member
+            .stream
+            .send(&Frame::Joined { room: room.clone() })
+            .await;
+
+        member.user = Some(user);
+        member.room = room;
+        member.token = Some(token);
+    }
+
+    /// Checks that `user` may moderate `target`, which takes a higher role.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If `user` outranks `target`.
+    /// - `Err(reason)`: Otherwise, with the reason to report to the client.
+    fn check_rank(config: &Config, user: &str, target: &str) -> std::result::Result<(), String> {
+        let role = config.role(user);
+        if role < Role::Moderator {
+            Err(String::from("Only moderators can do this"))
+        } else if role <= config.role(target) {
+            Err(format!("You cannot moderate {target}"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that `user` is not muted, forgetting mutes that ended.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If `user` can post.
+    /// - `Err(reason)`: If `user` is muted, with the reason to report to the client.
+    fn check_mute(
+        mutes: &mut HashMap<String, SystemTime>,
+        user: &str,
+    ) -> std::result::Result<(), String> {
+        let Some(until) = mutes.get(user) else {
+            return Ok(());
+        };
+        match until.duration_since(SystemTime::now()) {
+            Ok(left) => Err(format!(
+                "You are muted for {} more seconds",
+                left.as_secs() + 1
+            )),
+            Err(_) => {
+                mutes.remove(user);
+                Ok(())
+            }
+        }
+    }
+
+    /// Disconnects the clients that did not log in within
+    /// `config.handshake_timeout`, or sent nothing for `config.idle_timeout`.
+    async fn close_late(clients: &mut HashMap<PeerAddr, Member>, config: &Config) {
+        let handshake = Duration::from_secs(config.handshake_timeout);
+        let idle = Duration::from_secs(config.idle_timeout);
+        for (addr, member) in clients.iter_mut() {
+            // closed connections stay until their task reports them
+            if member.closed {
+                continue;
+            }
+
+            let reason = if member.user.is_none() && member.connected.elapsed() > handshake {
+                format!(
+                    "You did not log in within {} seconds",
+                    config.handshake_timeout
+                )
+            } else if config.idle_timeout > 0 && member.last_active.elapsed() > idle {
+                format!(
+                    "Disconnected after {} seconds of inactivity",
+                    config.idle_timeout
+                )
+            } else {
+                continue;
+            };
+            info!("Disconnecting client {addr}: {reason}");
+            METRICS.failure(Failure::Timeout);
+            Self::disconnect(member, &reason).await;
+        }
+    }
+
+    /// Pings the clients every `config.heartbeat_interval`, and disconnects
+    /// the ones that did not answer within `config.heartbeat_timeout`.
+    ///
+    /// # Returns
+    /// The addresses of the clients disconnected.
+    async fn heartbeat(clients: &mut HashMap<PeerAddr, Member>, config: &Config) -> Vec<PeerAddr> {
+        let interval = Duration::from_secs(config.heartbeat_interval);
+        let timeout = Duration::from_secs(config.heartbeat_timeout);
+        let mut evicted = Vec::new();
+        for (addr, member) in clients.iter_mut() {
+            if member.closed {
+                continue;
+            }
+            if let Some((_, at)) = member.ping {
+                if at.elapsed() > timeout {
+                    let reason =
+                        format!("No answer to ping for {} seconds", config.heartbeat_timeout);
+                    info!("Evicting client {addr}: {reason}");
+                    METRICS.failure(Failure::Timeout);
+                    Self::disconnect(member, &reason).await;
+                    evicted.push(*addr);
+                }
+                continue;
+            }
+
+            if member.last_ping.elapsed() >= interval {
+                let (ping, nonce) = Frame::ping();
+                member.stream.send(&ping).await;
+                member.ping = Some((nonce, Instant::now()));
+                member.last_ping = Instant::now();
+            }
+        }
+        evicted
+    }
+
+    /// Closes the connection of a client, telling it why. The client task
+    /// notices the closed socket and reports the disconnection.
+    async fn disconnect(member: &mut Member, reason: &str) {
+        let frame = Frame::Disconnected {
+            reason: reason.to_string(),
+        };
+        member.stream.send(&frame).await;
+        member.closed = true;
+        member.stream.close();
+    }
+
+    /// Message shown to the clients kicked or banned by `user`.
+    fn kick_reason(user: &str, reason: Option<String>) -> String {
+        match reason {
+            Some(reason) => format!("You were kicked by {user}: {reason}"),
+            None => format!("You were kicked by {user}"),
+        }
+    }
+
+    /// Disconnects the clients matching `filter`, ending their login sessions
+    /// so they cannot come back with their token.
+    ///
+    /// # Returns
+    /// The number of clients disconnected.
+    async fn kick(
+        clients: &mut HashMap<PeerAddr, Member>,
+        sessions: &mut Sessions,
+        filter: impl Fn(&Member) -> bool,
+        reason: &str,
+    ) -> usize {
+        let mut kicked = 0;
+        for member in clients.values_mut().filter(|member| filter(member)) {
+            if let Some(token) = member.token.take() {
+                sessions.revoke(&token);
+            }
+            member.user = None;
+            member.room.clear();
+            Self::disconnect(member, reason).await;
+            kicked += 1;
+        }
+        kicked
+    }
+
+    /// Connected clients, one per line, for the admin socket.
+    fn list_clients(clients: &HashMap<PeerAddr, Member>) -> String {
+        let mut members: Vec<&Member> = clients.values().collect();
+        members.sort_by_key(|member| member.connected);
+        let mut text = format!(
+            "{:<24} {:<20} {:<16} {:>10} {:>8}",
+            "ADDRESS", "USER", "ROOM", "CONNECTED", "IDLE"
+        );
+        for member in members {
+            text.push_str(&format!(
+                "\n{:<24} {:<20} {:<16} {:>9}s {:>7}s",
+                member.stream.addr.to_string(),
+                member.user.as_deref().unwrap_or("-"),
+                if member.room.is_empty() {
+                    "-"
+                } else {
+                    &member.room
+                },
+                member.connected.elapsed().as_secs(),
+                member.last_active.elapsed().as_secs(),
+            ));
+        }
+        text
+    }
+
+    /// Rooms of the server with the users in each of them, for the admin socket.
+    fn list_rooms(clients: &HashMap<PeerAddr, Member>, config: &Config) -> String {
+        let lines: Vec<String> = (config.rooms.iter())
+            .map(|room| {
+                let mut users: Vec<&str> = (clients.values())
+                    .filter(|member| member.room == *room)
+                    .filter_map(|member| member.user.as_deref())
+                    .collect();
+                users.sort_unstable();
+                format!("#{room} ({}): {}", users.len(), users.join(", "))
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Statistics of the server, for the admin socket.
+    fn stats(
+        started: Instant,
+        clients: &HashMap<PeerAddr, Member>,
+        config: &Config,
+        history: &History,
+        federation: &Federation,
+    ) -> String {
+        let logged_in = clients.values().filter(|m| m.user.is_some()).count();
+        format!(
+            "Server: {} {}\nUptime: {} seconds\nClients: {} ({logged_in} logged in)\n\
+             Rooms: {}\nLinked servers: {}\nNewest message: {}",
+            config.server_name,
+            env!("CARGO_PKG_VERSION"),
+            started.elapsed().as_secs(),
+            clients.len(),
+            config.rooms.len(),
+            federation.link_count(),
+            history.last_id(),
+        )
+    }
+
+    /// Greeting sent to the clients when they connect.
+    fn welcome(clients: &HashMap<PeerAddr, Member>, config: &Config) -> Frame {
+        // users logged in from several clients count once
+        let users: HashSet<&String> = clients.values().filter_map(|m| m.user.as_ref()).collect();
+        Frame::Welcome(Welcome {
+            server: config.server_name.clone(),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            motd: config.motd.clone(),
+            rooms: config.rooms.clone(),
+            users: users.len(),
+        })
+    }
+
+    /// Tells the logged in clients the rooms after they changed, moving the
+    /// ones whose room is gone to the first room.
+    async fn update_rooms(clients: &mut HashMap<PeerAddr, Member>, config: &Config) {
+        let rooms = Frame::Rooms {
+            rooms: config.rooms.clone(),
+        };
+        for member in clients.values_mut().filter(|member| member.user.is_some()) {
+            member.stream.send(&rooms).await;
+            if !config.rooms.contains(&member.room) {
+                member.room = config.rooms[0].clone();
+                let room = member.room.clone();
+                member.stream.send(&Frame::Joined { room }).await;
+            }
+        }
+    }
+
+    /// Sends the room traffic handed by the broker to the clients of this
+    /// server, and the messages posted on it to the linked servers.
+    async fn share(
+        shared: Shared,
+        clients: &HashMap<PeerAddr, Member>,
+        history: &mut History,
+        federation: &Federation,
+    ) {
+        match shared {
+            Shared::Message(msg) => {
+                METRICS.message();
+                Self::broadcast(clients, &msg.room, &Frame::Message(msg.clone())).await;
+                // the server receiving a relayed message already passed it on
+                if msg.origin.is_none() {
+                    federation.publish(&msg).await;
+                }
+                history.push(msg);
+            }
+            Shared::React { room, reaction } => {
+                Self::broadcast(clients, &room, &Frame::React(reaction)).await;
+            }
+            Shared::Deleted { room, id } => {
+                // the server it was deleted on already removed it, which
+                // makes this a no-op there
+                let _ = history.delete(id);
+                Self::broadcast(clients, &room, &Frame::Deleted { id }).await;
+            }
+        }
+    }
+
+    /// Sends a frame to every client in `room`.
+    async fn broadcast(clients: &HashMap<PeerAddr, Member>, room: &str, frame: &Frame) {
+        let start = Instant::now();
+        for member in clients.values().filter(|member| member.room == room) {
+            member.stream.send(frame).await;
+        }
+        METRICS.broadcast(start.elapsed());
+    }
+
     /// Handles communication with a single client.
     ///
     /// # Arguments
     /// - `messages`: Sender used to communicate with the server loop.
-    /// - `stream`: An `Arc`-wrapped TCP stream for the client.
+    /// - `stream`: Connection of the client.
+    /// - `incoming`: Receiving half of the connection.
+    /// - `accounts`: Registered users, checked when the client logs in.
+    /// - `max_message_size`: Maximum number of bytes of a single frame.
+    /// - `limiter`: Flood protection of the connection.
     ///
-    /// Reads data from the client, detects disconnection, and forwards
-    /// received messages to the server loop.
+    /// Reads frames from the client, detects disconnection, and forwards
+    /// them to the server loop.
     ///
     /// # Returns
     /// - `Ok(())`: If the client disconnects normally.
     /// - `Err(())`: If an error occurs while reading or sending messages.
-    async fn client(messages: Sender<ServerEvent>, stream: Connection) -> Result<()> {
-        let mut buffer = [0u8; 1024];
+    async fn client(
+        messages: Sender<ServerEvent>,
+        stream: Connection,
+        mut incoming: Incoming,
+        accounts: Arc<Mutex<Accounts>>,
+        max_message_size: usize,
+        mut limiter: RateLimiter,
+    ) -> Result<()> {
+        let mut buffer = vec![0u8; federation::frame_limit(max_message_size)];
+        let mut reader = FrameReader::default();
 
-        let client_addr = stream
-            .peer_addr()
-            .map_err(|err| error!("Failed to get client addres: {err}"))?;
+        let client_addr = stream.addr;
 
         messages
             .send(ServerEvent::ClientConnected(Arc::clone(&stream)))
             .await
             .map_err(|err| eprintln!("Failed to send message to server thread: {err}"))?;
 
-        loop {
-            if let Err(err) = stream.readable().await {
-                let _ = messages.send(ServerEvent::ClientDisconnected(Arc::clone(&stream)));
-                error!("Failed waiting for socket to become readable {client_addr}: {err}");
-                break Ok(());
-            }
-
-            match stream.as_ref().try_read(&mut buffer) {
-                Ok(0) => {
-                    // Connection closed
-                    let _ = messages.send(ServerEvent::ClientDisconnected(Arc::clone(&stream)));
-                    break Ok(());
-                }
-                Ok(n) => {
-                    let msg_str = match from_utf8(&buffer[0..n]) {
-                        Ok(s) => s,
-                        Err(err) => {
-                            let _ =
-                                messages.send(ServerEvent::ClientDisconnected(Arc::clone(&stream)));
-                            error!("Failed to convert message to UTF8: {err}");
-                            break Ok(());
-                        }
-                    };
+        while let Ok(true) = incoming.receive(&stream, &mut buffer, &mut reader).await {
+            if let Err(()) = Self::forward_frames(
+                &messages,
+                &stream,
+                &mut incoming,
+                &accounts,
+                &mut reader,
+                &mut limiter,
+                max_message_size,
+            )
+            .await
+            {
+                break;
+            }
 
-                    let msg = match serde_json::from_str(msg_str) {
-                        Ok(m) => m,
-                        Err(err) => {
-                            let _ =
-                                messages.send(ServerEvent::ClientDisconnected(Arc::clone(&stream)));
-                            error!("Failed to parse message: {err}");
-                            break Ok(());
-                        }
-                    };
+            // what is left is the start of a frame still being received
+            let max_frame_size = Self::frame_limit(&stream, max_message_size);
+            if reader.pending() > max_frame_size {
+                error!("Client {client_addr} sent a frame larger than {max_frame_size} bytes");
+                METRICS.failure(Failure::OversizedFrame);
+                let reason = format!("Frames cannot be larger than {max_frame_size} bytes");
+                stream.refuse(&mut incoming, &reason).await;
+                break;
+            }
+        }
+        incoming.finish(&stream).await;
+
+        let _ = messages
+            .send(ServerEvent::ClientDisconnected(Arc::clone(&stream)))
+            .await;
+        Ok(())
+    }
 
-                    if let Err(err) = messages
-                        .send(ServerEvent::NewMessage(Arc::clone(&stream), msg))
-                        .await
-                    {
-                        let _ = messages.send(ServerEvent::ClientDisconnected(Arc::clone(&stream)));
-                        error!("Failed to send message to server thread: {err}");
-                        break Ok(());
+    /// Forwards every complete frame received from a client to the server
+    /// loop, dropping the ones beyond the rate limits. Frames larger than
+    /// `max_message_size` are refused before being parsed.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If all frames were forwarded or dropped.
+    /// - `Err(())`: If a frame is too large, malformed or cannot be forwarded,
+    ///   or the client keeps flooding, in which case the client must be
+    ///   disconnected.
+    async fn forward_frames(
+        messages: &Sender<ServerEvent>,
+        stream: &Connection,
+        incoming: &mut Incoming,
+        accounts: &Arc<Mutex<Accounts>>,
+        reader: &mut FrameReader,
+        limiter: &mut RateLimiter,
+        max_message_size: usize,
+    ) -> Result<()> {
+        while let Some(line) = reader.next_line() {
+            let max_frame_size = Self::frame_limit(stream, max_message_size);
+            if line.len() > max_frame_size {
+                let addr = stream.addr;
+                error!(
+                    "Client {} sent a frame larger than {max_frame_size} bytes",
+                    addr
+                );
+                METRICS.failure(Failure::OversizedFrame);
+                let reason = format!("Frames cannot be larger than {max_frame_size} bytes");
+                stream.refuse(incoming, &reason).await;
+                return Err(());
+            }
+
+            let frame = Frame::decode(&line);
+            // linked servers relay the messages of all their clients, and
+            // malformed frames count as chat traffic
+            let verdict = match &frame {
+                _ if stream.link.get().is_some() => Verdict::Allow,
+                Ok(frame) if frame.is_login() => limiter.check_login(),
+                Ok(frame) if !frame.is_rate_limited() => Verdict::Allow,
+                _ => limiter.check(line.len()),
+            };
+            match verdict {
+                Verdict::Allow => {}
+                Verdict::Drop => continue,
+                Verdict::Warn(reason) => {
+                    let addr = stream.addr;
+                    info!("Client {} is flooding: {reason}", addr);
+                    stream.send(&Frame::Warning { reason }).await;
+                    continue;
+                }
+                Verdict::Disconnect(reason) => {
+                    let addr = stream.addr;
+                    info!("Disconnecting client {}: {reason}", addr);
+                    METRICS.failure(Failure::Flooding);
+                    stream.refuse(incoming, &reason).await;
+                    return Err(());
+                }
+            }
+
+            let stream = Arc::clone(stream);
+            let event = match frame {
+                Ok(Frame::Register { username, password }) => {
+                    match Self::register(accounts, &username, password).await {
+                        Ok(()) => ServerEvent::LoggedIn(stream, username),
+                        Err(reason) => {
+                            METRICS.failure(Failure::LoginFailed);
+                            stream.send(&Frame::Error { reason }).await;
+                            continue;
+                        }
                     }
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    // Tries to read from socket again
+                Ok(Frame::Login { username, password }) => {
+                    // the kernel vouches for the system user of a Unix socket client
+                    if stream.system_user.as_ref() == Some(&username) {
+                        info!("Client {} logged in by peer credentials", stream.addr);
+                        ServerEvent::LoggedIn(stream, username)
+                    } else if Self::login(accounts, &username, password).await {
+                        ServerEvent::LoggedIn(stream, username)
+                    } else {
+                        info!("Failed login as {username} from {}", stream.addr);
+                        METRICS.failure(Failure::LoginFailed);
+                        let reason = String::from("Invalid user name or password");
+                        stream.send(&Frame::Error { reason }).await;
+                        continue;
+                    }
+                }
+                Ok(Frame::Message(msg)) => ServerEvent::NewMessage(stream, msg),
+                Ok(Frame::Join { room }) => ServerEvent::JoinRoom(stream, room),
+                Ok(Frame::React(reaction)) => ServerEvent::React(stream, reaction),
+                Ok(Frame::Resume { token }) => ServerEvent::Resume(stream, token),
+                Ok(Frame::Logout) => ServerEvent::Logout(stream),
+                Ok(Frame::Search(query)) => ServerEvent::Search(stream, query),
+                Ok(Frame::Sync { room, after_id }) => ServerEvent::Sync(stream, room, after_id),
+                Ok(Frame::Kick { user, reason }) => {
+                    ServerEvent::Moderate(stream, Moderation::Kick { user, reason })
+                }
+                Ok(Frame::Ban {
+                    kind,
+                    target,
+                    reason,
+                }) => ServerEvent::Moderate(
+                    stream,
+                    Moderation::Ban {
+                        kind,
+                        target,
+                        reason,
+                    },
+                ),
+                Ok(Frame::Unban { kind, target }) => {
+                    ServerEvent::Moderate(stream, Moderation::Unban { kind, target })
+                }
+                Ok(Frame::Mute { user, seconds }) => {
+                    ServerEvent::Moderate(stream, Moderation::Mute { user, seconds })
+                }
+                Ok(Frame::Delete { id }) => {
+                    ServerEvent::Moderate(stream, Moderation::Delete { id })
+                }
+                Ok(Frame::Ping { nonce }) => {
+                    stream.send(&Frame::Pong { nonce }).await;
                     continue;
                 }
+                Ok(Frame::Pong { nonce }) => ServerEvent::Pong(stream, nonce),
+                Ok(Frame::Link { server, password }) => ServerEvent::Link(stream, server, password),
+                Ok(Frame::Relay(relay)) => ServerEvent::Relay(stream, relay),
+                Ok(_) => {
+                    error!("Client sent a frame reserved to the server");
+                    METRICS.failure(Failure::MalformedFrame);
+                    return Err(());
+                }
                 Err(err) => {
-                    let _ = messages.send(ServerEvent::ClientDisconnected(Arc::clone(&stream)));
-                    error!("Failed to read from client {client_addr}: {err}");
-                    break Ok(());
+                    error!("Failed to parse frame: {err}");
+                    METRICS.failure(Failure::MalformedFrame);
+                    return Err(());
                 }
-            }
+            };
+
+            messages
+                .send(event)
+                .await
+                .map_err(|err| error!("Failed to send message to server thread: {err}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Largest frame `stream` can send, linked servers sending larger ones
+    /// than clients.
+    fn frame_limit(stream: &Connection, max_message_size: usize) -> usize {
+        match stream.link.get() {
+            Some(_) => federation::frame_limit(max_message_size),
+            None => max_message_size,
         }
     }
+
+    /// Creates an account. The password is hashed on a blocking thread, as
+    /// argon2 is slow on purpose.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the account was created.
+    /// - `Err(reason)`: If the credentials are refused, with the reason to
+    ///   report to the client.
+    async fn register(
+        accounts: &Arc<Mutex<Accounts>>,
+        username: &str,
+        password: String,
+    ) -> std::result::Result<(), String> {
+        accounts.lock().unwrap().check_new(username, &password)?;
+        let hash = tokio::task::spawn_blocking(move || hash_password(&password))
+            .await
+            .ok()
+            .and_then(|hash| hash.ok())
+            .ok_or_else(|| String::from("The account could not be created"))?;
+        accounts.lock().unwrap().insert(username, hash)
+    }
+
+    /// Checks the password of a user on a blocking thread, as argon2 is slow
+    /// on purpose.
+    async fn login(accounts: &Arc<Mutex<Accounts>>, username: &str, password: String) -> bool {
+        let Some(hash) = accounts.lock().unwrap().hash(username) else {
+            return false;
+        };
+        tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+struct Member {
+    // socket of the client
+    stream: Connection,
+    // room the client is currently in, empty until it logs in
+    room: String,
+    // user the client is logged in as
+    user: Option<String>,
+    // token of the login session
+    token: Option<String>,
+    // when the client connected
+    connected: Instant,
+    // when the client last sent a frame
+    last_active: Instant,
+    // whether the server closed the connection, waiting for the client task to end
+    closed: bool,
+    // when the client was last pinged
+    last_ping: Instant,
+    // nonce of the ping waiting for an answer, and when it was sent
+    ping: Option<(u64, Instant)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moderators_only_moderate_lower_roles() {
+        let mut config = Config::default();
+        config.roles.insert(String::from("olivia"), Role::Owner);
+        config.roles.insert(String::from("mark"), Role::Moderator);
+        config.roles.insert(String::from("max"), Role::Moderator);
+
+        assert!(Server::check_rank(&config, "olivia", "mark").is_ok());
+        assert!(Server::check_rank(&config, "olivia", "alice").is_ok());
+        assert!(Server::check_rank(&config, "mark", "alice").is_ok());
+        assert!(Server::check_rank(&config, "mark", "max").is_err());
+        assert!(Server::check_rank(&config, "mark", "olivia").is_err());
+        assert!(Server::check_rank(&config, "alice", "bob").is_err());
+    }
+
+    #[test]
+    fn mutes_end() {
+        let mut mutes = HashMap::new();
+        mutes.insert(
+            String::from("alice"),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        mutes.insert(
+            String::from("bob"),
+            SystemTime::now() - Duration::from_secs(1),
+        );
+
+        assert!(Server::check_mute(&mut mutes, "alice").is_err());
+        assert!(Server::check_mute(&mut mutes, "bob").is_ok());
+        assert!(Server::check_mute(&mut mutes, "carol").is_ok());
+        assert!(!mutes.contains_key("bob"));
+    }
 }
@@ -0,0 +1,133 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// Login sessions, letting a client that lost its connection come back with
+/// a token instead of its password. Sessions live in memory, so they end when
+/// the server stops.
+pub struct Sessions {
+    // session of every token
+    sessions: HashMap<String, Session>,
+    // time a session lasts without being used
+    ttl: Duration,
+}
+
+/// Where a client was when its connection ended.
+#[derive(Clone)]
+pub struct Session {
+    pub user: String,
+    /// Room the client was in.
+    pub room: String,
+    /// Identifier of the newest message the client received.
+    pub last_read: u64,
+    // when the token stops being accepted
+    expires: SystemTime,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Opens a session for `user` in `room`, returning its token.
+    pub fn create(&mut self, user: &str, room: &str, last_read: u64) -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+        let session = Session {
+            user: user.to_string(),
+            room: room.to_string(),
+            last_read,
+            expires: SystemTime::now() + self.ttl,
+        };
+        self.sessions.insert(token.clone(), session);
+        token
+    }
+
+    /// Session of `token`, if it exists and has not expired. Using a session
+    /// extends its life.
+    pub fn resume(&mut self, token: &str) -> Option<Session> {
+        let now = SystemTime::now();
+        let session = self
+            .sessions
+            .get_mut(token)
+            .filter(|session| session.expires > now)?;
+        session.expires = now + self.ttl;
+        Some(session.clone())
+    }
+
+    /// Records where the client of `token` is.
+    pub fn update(&mut self, token: &str, room: &str, last_read: u64) {
+        if let Some(session) = self.sessions.get_mut(token) {
+            session.room = room.to_string();
+            session.last_read = last_read;
+            session.expires = SystemTime::now() + self.ttl;
+        }
+    }
+
+    /// Ends the session of `token`, which is not accepted anymore.
+    pub fn revoke(&mut self, token: &str) {
+        self.sessions.remove(token);
+    }
+
+    /// Forgets the sessions that expired. The sessions of the `connected`
+    /// tokens are in use, so their life is extended instead.
+    pub fn prune<'a>(&mut self, connected: impl Iterator<Item = &'a String>) {
+        let now = SystemTime::now();
+        for token in connected {
+            if let Some(session) = self.sessions.get_mut(token) {
+                session.expires = now + self.ttl;
+            }
+        }
+        self.sessions.retain(|_, session| session.expires > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn sessions_resume_until_revoked() {
+        let mut sessions = Sessions::new(DAY);
+        let token = sessions.create("alice", "general", 3);
+        let other = sessions.create("alice", "general", 3);
+        assert_ne!(token, other);
+        assert_eq!(token.len(), 64);
+
+        sessions.update(&token, "random", 7);
+        let session = sessions.resume(&token).unwrap();
+        assert_eq!(session.user, "alice");
+        assert_eq!(session.room, "random");
+        assert_eq!(session.last_read, 7);
+
+        sessions.revoke(&token);
+        assert!(sessions.resume(&token).is_none());
+        assert!(sessions.resume(&other).is_some());
+        assert!(sessions.resume("unknown").is_none());
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut sessions = Sessions::new(DAY);
+        let token = sessions.create("alice", "general", 0);
+        sessions.sessions.get_mut(&token).unwrap().expires = SystemTime::now();
+        assert!(sessions.resume(&token).is_none());
+
+        let idle = sessions.create("alice", "general", 0);
+        let connected = sessions.create("bob", "general", 0);
+        for token in [&idle, &connected] {
+            sessions.sessions.get_mut(token).unwrap().expires = SystemTime::now();
+        }
+        sessions.prune([&connected].into_iter());
+        assert!(!sessions.sessions.contains_key(&idle));
+        assert!(!sessions.sessions.contains_key(&token));
+        assert!(sessions.resume(&connected).is_some());
+    }
+}
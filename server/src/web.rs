@@ -0,0 +1,233 @@
+use crate::peer::PeerAddr;
+use crate::{debug, error};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Chat page served to browsers, talking to the server over WebSocket.
+const PAGE: &str = include_str!("../web/index.html");
+/// Longest request head read from a browser.
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Time a browser has to send the head of its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request received on a WebSocket listener that also serves the chat page.
+pub enum Request {
+    /// Plain HTTP request, with its method and path.
+    Page(TcpStream, String, String),
+    /// WebSocket handshake, whose head was already read from the socket.
+    WebSocket(ReadAhead),
+}
+
+/// Reads the head of the request of a connection accepted on a WebSocket
+/// listener, to tell browsers asking for the page from WebSocket clients.
+///
+/// # Returns
+/// The request, or `None` if the connection closed first or the head is too
+/// slow or too large.
+pub async fn read_ahead(mut stream: TcpStream) -> Option<Request> {
+    let (read, end) = read_head(&mut stream).await?;
+    let head = String::from_utf8_lossy(&read[..end]).to_ascii_lowercase();
+    let websocket = head.lines().any(|line| {
+        line.strip_prefix("upgrade:")
+            .is_some_and(|value| value.contains("websocket"))
+    });
+    if websocket {
+        return Some(Request::WebSocket(ReadAhead { read, stream }));
+    }
+    let (method, path) = parse_request(&read[..end]);
+    Some(Request::Page(stream, method, path))
+}
+
+/// TCP connection whose first bytes were already read, which reads return
+/// before the rest of the stream.
+pub struct ReadAhead {
+    read: Vec<u8>,
+    stream: TcpStream,
+}
+
+impl ReadAhead {
+    /// A connection nothing was read from yet.
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            read: Vec::new(),
+            stream,
+        }
+    }
+}
+
+impl AsyncRead for ReadAhead {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read.is_empty() {
+            return Pin::new(&mut self.stream).poll_read(cx, buf);
+        }
+        let n = self.read.len().min(buf.remaining());
+        buf.put_slice(&self.read[..n]);
+        self.read.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReadAhead {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Answers a plain HTTP request with the chat page, or with a 404 for any
+/// other path, and closes the connection.
+pub async fn serve(mut stream: TcpStream, addr: PeerAddr, method: &str, path: &str) {
+    debug!("Client {addr} requested {method} {path}");
+
+    let response = match (method, path) {
+        ("GET" | "HEAD", "/" | "/index.html") => {
+            response("200 OK", "text/html; charset=utf-8", PAGE, method == "HEAD")
+        }
+        ("GET" | "HEAD", _) => response("404 Not Found", "text/plain", "Not found\n", false),
+        _ => response(
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n",
+            false,
+        ),
+    };
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        error!("Failed to send the chat page to {addr}: {err}");
+        return;
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Reads the head of an HTTP request, which must arrive within
+/// `REQUEST_TIMEOUT` and fit in `MAX_REQUEST_SIZE` bytes.
+///
+/// # Returns
+/// The method and the path of the request, without its query, or `None` if
+/// the connection closed first or the head is too slow or too large.
+pub async fn read_request(stream: &mut TcpStream) -> Option<(String, String)> {
+    let (read, end) = read_head(stream).await?;
+    Some(parse_request(&read[..end]))
+}
+
+/// Reads from a connection until the end of the head of an HTTP request.
+///
+/// # Returns
+/// The bytes read, which may go past the head, and where the head ends.
+async fn read_head(stream: &mut TcpStream) -> Option<(Vec<u8>, usize)> {
+    let mut buffer = vec![0u8; MAX_REQUEST_SIZE];
+    let head = async {
+        let mut read = 0;
+        loop {
+            if let Some(end) = find_head_end(&buffer[..read]) {
+                return Some((read, end));
+            }
+            if read == buffer.len() {
+                return None;
+            }
+            match stream.read(&mut buffer[read..]).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => read += n,
+            }
+        }
+    };
+    let (read, end) = tokio::time::timeout(REQUEST_TIMEOUT, head).await.ok()??;
+    buffer.truncate(read);
+    Some((buffer, end))
+}
+
+/// Method and path, without its query, of the head of an HTTP request.
+fn parse_request(head: &[u8]) -> (String, String) {
+    let head = String::from_utf8_lossy(head);
+    let mut request = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request.next().unwrap_or_default().to_string();
+    let path = (request.next().unwrap_or_default().split('?').next())
+        .unwrap_or_default()
+        .to_string();
+    (method, path)
+}
+
+/// An HTTP response closing the connection, without its body when `head_only`.
+pub fn response(status: &str, content_type: &str, body: &str, head_only: bool) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        if head_only { "" } else { body }
+    )
+}
+
+/// Position right after the blank line ending the head of an HTTP request.
+fn find_head_end(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Sends `bytes` over a loopback connection and reads them ahead.
+    async fn request(bytes: &'static [u8]) -> Option<Request> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            for chunk in bytes.chunks(16) {
+                stream.write_all(chunk).await.unwrap();
+            }
+            stream
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let request = read_ahead(stream).await;
+        drop(client.await);
+        request
+    }
+
+    #[tokio::test]
+    async fn page_requests() {
+        let head = b"GET /index.html?x=1 HTTP/1.1\r\nHost: chat\r\n\r\n";
+        let Some(Request::Page(_, method, path)) = request(head).await else {
+            panic!("not a page request");
+        };
+        assert_eq!((method.as_str(), path.as_str()), ("GET", "/index.html"));
+    }
+
+    #[tokio::test]
+    async fn websocket_handshakes_are_read_again() {
+        let head = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nrest";
+        let Some(Request::WebSocket(mut stream)) = request(head).await else {
+            panic!("not a WebSocket handshake");
+        };
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, head);
+    }
+
+    #[tokio::test]
+    async fn oversized_heads() {
+        let head = [b'a'; MAX_REQUEST_SIZE + 1].to_vec().leak();
+        assert!(request(head).await.is_none());
+    }
+}